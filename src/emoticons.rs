@@ -1,59 +1,530 @@
 //! Read and prepare the emoticon pixels
 
 use image;
-use image::{DynamicImage};
+use image::{DynamicImage, GenericImage, FilterType, Rgba};
 use std::rc::Rc;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::char;
+use std::collections::HashMap;
 use std::fs::File;
 use std::fmt;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
 
 use hsl;
+use oklab;
+use error::EmotimError;
+use {ComparisationMethod, ADAPTIVE_CHROMA_THRESHOLD};
+
+
 
+/// Width and height in pixels that svg emoticons are rasterized to by default.
+/// (The size of the png emoticons in assets/emoticons2)
+pub const SVG_SIZE : u32 = 64;
 
+/// Prefixes of the filenames of common emoticon sets that are stripped before the
+/// codepoints are parsed, eg. `emoji_u1f600.png`.
+const FILENAME_PREFIXES : [&str ; 3] = ["emoji_u", "u+", "u"];
+
+/// Minimum time between two updates of the progress printed while reading emoticons.
+/// Flushing stdout for every file slows down reading large sets.
+pub const PROGRESS_INTERVAL : Duration = Duration::from_millis(100);
 
 /// A list of `Emoticon`s.
 pub type Emoticons = Vec<Rc<Emoticon>>;
 
+/// Name and group of an emoticon.
+pub type Metadata = (String, Option<String>);
+
 /// Reads all emoticons from assets/emoticons2.
 ///
 /// Expects the filename to be `[<unicodepoint>-]<unicodepoint>.png`
 /// where `<unicodepoint>` is a hex number. Eg: `0023-20e3.png` or `1f004.png`
+/// Other schemes like `emoji_u1f468_200d_1f469.png` work, too.
+///
+/// Names and groups of the emoticons are read from `assets/emoticons2.csv` if
+/// that file exists. See `parse_metadata()` for the format.
 pub fn read_emoticons() -> Emoticons {
-    let emotifolder = PathBuf::from("assets/emoticons2".to_string());
+    read_emoticons_with_mode(hsl::HistogramMode::Full)
+}
+
+/// Like `read_emoticons()`, but only keeps the parts of the histograms given by `mode`
+/// to save memory. See `ComparisationMethod::histogram_mode()` for what each method needs.
+///
+/// `HistogramMode::Maxima` keeps only the list of maxima and drops the HSL images, see
+/// `Emoticon::discard()`. This is enough for `Maxima`, `SoftMaxima` and all methods that
+/// do not use the histograms.
+///
+/// `HistogramMode::Sparse` is meant for very large sets. It works with `Correlation`
+/// and all methods that do not use the histograms.
+pub fn read_emoticons_with_mode(mode : hsl::HistogramMode) -> Emoticons {
+    load_emoticons(Path::new("assets/emoticons2"), mode, OnError::Fail).expect("Could not read emoticons").emoticons
+}
+
+/// What `load_emoticons()` does with files that can not be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnError {
+    /// Ignore the file.
+    Skip,
+    /// Abort and return the error. Files whose names contain no codepoints (eg. a readme)
+    /// are skipped anyway.
+    Fail,
+    /// Ignore the file, but remember the error in `LoadReport::skipped`.
+    SkipAndReport,
+}
+
+/// Result of `load_emoticons()`.
+pub struct LoadReport {
+    pub emoticons : Emoticons,
+    /// Files that have been skipped because of an error (only with `OnError::SkipAndReport`).
+    pub skipped : Vec<(PathBuf, EmotimError)>,
+}
+
+/// Reads all emoticons from `folder`, see `read_emoticons()` for the filename format.
+///
+/// Keeps only the parts of the histograms given by `mode` and handles corrupt files
+/// according to `on_error`. Names and groups are read from `<folder>.csv` if that file exists,
+/// an unreadable metadata file is handled like a corrupt emoticon.
+pub fn load_emoticons(folder : &Path,
+                      mode : hsl::HistogramMode,
+                      on_error : OnError) -> Result<LoadReport, EmotimError> {
+    load_emoticons_sized(folder, mode, on_error, SVG_SIZE)
+}
+
+/// Like `load_emoticons()`, but rasterizes svg files to `svg_size` × `svg_size` pixels.
+///
+/// Svg files need the feature `svg`, without it they are treated as corrupt files.
+pub fn load_emoticons_sized(folder : &Path,
+                            mode : hsl::HistogramMode,
+                            on_error : OnError,
+                            svg_size : u32) -> Result<LoadReport, EmotimError> {
+    let (emoticons, skipped) = load_emoticon_list(folder, mode, on_error, svg_size, None, &mut |_| {})?;
+    Ok(LoadReport {
+        emoticons : emoticons.into_iter().map(Rc::new).collect(),
+        skipped : skipped,
+    })
+}
+
+/// Like `load_emoticons()`, but scales each emoticon to `cell_size` × `cell_size` pixels
+/// right after decoding (svg files are rasterized to that size), and calculates the
+/// histograms of the scaled images. Rendering then pastes the small emoticons without
+/// resizing each cell, which is the fast path for small mosaics.
+pub fn load_emoticons_resized(folder : &Path,
+                              mode : hsl::HistogramMode,
+                              on_error : OnError,
+                              cell_size : u32) -> Result<LoadReport, EmotimError> {
+    let (emoticons, skipped) = load_emoticon_list(folder, mode, on_error, cell_size, Some(cell_size), &mut |_| {})?;
+    Ok(LoadReport {
+        emoticons : emoticons.into_iter().map(Rc::new).collect(),
+        skipped : skipped,
+    })
+}
+
+/// Emoticons and skipped files, before the emoticons are wrapped into `Rc`s, which can
+/// not be sent between threads.
+type EmoticonList = (Vec<Emoticon>, Vec<(PathBuf, EmotimError)>);
+
+/// Does the work of `load_emoticons_sized()` and `load_emoticons_resized()` (if `cell_size`
+/// is given). Calls `progress` with the number of emoticons read so far after each one.
+fn load_emoticon_list(folder : &Path,
+                      mode : hsl::HistogramMode,
+                      on_error : OnError,
+                      svg_size : u32,
+                      cell_size : Option<u32>,
+                      progress : &mut dyn FnMut(usize)) -> Result<EmoticonList, EmotimError> {
     let mut emoticons = Vec::with_capacity(1700);
-    println!("Read folder {}:", emotifolder.display());
-    for (i, direntry) in emotifolder.read_dir()
-                                    .expect("Folder not found")
-                                    .enumerate() {
+    let mut skipped = Vec::new();
+    let metadata_path = folder.with_extension("csv");
+    let metadata = match read_metadata(&metadata_path) {
+        Ok(metadata) => metadata,
+        Err(e) => match on_error {
+            OnError::Skip => HashMap::new(),
+            OnError::Fail => return Err(e.into()),
+            OnError::SkipAndReport => {
+                skipped.push((metadata_path, e.into()));
+                HashMap::new()
+            }
+        },
+    };
+    let mut throttle = Throttle::new(PROGRESS_INTERVAL);
+    let mut last = None;
+    println!("Read folder {}:", folder.display());
+    for (i, direntry) in folder.read_dir()?.enumerate() {
         if let Ok(direntry) = direntry {
             if let Ok(filetype) = direntry.file_type() {
                 if ! filetype.is_file() {
                     continue;
                 }
             }
-            emoticons.push(Rc::new(Emoticon::read_emoticon(direntry.path())));
+            let mut emoticon = match Emoticon::try_read_emoticon_resized(&direntry.path(), svg_size, cell_size) {
+                Ok(emoticon) => emoticon,
+                Err(e) => match on_error {
+                    OnError::Skip => continue,
+                    OnError::Fail => match e {
+                        EmotimError::InvalidFilename(_) => continue,
+                        e => return Err(e),
+                    },
+                    OnError::SkipAndReport => {
+                        skipped.push((direntry.path(), e));
+                        continue;
+                    }
+                },
+            };
+            if mode == hsl::HistogramMode::Sparse {
+                emoticon.sparse = Some(hsl::SparseHistogram::from_histogram(&emoticon.hist));
+            }
+            emoticon.discard(mode);
+            if let Some((name, group)) = metadata.get(&emoticon.codepoints_hex()) {
+                emoticon.name = Some(name.clone());
+                emoticon.group = group.clone();
+            }
+            emoticons.push(emoticon);
             // progress
-            print!("\r{}", i);
-            io::stdout().flush().ok();
+            progress(emoticons.len());
+            last = Some(i);
+            if throttle.ready() {
+                print!("\r{}", i);
+                io::stdout().flush().ok();
+            }
         }
     }
+    if let Some(i) = last {
+        print!("\r{}", i);
+    }
     println!("");
-    emoticons
+    Ok((emoticons, skipped))
+}
+
+/// Rate limit for progress output.
+struct Throttle {
+    interval : Duration,
+    last : Option<Instant>,
+}
+
+impl Throttle {
+    fn new(interval : Duration) -> Throttle {
+        Throttle { interval : interval, last : None }
+    }
+
+    /// True on the first call and if at least `interval` has passed since the last
+    /// call that returned true.
+    fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Emoticons that are being read on a background thread, see `spawn_load_emoticons()`.
+pub struct EmoticonLoader {
+    handle : JoinHandle<Result<EmoticonList, EmotimError>>,
+}
+
+impl EmoticonLoader {
+    /// True if the loading is done, so that `join()` does not block.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the loading to finish and returns the emoticons.
+    ///
+    /// Panics if the background thread panicked.
+    pub fn join(self) -> Result<LoadReport, EmotimError> {
+        let (emoticons, skipped) = self.handle.join().expect("emoticon loader panicked")?;
+        Ok(LoadReport {
+            emoticons : emoticons.into_iter().map(Rc::new).collect(),
+            skipped : skipped,
+        })
+    }
+}
+
+/// Like `load_emoticons()`, but reads the emoticons on a background thread, so that
+/// eg. a GUI stays responsive. Poll with `EmoticonLoader::is_finished()` and get the
+/// emoticons with `EmoticonLoader::join()`.
+pub fn spawn_load_emoticons(folder : PathBuf, mode : hsl::HistogramMode, on_error : OnError) -> EmoticonLoader {
+    spawn_load_emoticons_with_progress(folder, mode, on_error, |_| {})
+}
+
+/// Like `spawn_load_emoticons()`, but calls `progress` (on the background thread) with
+/// the number of emoticons read so far after each one.
+pub fn spawn_load_emoticons_with_progress<F>(folder : PathBuf,
+                                             mode : hsl::HistogramMode,
+                                             on_error : OnError,
+                                             mut progress : F) -> EmoticonLoader
+    where F : FnMut(usize) + Send + 'static {
+    EmoticonLoader {
+        handle : thread::spawn(move || load_emoticon_list(&folder, mode, on_error, SVG_SIZE, None, &mut progress)),
+    }
+}
+
+/// Renders an svg file into a `size` × `size` image. The aspect ratio is kept,
+/// the drawing is centered.
+#[cfg(feature = "svg")]
+fn rasterize_svg(path : &Path, size : u32) -> Result<DynamicImage, EmotimError> {
+    use resvg::{tiny_skia, usvg};
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let tree = usvg::Tree::from_data(&data, &usvg::Options::default())
+                   .map_err(|e| EmotimError::Svg(e.to_string()))?;
+    let mut pixmap = tiny_skia::Pixmap::new(size, size)
+                         .ok_or_else(|| EmotimError::Svg(format!("invalid size {}", size)))?;
+    let svg_size = tree.size();
+    let scale = size as f32 / svg_size.width().max(svg_size.height());
+    let transform = tiny_skia::Transform::from_scale(scale, scale)
+                        .post_translate((size as f32 - svg_size.width() * scale) / 2.0,
+                                        (size as f32 - svg_size.height() * scale) / 2.0);
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+    let raw = pixmap.pixels()
+                    .iter()
+                    .flat_map(|p| {
+                        let p = p.demultiply();
+                        vec![p.red(), p.green(), p.blue(), p.alpha()]
+                    })
+                    .collect();
+    Ok(DynamicImage::ImageRgba8(image::RgbaImage::from_raw(size, size, raw).unwrap()))
+}
+
+/// Pairwise similarities of all emoticons: `matrix[i][j]` is
+/// `emoticons[i].similarity(&emoticons[j], method)`. Calculated in parallel.
+///
+/// Not all methods are symmetric, so the full matrix is calculated. Useful for
+/// clustering and for finding emoticons that look alike.
+pub fn similarity_matrix(emoticons : &Emoticons, method : ComparisationMethod) -> Vec<Vec<f32>> {
+    // `Rc` can not be shared between threads, plain references can
+    let emoticon_refs : Vec<&Emoticon> = emoticons.iter().map(|e| &**e).collect();
+    emoticon_refs.par_iter()
+                 .map(|e| emoticon_refs.iter().map(|other| e.similarity(other, method)).collect())
+                 .collect()
+}
+
+/// Combines two sets of emoticons. Emoticons of `overlay` replace the ones of `base`
+/// with the same codepoints (at the same position), the others are appended.
+pub fn merge_emoticons(base : Emoticons, overlay : Emoticons) -> Emoticons {
+    let mut merged = base;
+    let mut positions : HashMap<Vec<char>, usize> = merged.iter()
+                                                          .enumerate()
+                                                          .map(|(i, e)| (e.codepoints(), i))
+                                                          .collect();
+    for e in overlay {
+        let codepoints = e.codepoints();
+        match positions.get(&codepoints) {
+            Some(&i) => merged[i] = e,
+            None => {
+                positions.insert(codepoints, merged.len());
+                merged.push(e);
+            }
+        }
+    }
+    merged
+}
+
+/// Reads the metadata file at `path`. Returns an empty map if there is no such file.
+pub fn read_metadata(path : &Path) -> io::Result<HashMap<String, Metadata>> {
+    let mut content = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut content)?;
+            Ok(parse_metadata(&content))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses emoticon metadata. Returns a map from filename (without `.png`) to name and group.
+///
+/// Each line has the format `<filename without .png>;<name>[;<group>]`. Empty lines
+/// and lines starting with `#` are skipped. Eg:
+///
+/// ```text
+/// # codepoints;name;group
+/// 1f600;grinning face;Smileys & Emotion
+/// 0023-20e3;keycap: #;Symbols
+/// ```
+pub fn parse_metadata(content : &str) -> HashMap<String, Metadata> {
+    let mut metadata = HashMap::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(';').map(|f| f.trim());
+        let codepoints = fields.next().unwrap().to_lowercase();
+        let name = match fields.next() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let group = fields.next().map(|g| g.to_string());
+        metadata.insert(codepoints, (name, group));
+    }
+    metadata
+}
+
+/// How well a set of emoticons covers the reduced depth color space.
+///
+/// Each emoticon counts for the bin of its dominant color.
+#[derive(Debug, Clone)]
+pub struct CoverageReport {
+    /// Number of bins (out of `hsl::TOTAL_BINS`) that contain the dominant color of at least one emoticon.
+    pub covered_bins : usize,
+    /// Distance (in bins, hue wraps around) from the bin that is most distant
+    /// to all covered bins to its nearest covered bin.
+    pub largest_gap : usize,
+    /// Center of the largest gap. `None` if no bin is covered.
+    pub largest_gap_center : Option<hsl::Hsl>,
+    /// Number of covered bins for each of the 16 hue slices.
+    pub per_hue : [usize ; hsl::BINS_PER_CHANNEL],
+}
+
+/// Computes how well `emoticons` cover the color space.
+///
+/// Helps to find out which colors are missing in an emoticon set.
+pub fn color_coverage(emoticons : &Emoticons) -> CoverageReport {
+    let mut covered = [[[false ; hsl::BINS_PER_CHANNEL] ; hsl::BINS_PER_CHANNEL] ; hsl::BINS_PER_CHANNEL];
+    for e in emoticons {
+        if let Some(c) = e.dominant_color() {
+            covered[c.h2 as usize][c.c2 as usize][c.l as usize] = true;
+        }
+    }
+
+    let mut covered_bins = Vec::new();
+    let mut per_hue = [0 ; hsl::BINS_PER_CHANNEL];
+    for (ih, slice) in covered.iter().enumerate() {
+        for (ic, row) in slice.iter().enumerate() {
+            for (il, &is_covered) in row.iter().enumerate() {
+                if is_covered {
+                    covered_bins.push((ih, ic, il));
+                    per_hue[ih] += 1;
+                }
+            }
+        }
+    }
+
+    // Chebyshev distance of each bin to its nearest covered bin
+    let mut largest_gap = 0;
+    let mut largest_gap_center = None;
+    if !covered_bins.is_empty() {
+        for ih in 0..hsl::BINS_PER_CHANNEL {
+            for ic in 0..hsl::BINS_PER_CHANNEL {
+                for il in 0..hsl::BINS_PER_CHANNEL {
+                    let nearest = covered_bins.iter().map(|&(h, c, l)| {
+                        let dh = ih.abs_diff(h);
+                        let dh = dh.min(hsl::BINS_PER_CHANNEL - dh);
+                        let dc = ic.abs_diff(c);
+                        let dl = il.abs_diff(l);
+                        dh.max(dc).max(dl)
+                    }).min().unwrap();
+                    if nearest > largest_gap {
+                        largest_gap = nearest;
+                        largest_gap_center = Some(hsl::Hsl::new(ih as u8, ic as u8, il as u8, 1));
+                    }
+                }
+            }
+        }
+    }
+
+    CoverageReport {
+        covered_bins : covered_bins.len(),
+        largest_gap : largest_gap,
+        largest_gap_center : largest_gap_center,
+        per_hue : per_hue,
+    }
+}
+
+/// Smallest rectangle `(x, y, width, height)` that contains all pixels of `img` that are
+/// not fully transparent. `None` if all are.
+pub fn opaque_bounding_box(img : &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in img.pixels() {
+        if pixel.data[3] > 0 {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+    if left > right {
+        return None;
+    }
+    Some((left, top, right - left + 1, bottom - top + 1))
+}
+
+/// Summary of a single emoticon file, see `inspect_emoticon()`.
+#[derive(Debug, Clone)]
+pub struct EmoticonInspection {
+    /// All unicode codepoints in display order, see `Emoticon::codepoints()`.
+    pub codepoints : Vec<char>,
+    pub width : u32,
+    pub height : u32,
+    /// Fraction of visible pixels, see `Emoticon::coverage`.
+    pub coverage : f32,
+    /// The maxima of the histogram as RGB with their height, most significant first.
+    /// These are the colors that `ComparisationMethod::Maxima` compares.
+    pub dominant_colors : Vec<(Rgba<u8>, f32)>,
+}
+
+/// Reads a single emoticon and summarizes what the matcher sees of it. Helps to find out
+/// why a specific emoticon matches oddly without loading the whole set.
+pub fn inspect_emoticon(path : &Path) -> Result<EmoticonInspection, EmotimError> {
+    let emoticon = Emoticon::try_read_emoticon(path)?;
+    Ok(EmoticonInspection {
+        codepoints : emoticon.codepoints(),
+        width : emoticon.img.width(),
+        height : emoticon.img.height(),
+        coverage : emoticon.coverage,
+        dominant_colors : emoticon.hist.maxima.iter()
+                                              .rev()
+                                              .map(|&(ref color, height)| (color.extend_dynamic().to_rgba(), height))
+                                              .collect(),
+    })
 }
 
 /// An emoticon with metadata like histogram and unicode representation.
 pub struct Emoticon {
     pub img: DynamicImage,
+    /// Last codepoint of `sequence`
     pub unicode : char,
+    /// First codepoint of `sequence` if there is more than one
     pub unicode2 : Option<char>,
+    /// All unicode codepoints in display order
+    pub sequence : Vec<char>,
     pub filename : String,
+    /// Empty after `discard(HistogramMode::Maxima)`
     pub hsl : hsl::HslImage,
+    /// Empty after `discard(HistogramMode::Maxima)`
     pub hslreduced : hsl::HslImageWithReducedDepth,
     pub hist : hsl::HslHistogram,
+    /// Only loaded with `HistogramMode::Sparse`, see `read_emoticons_with_mode()`
+    pub sparse : Option<hsl::SparseHistogram>,
+    pub spatial : hsl::SpatialSignature,
+    /// Mean color of the visible pixels (reduced color depth)
+    pub mean_color : hsl::Hsl,
+    /// Mean color of the visible pixels in OKLab, `None` if mostly transparent.
+    /// See `oklab::mean_oklab()`
+    pub mean_oklab : Option<oklab::Oklab>,
+    /// Mean chroma of the visible pixels (0.0: grey, 1.0: fully saturated)
+    pub mean_chroma : f32,
+    /// Fraction of visible pixels (0.0: fully transparent, 1.0: covers the whole cell)
+    pub coverage : f32,
+    /// Fraction of pixels on an edge (0.0: flat, 1.0: busy), see `HslImage::edge_density()`
+    pub edge_density : f32,
+    /// Smallest rectangle `(x, y, width, height)` that contains all pixels that are not
+    /// fully transparent, see `opaque_bounding_box()`. `None` if the image is empty.
+    pub bounding_box : Option<(u32, u32, u32, u32)>,
+    /// Name, eg. `grinning face`. (From the metadata file)
+    pub name : Option<String>,
+    /// Group, eg. `Smileys & Emotion`. (From the metadata file)
+    pub group : Option<String>,
 }
 
 impl Emoticon {
@@ -62,37 +533,197 @@ impl Emoticon {
     ///
     /// Expects the filename to be `[<unicodepoint>-]<unicodepoint>.png`
     /// where `<unicodepoint>` is a hex number. Eg: `0023-20e3.png` or `1f004.png`
+    /// Any number of codepoints, uppercase hex, `_` separators and prefixes like
+    /// `emoji_u` are accepted as well.
+    ///
+    /// Panics if the file can not be read, see `try_read_emoticon()`.
     pub fn read_emoticon(path : PathBuf) -> Emoticon {
-        let filename = path.file_name().unwrap().to_str().unwrap();
-        let img = image::open(&path).unwrap();
-        let hsl = hsl::HslImage::from_image(&img);
+        Emoticon::try_read_emoticon(&path).unwrap()
+    }
+
+    /// Reads emoticon from png file. Returns an error if the file can not be decoded
+    /// or its name does not contain unicode codepoints.
+    pub fn try_read_emoticon(path : &Path) -> Result<Emoticon, EmotimError> {
+        Emoticon::try_read_emoticon_sized(path, SVG_SIZE)
+    }
+
+    /// Like `try_read_emoticon()`, but also reads svg files (with the feature `svg`)
+    /// and rasterizes them to `svg_size` × `svg_size` pixels. Eg. `1f004.svg`
+    pub fn try_read_emoticon_sized(path : &Path, svg_size : u32) -> Result<Emoticon, EmotimError> {
+        Emoticon::try_read_emoticon_resized(path, svg_size, None)
+    }
+
+    /// Like `try_read_emoticon_sized()`, but scales the image to `cell_size` × `cell_size`
+    /// pixels before the histograms are calculated, if given.
+    #[cfg_attr(not(feature = "svg"), allow(unused_variables))]
+    pub fn try_read_emoticon_resized(path : &Path, svg_size : u32, cell_size : Option<u32>) -> Result<Emoticon, EmotimError> {
+        let filename = path.file_name()
+                           .and_then(|f| f.to_str())
+                           .ok_or_else(|| EmotimError::InvalidFilename(path.display().to_string()))?;
+        Emoticon::parse_filename(filename)?;
+        let img = match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "svg")]
+            Some("svg") => rasterize_svg(path, svg_size)?,
+            _ => image::open(path)?,
+        };
+        let img = match cell_size {
+            Some(size) if (size, size) != img.dimensions() => img.resize_exact(size, size, FilterType::Lanczos3),
+            _ => img,
+        };
+        Emoticon::try_from_image(img, filename)
+    }
+
+    /// Creates an emoticon from an image that is already in memory.
+    ///
+    /// The unicode codepoints are taken from `filename`, see `read_emoticon()`.
+    /// Panics if the filename is invalid, see `try_from_image()`.
+    pub fn from_image(img : DynamicImage, filename : &str) -> Emoticon {
+        Emoticon::try_from_image(img, filename).unwrap()
+    }
+
+    /// Creates an emoticon from an image that is already in memory. Returns an error if
+    /// `filename` does not contain unicode codepoints.
+    pub fn try_from_image(img : DynamicImage, filename : &str) -> Result<Emoticon, EmotimError> {
+        Emoticon::try_from_image_with(img, filename, false)
+    }
+
+    /// Like `try_from_image()`, but converts to HSL in linear light if `linear_light`,
+    /// see `hsl::HslImage::from_image_with()`.
+    pub fn try_from_image_with(img : DynamicImage, filename : &str, linear_light : bool) -> Result<Emoticon, EmotimError> {
+        let sequence = Emoticon::parse_filename(filename)?;
+        let hsl = hsl::HslImage::from_image_with(&img, linear_light);
         let hslreduced = hsl.reduce_dynamic();
         let hist = hslreduced.histogram();
+        let spatial = hsl::SpatialSignature::from_image(&img);
+        let mean_color = hsl::mean_color(&img);
+        let mean_oklab = oklab::mean_oklab(&img);
+        let mean_chroma = hsl.mean_chroma();
+        let coverage = 1.0 - hslreduced.transparency();
+        let edge_density = hsl.edge_density();
+        let bounding_box = opaque_bounding_box(&img);
 
-        let mut ret = Emoticon {
+        Ok(Emoticon {
             img : img,
-            unicode : ' ',
-            unicode2 : None,
+            unicode : sequence[sequence.len() - 1],
+            unicode2 : if sequence.len() > 1 { Some(sequence[0]) } else { None },
+            sequence : sequence,
             filename : filename.to_string(),
             hsl : hsl,
             hslreduced : hslreduced,
             hist : hist,
-        };
+            sparse : None,
+            spatial : spatial,
+            mean_color : mean_color,
+            mean_oklab : mean_oklab,
+            mean_chroma : mean_chroma,
+            coverage : coverage,
+            edge_density : edge_density,
+            bounding_box : bounding_box,
+            name : None,
+            group : None,
+        })
+    }
+
+    /// A copy whose HSL image and histograms are calculated in linear light, see
+    /// `EmoimageBuilder::linear_light()`. Keeps name and group.
+    pub fn with_linear_light(&self) -> Emoticon {
+        let mut emoticon = Emoticon::try_from_image_with(self.img.clone(), &self.filename, true)
+                                    .expect("filename has been parsed before");
+        emoticon.name = self.name.clone();
+        emoticon.group = self.group.clone();
+        emoticon
+    }
+
+    /// Parses the unicode codepoints from a filename like `0023-20e3.png` or `1f004.png`.
+    ///
+    /// Tolerates the naming schemes of other emoticon sets: the hex numbers may be
+    /// uppercase and separated by `-` or `_`, and there may be a prefix like `emoji_u`
+    /// (see `FILENAME_PREFIXES`). Eg. `emoji_u1f468_200d_1f469.png`
+    fn parse_filename(filename : &str) -> Result<Vec<char>, EmotimError> {
+        let invalid = || EmotimError::InvalidFilename(filename.to_string());
+        let stem = filename.rsplitn(2, '.').last().ok_or_else(invalid)?.to_lowercase();
+        let stem = FILENAME_PREFIXES.iter()
+                                    .find(|prefix| stem.starts_with(*prefix))
+                                    .map_or(&stem[..], |prefix| &stem[prefix.len()..]);
+        stem.split(['-', '_'])
+            .map(|s| Emoticon::str_to_unicode(s).ok_or_else(invalid))
+            .collect()
+    }
 
-        if filename.contains("-") {
-            let mut split = filename.split(|c| c == '-' || c == '.');
-            ret.unicode2 = Some(Emoticon::str_to_unicode(split.next().unwrap()));
-            ret.unicode = Emoticon::str_to_unicode(split.next().unwrap());
-        } else {
-            ret.unicode = Emoticon::str_to_unicode(&filename[..filename.len() - 4]);
+    /// Frees the parts of the histogram that are not needed for `mode`, see
+    /// `HslHistogram::discard()`. `HistogramMode::Maxima` also frees the HSL images, so
+    /// that the analysis of the emoticon shrinks to `maxima_histogram()`.
+    pub fn discard(&mut self, mode : hsl::HistogramMode) {
+        self.hist.discard(mode);
+        if mode == hsl::HistogramMode::Maxima {
+            self.hsl = hsl::HslImage::empty();
+            self.hslreduced = self.hsl.reduce_dynamic();
         }
-        ret
+    }
+
+    /// The maxima of the histogram together with the size of the image. Works after
+    /// `discard()` as well.
+    pub fn maxima_histogram(&self) -> hsl::MaximaHistogram {
+        let (width, height) = self.img.dimensions();
+        hsl::MaximaHistogram::from_histogram(&self.hist, width, height)
+    }
+
+    /// The most frequent color of this emoticon (in reduced color depth).
+    pub fn dominant_color(&self) -> Option<hsl::Hsl> {
+        self.hist.dominant_color()
+    }
+
+    /// Correlation of the smoothed histogram with the one of a chunk. Uses the sparse
+    /// histogram if the dense one has been discarded.
+    pub fn similarity_by_correlation(&self, hist : &hsl::HslHistogram) -> f32 {
+        match (self.hist.smoothed.as_ref(), self.sparse.as_ref()) {
+            (None, Some(sparse)) => sparse.similarity_by_correlation_with_dense(hist),
+            _ => self.hist.similarity_by_correlation(hist),
+        }
+    }
+
+    /// Similarity of `other` to this emoticon by `method`, as if `other` was a chunk of
+    /// the input image. Needs the full histograms, see `HistogramMode`.
+    pub fn similarity(&self, other : &Emoticon, method : ComparisationMethod) -> f32 {
+        match method {
+            ComparisationMethod::Correlation => self.similarity_by_correlation(&other.hist),
+            ComparisationMethod::Maxima => self.hist.similarity_by_maxima(&other.hist),
+            ComparisationMethod::Spatial => self.spatial.similarity(&other.spatial),
+            ComparisationMethod::NearestColor => self.mean_color.similarity(&other.mean_color),
+            ComparisationMethod::Adaptive => if other.mean_chroma < ADAPTIVE_CHROMA_THRESHOLD {
+                self.hist.similarity_by_lightness(&other.hist)
+            } else {
+                self.hist.similarity_by_correlation(&other.hist)
+            },
+            ComparisationMethod::Chroma => self.hist.similarity_by_chroma(&other.hist),
+            ComparisationMethod::SoftMaxima =>
+                self.hist.similarity_by_soft_maxima(&other.hist, hsl::SOFT_MAXIMA_BANDWIDTH),
+            ComparisationMethod::Oklab => match (self.mean_oklab, other.mean_oklab) {
+                (Some(ref a), Some(ref b)) => a.similarity(b),
+                _ => 0.0,
+            },
+            ComparisationMethod::ColorCoverage { gamma } =>
+                self.similarity_by_correlation(&other.hist) * self.coverage.powf(gamma),
+        }
+    }
+
+    /// All unicode codepoints of this emoticon in display order.
+    pub fn codepoints(&self) -> Vec<char> {
+        self.sequence.clone()
+    }
+
+    /// The codepoints as lowercase hex numbers joined by `-`, eg. `0023-20e3`. This is
+    /// the filename scheme of assets/emoticons2 and the key of the metadata file.
+    pub fn codepoints_hex(&self) -> String {
+        self.sequence.iter().map(|&c| format!("{:04x}", c as u32)).collect::<Vec<_>>().join("-")
     }
 
     /// Converts a hex number representation of a unicodepoint like `20e3` to a `char`
-    fn str_to_unicode(s : &str) -> char {
-        let unicodepoint = u32::from_str_radix(s, 16).expect(&format!("str {} is no hex string", s));
-        char::from_u32(unicodepoint).expect(&format!("str {} does not represent a valid unicodepoint", s))
+    fn str_to_unicode(s : &str) -> Option<char> {
+        if s.is_empty() || s.starts_with('+') {
+            return None;
+        }
+        u32::from_str_radix(s, 16).ok().and_then(char::from_u32)
     }
 
     /// For debugging purposes, save reduced hsl image (convert back to rgb first) into out/reduced
@@ -107,19 +738,77 @@ impl Emoticon {
 
 impl fmt::Display for Emoticon {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(u2) = self.unicode2 {
-            write!(f, "{}", u2).unwrap()
+        for c in &self.sequence {
+            write!(f, "{}", c)?;
         }
-        write!(f, "{}", self.unicode)
+        Ok(())
     }
 }
 
+/// First codepoint of the swatches of `synthetic_palette()` (supplementary private use area A).
+pub const SYNTHETIC_CODEPOINT : u32 = 0xf0000;
+
+/// Builds square solid color swatches of size `tile_size` that cover the HSL space, for
+/// a quantized color (pixel art) mosaic without emoticon files.
+///
+/// The `hue_steps` hues are spread evenly over the hue wheel, the `light_steps` lightnesses
+/// evenly between black and white (exclusive) and the `chroma_steps` chromas from grey
+/// (if `chroma_steps > 1`) to full chroma. Greys are built only once per lightness.
+/// The swatches get consecutive codepoints starting at `SYNTHETIC_CODEPOINT`.
+///
+/// Panics if a step count is 0.
+pub fn synthetic_palette(hue_steps : u32, light_steps : u32, chroma_steps : u32, tile_size : u32) -> Emoticons {
+    use image::RgbaImage;
+    assert!(hue_steps > 0 && light_steps > 0 && chroma_steps > 0, "step counts must be > 0");
+    let mut colors = Vec::new();
+    for l in 0..light_steps {
+        let l = 255 * (l + 1) / (light_steps + 1);
+        for c in 0..chroma_steps {
+            let c = if chroma_steps > 1 { 255 * c / (chroma_steps - 1) } else { 255 };
+            let hues = if c == 0 { 1 } else { hue_steps };
+            for h in 0..hues {
+                colors.push(hsl::Hsl::new((256 * h / hue_steps) as u8, c as u8, l as u8, 255));
+            }
+        }
+    }
+    colors.iter().enumerate().map(|(i, color)| {
+        let img = RgbaImage::from_pixel(tile_size, tile_size, color.to_rgba());
+        Rc::new(Emoticon::from_image(DynamicImage::ImageRgba8(img), &format!("{:x}.png", SYNTHETIC_CODEPOINT + i as u32)))
+    }).collect()
+}
+
+/// Builds `n` square solid color emoticons of size `size` at full saturation, spanning the hue wheel.
+///
+/// The first one is red. Their unicode codepoints are taken from the private use area,
+/// starting at `U+E000`.
+#[cfg(test)]
+pub fn hue_wheel(n : u32, size : u32) -> Emoticons {
+    use image::{RgbaImage, Rgba};
+    (0..n).map(|i| {
+        // hsv to rgb with s = v = 1
+        let h_tick = i as f32 * 6.0 / n as f32;
+        let x = ((1.0 - (h_tick % 2.0 - 1.0).abs()) * 255.0) as u8;
+        let rgb = match h_tick as u32 {
+            0 => [255, x, 0],
+            1 => [x, 255, 0],
+            2 => [0, 255, x],
+            3 => [0, x, 255],
+            4 => [x, 0, 255],
+            _ => [255, 0, x],
+        };
+        let img = RgbaImage::from_pixel(size, size, Rgba([rgb[0], rgb[1], rgb[2], 255]));
+        Rc::new(Emoticon::from_image(DynamicImage::ImageRgba8(img), &format!("{:x}.png", 0xe000 + i)))
+    }).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use test::Bencher;
     use std::path::PathBuf;
     use std::rc::Rc;
+    use image::GenericImage;
+    use tests::TempDir;
 
 
     fn open_emoticon() -> Emoticon {
@@ -142,6 +831,196 @@ mod tests {
     fn bench_open_emoticon_rc(b: &mut Bencher) {
         b.iter(|| open_emoticon_rc());
     }
+
+    #[test]
+    fn bounding_box_excludes_transparent_frame() {
+        // Opaque from (3, 2) to (10, 12), the frame is transparent
+        let img = image::RgbaImage::from_fn(16, 16, |x, y| {
+            let inside = (3..11).contains(&x) && (2..13).contains(&y);
+            image::Rgba([255, 200, 0, if inside { 255 } else { 0 }])
+        });
+        let emoticon = Emoticon::from_image(DynamicImage::ImageRgba8(img), "1f7e7.png");
+        assert_eq!(emoticon.bounding_box, Some((3, 2, 8, 11)));
+        let empty = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        assert_eq!(opaque_bounding_box(&empty), None);
+    }
+
+    #[test]
+    fn inspect() {
+        let dir = TempDir::new("inspect");
+        let path = dir.join("1f534.png");
+        let img = image::RgbaImage::from_fn(16, 8, |x, _| {
+            if x < 8 { image::Rgba([200, 60, 60, 255]) } else { image::Rgba([0, 0, 0, 0]) }
+        });
+        img.save(&path).unwrap();
+        let inspection = inspect_emoticon(&path).unwrap();
+        assert_eq!(inspection.codepoints, vec!['\u{1f534}']);
+        assert_eq!((inspection.width, inspection.height), (16, 8));
+        assert!((inspection.coverage - 0.5).abs() < 1e-6);
+        let (color, _) = inspection.dominant_colors[0];
+        assert!(color.data[0] > 150 && color.data[1] < 100 && color.data[2] < 100, "{:?}", color);
+        assert!(inspect_emoticon(&path.with_file_name("missing.png")).is_err());
+    }
+
+    #[test]
+    fn corrupt_files_are_skipped() {
+        let dir = TempDir::new("corrupt_emoticons");
+        let folder = dir.join("emoticons");
+        ::std::fs::create_dir(&folder).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("00a9.png")).unwrap();
+        File::create(folder.join("1f600.png")).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("not_hex.png")).unwrap();
+
+        let report = load_emoticons(&folder, hsl::HistogramMode::Full, OnError::SkipAndReport).unwrap();
+        assert_eq!(report.emoticons.len(), 1);
+        assert_eq!(report.skipped.len(), 2);
+        let report = load_emoticons(&folder, hsl::HistogramMode::Full, OnError::Skip).unwrap();
+        assert_eq!((report.emoticons.len(), report.skipped.len()), (1, 0));
+        assert!(load_emoticons(&folder, hsl::HistogramMode::Full, OnError::Fail).is_err());
+    }
+
+    #[test]
+    fn unreadable_metadata_follows_on_error() {
+        let dir = TempDir::new("unreadable_metadata");
+        let folder = dir.join("emoticons");
+        ::std::fs::create_dir(&folder).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("00a9.png")).unwrap();
+        // Not utf-8
+        File::create(dir.join("emoticons.csv")).unwrap().write_all(&[0xff, 0xfe, b';']).unwrap();
+
+        let report = load_emoticons(&folder, hsl::HistogramMode::Full, OnError::SkipAndReport).unwrap();
+        assert_eq!(report.emoticons.len(), 1);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, dir.join("emoticons.csv"));
+        let report = load_emoticons(&folder, hsl::HistogramMode::Full, OnError::Skip).unwrap();
+        assert_eq!((report.emoticons.len(), report.skipped.len()), (1, 0));
+        match load_emoticons(&folder, hsl::HistogramMode::Full, OnError::Fail) {
+            Err(EmotimError::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("expected an io error"),
+        }
+    }
+
+    #[test]
+    fn resized_at_load() {
+        let dir = TempDir::new("resized_emoticons");
+        let folder = dir.join("emoticons");
+        ::std::fs::create_dir(&folder).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("00a9.png")).unwrap();
+
+        let report = load_emoticons_resized(&folder, hsl::HistogramMode::Full, OnError::Fail, 16).unwrap();
+        let emoticon = &report.emoticons[0];
+        assert_eq!(emoticon.img.dimensions(), (16, 16));
+        // The histograms belong to the small image
+        assert_eq!(emoticon.hsl.pixels.len(), 16 * 16);
+        let full = load_emoticons(&folder, hsl::HistogramMode::Full, OnError::Fail).unwrap();
+        assert!(full.emoticons[0].img.dimensions() != (16, 16));
+    }
+
+    #[test]
+    #[cfg(feature = "svg")]
+    fn svg_is_rasterized() {
+        use image::GenericImage;
+        let dir = TempDir::new("svg_emoticons");
+        let folder = dir.join("emoticons");
+        ::std::fs::create_dir(&folder).unwrap();
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="20">
+                         <rect width="10" height="20" fill="#ff0000"/></svg>"##;
+        File::create(folder.join("1f534.svg")).unwrap().write_all(svg.as_bytes()).unwrap();
+
+        let report = load_emoticons_sized(&folder, hsl::HistogramMode::Full, OnError::Fail, 16).unwrap();
+        let emoticon = &report.emoticons[0];
+        assert_eq!(emoticon.unicode, '\u{1f534}');
+        assert_eq!((emoticon.img.width(), emoticon.img.height()), (16, 16));
+        assert_eq!(emoticon.img.get_pixel(8, 8).data, [255, 0, 0, 255]);
+        assert_eq!(emoticon.img.get_pixel(0, 8).data[3], 0);
+    }
+
+    #[test]
+    fn filename_schemes() {
+        assert_eq!(Emoticon::parse_filename("1f004.png").unwrap(), vec!['\u{1f004}']);
+        assert_eq!(Emoticon::parse_filename("0023-20e3.png").unwrap(), vec!['#', '\u{20e3}']);
+        assert_eq!(Emoticon::parse_filename("emoji_u1f600.png").unwrap(), vec!['\u{1f600}']);
+        assert_eq!(Emoticon::parse_filename("1F468_200D_1F469.png").unwrap(),
+                   vec!['\u{1f468}', '\u{200d}', '\u{1f469}']);
+        assert_eq!(Emoticon::parse_filename("U+1F600.svg").unwrap(), vec!['\u{1f600}']);
+        assert!(Emoticon::parse_filename("readme.txt").is_err());
+        assert!(Emoticon::parse_filename("1f600__1f601.png").is_err());
+        assert!(Emoticon::parse_filename("emoji_u.png").is_err());
+    }
+
+    #[test]
+    fn similarity_matrix_of_hue_wheel() {
+        let emos = hue_wheel(6, 4);
+        let matrix = similarity_matrix(&emos, ComparisationMethod::NearestColor);
+        assert_eq!(matrix.len(), 6);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row.len(), 6);
+            assert!(row.iter().all(|&s| s <= row[i]));
+            for (j, &s) in row.iter().enumerate() {
+                assert_eq!(s, matrix[j][i]);
+            }
+        }
+    }
+
+    #[test]
+    fn load_in_background() {
+        use std::sync::{Arc, Mutex};
+        let dir = TempDir::new("background_emoticons");
+        let folder = dir.join("emoticons");
+        ::std::fs::create_dir(&folder).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("00a9.png")).unwrap();
+        ::std::fs::copy("assets/emoticons2/00a9.png", folder.join("00ae.png")).unwrap();
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        let loader = spawn_load_emoticons_with_progress(folder, hsl::HistogramMode::Full, OnError::Fail,
+                                                        move |n| reported.lock().unwrap().push(n));
+        let report = loader.join().unwrap();
+        assert_eq!(report.emoticons.len(), 2);
+        assert_eq!(*progress.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn synthetic_swatches() {
+        // 2 lightnesses * (1 grey + 2 chromas * 6 hues)
+        let palette = synthetic_palette(6, 2, 3, 4);
+        assert_eq!(palette.len(), 26);
+        assert_eq!(palette[0].codepoints(), vec![char::from_u32(SYNTHETIC_CODEPOINT).unwrap()]);
+        assert_eq!(palette[25].filename, "f0019.png");
+        assert!(palette.iter().all(|e| e.img.dimensions() == (4, 4)));
+        // The grey is grey, the others are colorful
+        assert!(palette[0].mean_chroma < 0.05, "{}", palette[0].mean_chroma);
+        assert!(palette[1..13].iter().all(|e| e.mean_chroma > palette[0].mean_chroma));
+    }
+
+    #[test]
+    fn throttle() {
+        let mut throttle = Throttle::new(Duration::from_secs(3600));
+        assert!(throttle.ready());
+        assert!(!throttle.ready());
+        let mut unthrottled = Throttle::new(Duration::from_secs(0));
+        assert!(unthrottled.ready() && unthrottled.ready());
+    }
+
+    #[test]
+    fn merge_replaces_by_codepoints() {
+        let base = hue_wheel(3, 4);
+        let mut overlay = hue_wheel(6, 4);
+        overlay.remove(0);
+        let merged = merge_emoticons(base.clone(), overlay.clone());
+        assert_eq!(merged.len(), 6);
+        assert!(Rc::ptr_eq(&merged[0], &base[0]));
+        assert!(Rc::ptr_eq(&merged[1], &overlay[0]));
+        assert!(Rc::ptr_eq(&merged[5], &overlay[4]));
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = parse_metadata("# comment\n\n1F600;grinning face;Smileys & Emotion\n0023-20e3; keycap: # \n");
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata["1f600"], ("grinning face".to_string(), Some("Smileys & Emotion".to_string())));
+        assert_eq!(metadata["0023-20e3"], ("keycap: #".to_string(), None));
+    }
 }
 
 
@@ -0,0 +1,125 @@
+//! Errors that can occur while converting images.
+
+use image;
+use hsl::{Bins, HistogramMode};
+use ComparisationMethod;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Error type of this crate.
+#[derive(Debug)]
+pub enum EmotimError {
+    /// The chunk size `frac` must be at least 1.
+    InvalidFrac(u32),
+    /// The input image is smaller than a single chunk.
+    ImageTooSmall { width : u32, height : u32, frac : u32 },
+    /// Each channel needs at least one histogram bin.
+    InvalidBins(Bins),
+    /// The bandwidth of `ComparisationMethod::SoftMaxima` must be positive.
+    InvalidBandwidth(f32),
+    /// The weight of the edge density must be between 0.0 and 1.0.
+    InvalidEdgeWeight(f32),
+    /// The epsilon of the random tiebreak must not be negative.
+    InvalidEpsilon(f32),
+    /// The gamma of `ComparisationMethod::ColorCoverage` must not be negative.
+    InvalidGamma(f32),
+    /// Posterizing needs at least one level.
+    InvalidPosterizeLevels(u8),
+    /// There are no emoticons to choose from, or all cells are empty.
+    NoEmoticons,
+    /// A group of `EmoimageBuilder::regions()` has no emoticons to choose from.
+    EmptyGroup(String),
+    /// The histogram of an emoticon was discarded down to less than the method compares,
+    /// see `ComparisationMethod::histogram_mode()`.
+    DiscardedHistogram { filename : String, needed : HistogramMode },
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// Decoding or encoding an image failed.
+    Image(image::ImageError),
+    /// Encoding an image with an encoder other than the ones of the `image` crate failed.
+    Encoding(String),
+    /// Parsing an svg file failed.
+    Svg(String),
+    /// The unicode codepoints of an emoticon could not be parsed from its filename.
+    InvalidFilename(String),
+    /// A raw pixel buffer does not match the dimensions of the image.
+    InvalidBufferSize { expected : usize, actual : usize },
+    /// The number of precomputed histograms does not match the number of cells.
+    HistogramCount { expected : usize, actual : usize },
+    /// The method needs more than the histogram of a chunk.
+    UnsupportedMethod(ComparisationMethod),
+    /// The name of an `OutputFormat` is not known.
+    UnknownOutputFormat(String),
+    /// The rendered image would exceed `render::MAX_OUTPUT_BYTES`.
+    OutputTooLarge { width : u64, height : u64 },
+    /// The thread pool for `EmoimageBuilder::threads()` could not be created.
+    ThreadPool(String),
+    /// The mask of `Emoimage::apply_mask()` has no pixels.
+    EmptyMask,
+}
+
+impl fmt::Display for EmotimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmotimError::InvalidFrac(frac) => write!(f, "invalid chunk size {} (must be > 0)", frac),
+            EmotimError::ImageTooSmall { width, height, frac } =>
+                write!(f, "image of size {}x{} is smaller than one chunk of size {}", width, height, frac),
+            EmotimError::InvalidBins(bins) =>
+                write!(f, "invalid histogram bins {}/{}/{} (must be > 0)", bins.hue, bins.chroma, bins.lightness),
+            EmotimError::InvalidBandwidth(bandwidth) =>
+                write!(f, "invalid soft maxima bandwidth {} (must be > 0)", bandwidth),
+            EmotimError::InvalidEdgeWeight(weight) =>
+                write!(f, "invalid edge weight {} (must be between 0 and 1)", weight),
+            EmotimError::InvalidEpsilon(epsilon) =>
+                write!(f, "invalid tiebreak epsilon {} (must be >= 0)", epsilon),
+            EmotimError::InvalidGamma(gamma) =>
+                write!(f, "invalid coverage gamma {} (must be >= 0)", gamma),
+            EmotimError::InvalidPosterizeLevels(levels) =>
+                write!(f, "invalid number of posterize levels {} (must be > 0)", levels),
+            EmotimError::NoEmoticons => write!(f, "no emoticons to choose from"),
+            EmotimError::EmptyGroup(ref group) => write!(f, "no emoticons to choose from in group {}", group),
+            EmotimError::DiscardedHistogram { ref filename, needed } =>
+                write!(f, "histogram of emoticon {} was discarded, the method needs {:?}", filename, needed),
+            EmotimError::Io(ref e) => write!(f, "io error: {}", e),
+            EmotimError::Image(ref e) => write!(f, "image error: {}", e),
+            EmotimError::Encoding(ref e) => write!(f, "encoding error: {}", e),
+            EmotimError::Svg(ref e) => write!(f, "svg error: {}", e),
+            EmotimError::InvalidFilename(ref name) => write!(f, "no unicode codepoints in filename {}", name),
+            EmotimError::InvalidBufferSize { expected, actual } =>
+                write!(f, "got {} bytes of pixel data, expected {}", actual, expected),
+            EmotimError::HistogramCount { expected, actual } =>
+                write!(f, "got {} histograms for {} cells", actual, expected),
+            EmotimError::UnsupportedMethod(method) =>
+                write!(f, "method {:?} can not match precomputed histograms", method),
+            EmotimError::UnknownOutputFormat(ref name) =>
+                write!(f, "unknown output format {} (png, text, html, ansi or json)", name),
+            EmotimError::OutputTooLarge { width, height } =>
+                write!(f, "rendered image of size {}x{} is too large (use a larger chunk size)", width, height),
+            EmotimError::ThreadPool(ref e) => write!(f, "could not create thread pool: {}", e),
+            EmotimError::EmptyMask => write!(f, "mask has no pixels"),
+        }
+    }
+}
+
+impl Error for EmotimError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            EmotimError::Io(ref e) => Some(e),
+            EmotimError::Image(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmotimError {
+    fn from(err : io::Error) -> EmotimError {
+        EmotimError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for EmotimError {
+    fn from(err : image::ImageError) -> EmotimError {
+        EmotimError::Image(err)
+    }
+}
@@ -0,0 +1,249 @@
+//! Objective measures of how well an `Emoimage` represents its input image.
+
+use image::{DynamicImage, RgbaImage, Rgba, Pixel, FilterType};
+
+use Emoimage;
+use hsl;
+
+/// Size of one cell of the color mosaic that `Emoimage::fidelity()` compares.
+pub const FIDELITY_CELL_SIZE : u32 = 4;
+/// Width and height of the windows of `ssim()`. Neighbouring windows overlap by half.
+pub const SSIM_WINDOW : u32 = 8;
+
+/// Stabilizing constants of SSIM for a dynamic range of 255: `(0.01 * 255)²` and `(0.03 * 255)²`.
+const SSIM_C1 : f32 = 6.5025;
+const SSIM_C2 : f32 = 58.5225;
+
+/// Result of `Emoimage::fidelity()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FidelityScore {
+    /// Peak signal to noise ratio of the RGB channels in dB. Higher is better,
+    /// infinite for identical images.
+    pub psnr : f32,
+    /// Mean structural similarity of the lightness, from -1.0 to 1.0 (identical).
+    pub ssim : f32,
+}
+
+impl Emoimage {
+    /// Compares the color mosaic (see `to_color_image()`, cell size `FIDELITY_CELL_SIZE`)
+    /// with the `original` image, downsampled to the same size. Useful to compare
+    /// comparison methods and parameters objectively.
+    ///
+    /// Empty cells count as black. The whole original is scaled, so parts that were cut
+    /// off while splitting it into chunks slightly distort the result.
+    pub fn fidelity(&self, original : &DynamicImage) -> FidelityScore {
+        let mosaic = self.to_color_image(FIDELITY_CELL_SIZE).to_rgba();
+        let original = original.resize_exact(mosaic.width(), mosaic.height(), FilterType::Triangle).to_rgba();
+        FidelityScore {
+            psnr : psnr(&mosaic, &original),
+            ssim : ssim(&mosaic, &original),
+        }
+    }
+
+    /// How much color got lost in each cell (row by row): the HSL distance
+    /// (`1 - Hsl::similarity()`) between the mean color of the chunk of `original` (see
+    /// `hsl::mean_color()`) and the dominant color of the chosen emoticon. 0.0 for empty
+    /// cells and mostly transparent chunks. Complements `confidences` when tuning `frac`
+    /// and the method.
+    ///
+    /// The chunks are cut as when the `Emoimage` was calculated (see `frac` and `offset`),
+    /// so pass the same image (not padded). Pixels outside of `original`, eg. the padding,
+    /// count as transparent.
+    pub fn quantization_error(&self, original : &DynamicImage) -> Vec<f32> {
+        let rgba = original.to_rgba();
+        let frac = self.frac;
+        let (offset_x, offset_y) = self.offset;
+        self.emopixels.iter().enumerate().map(|(i, emo)| {
+            let dominant = match emo.as_ref().and_then(|e| e.dominant_color()) {
+                Some(dominant) => dominant,
+                None => return 0.0,
+            };
+            if frac == 0 {
+                return 0.0;
+            }
+            let (left, top) = (i as u32 % self.width * frac, i as u32 / self.width * frac);
+            let chunk = RgbaImage::from_fn(frac, frac, |x, y| {
+                match ((left + x).checked_sub(offset_x), (top + y).checked_sub(offset_y)) {
+                    (Some(x), Some(y)) if x < rgba.width() && y < rgba.height() => *rgba.get_pixel(x, y),
+                    _ => Rgba([0, 0, 0, 0]),
+                }
+            });
+            let mean = hsl::mean_color(&chunk);
+            if mean.a == 0 {
+                return 0.0;
+            }
+            1.0 - mean.similarity(&dominant)
+        }).collect()
+    }
+
+    /// Renders `quantization_error()` as heatmap: each cell as a block of size `cell_size`
+    /// from black (no loss) to red (error of 1.0 or more). Empty cells are transparent.
+    pub fn quantization_heatmap(&self, original : &DynamicImage, cell_size : u32) -> DynamicImage {
+        let errors = self.quantization_error(original);
+        let mut img = RgbaImage::new(self.width * cell_size, self.height * cell_size);
+        for (i, (&error, emo)) in errors.iter().zip(self.emopixels.iter()).enumerate() {
+            if emo.is_none() {
+                continue;
+            }
+            let color = Rgba([(error.clamp(0.0, 1.0) * 255.0).round() as u8, 0, 0, 255]);
+            let (x, y) = ((i as u32 % self.width) * cell_size, (i as u32 / self.width) * cell_size);
+            for dy in 0..cell_size {
+                for dx in 0..cell_size {
+                    img.put_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(img)
+    }
+}
+
+/// Peak signal to noise ratio of the RGB channels of two images of equal size in dB.
+/// Infinite if they are equal. Alpha is ignored.
+///
+/// Panics if the dimensions differ.
+pub fn psnr(a : &RgbaImage, b : &RgbaImage) -> f32 {
+    assert_eq!(a.dimensions(), b.dimensions(), "images must have equal dimensions");
+    let mut sum = 0.0;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        for c in 0..3 {
+            let d = pa[c] as f32 - pb[c] as f32;
+            sum += d * d;
+        }
+    }
+    let mse = sum / (3 * a.width() * a.height()).max(1) as f32;
+    if mse == 0.0 {
+        return f32::INFINITY;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Mean structural similarity (SSIM) of the lightness of two images of equal size.
+///
+/// The means, variances and the covariance are calculated in square windows of size
+/// `SSIM_WINDOW` (or the whole image if it is smaller) with a step of half the window.
+/// Alpha is ignored. Panics if the dimensions differ.
+pub fn ssim(a : &RgbaImage, b : &RgbaImage) -> f32 {
+    assert_eq!(a.dimensions(), b.dimensions(), "images must have equal dimensions");
+    let (width, height) = a.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let la = lightness(a);
+    let lb = lightness(b);
+    let window_w = SSIM_WINDOW.min(width);
+    let window_h = SSIM_WINDOW.min(height);
+    let positions = |size : u32, window : u32| {
+        let step = (window / 2).max(1);
+        (0..(size - window) / step + 1).map(move |i| i * step)
+    };
+    let mut sum = 0.0;
+    let mut windows = 0;
+    for top in positions(height, window_h) {
+        for left in positions(width, window_w) {
+            let values = (top..top + window_h).flat_map(|y| (left..left + window_w).map(move |x| (y * width + x) as usize));
+            sum += window_ssim(values.map(|i| (la[i], lb[i])));
+            windows += 1;
+        }
+    }
+    sum / windows as f32
+}
+
+/// SSIM of one window, given as pairs of corresponding values.
+fn window_ssim<I>(values : I) -> f32
+    where I : Iterator<Item = (f32, f32)> + Clone {
+    let n = values.clone().count() as f32;
+    let (sum_a, sum_b) = values.clone().fold((0.0, 0.0), |s, v| (s.0 + v.0, s.1 + v.1));
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+    for (va, vb) in values {
+        var_a += (va - mean_a) * (va - mean_a);
+        var_b += (vb - mean_b) * (vb - mean_b);
+        cov += (va - mean_a) * (vb - mean_b);
+    }
+    let (var_a, var_b, cov) = (var_a / n, var_b / n, cov / n);
+    (2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * cov + SSIM_C2)
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+}
+
+/// Perceived lightness (0 to 255) of each pixel, row by row.
+fn lightness(img : &RgbaImage) -> Vec<f32> {
+    img.pixels()
+       .map(|p| {
+           let (r, g, b, _) = p.channels4();
+           0.3 * r as f32 + 0.59 * g as f32 + 0.11 * b as f32
+       })
+       .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImage, Rgba};
+    use emoticons;
+    use EmoimageBuilder;
+
+    #[test]
+    fn equal_images() {
+        let img = RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * 16) as u8, (y * 16) as u8, 0, 255]));
+        assert_eq!(psnr(&img, &img), f32::INFINITY);
+        assert!((ssim(&img, &img) - 1.0).abs() < 1e-6);
+        let darker = RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * 14) as u8, (y * 14) as u8, 0, 255]));
+        let noise = RgbaImage::from_fn(16, 16, |x, y| Rgba([((x * 7 + y * 13) * 37 % 256) as u8, 0, 0, 255]));
+        assert!(psnr(&img, &darker) > psnr(&img, &noise));
+        assert!(ssim(&img, &darker) > ssim(&img, &noise));
+        // Smaller than one window
+        let tiny = RgbaImage::from_pixel(3, 2, Rgba([10, 20, 30, 255]));
+        assert!((ssim(&tiny, &tiny) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn quantization_error_per_cell() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(24, 8, |x, _| match x / 8 {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([128, 128, 128, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        }));
+        let emoimg = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        let errors = emoimg.quantization_error(&img);
+        assert_eq!(errors.len(), 3);
+        // Red has a red emoticon, grey only colorful ones
+        assert!(errors[0] < 0.05, "{:?}", errors);
+        assert!(errors[1] > errors[0], "{:?}", errors);
+        assert_eq!(errors[2], 0.0);
+        let heatmap = emoimg.quantization_heatmap(&img, 2);
+        assert_eq!(heatmap.dimensions(), (6, 2));
+        assert!(heatmap.get_pixel(2, 0).data[0] > heatmap.get_pixel(0, 0).data[0]);
+        assert_eq!(heatmap.get_pixel(4, 0).data[3], 0);
+    }
+
+    #[test]
+    fn quantization_error_of_padded_image() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Padded to 24x8, the image starts at x = 2: red, grey and red chunks
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(20, 8, |x, _| {
+            if (6..14).contains(&x) { Rgba([128, 128, 128, 255]) } else { Rgba([255, 0, 0, 255]) }
+        }));
+        let emoimg = EmoimageBuilder::new().frac(8).pad(true).build(&img, &emos).unwrap();
+        assert_eq!((emoimg.width, emoimg.frac, emoimg.offset), (3, 8, (2, 0)));
+        let errors = emoimg.quantization_error(&img);
+        assert!(errors[0] < 0.05 && errors[2] < 0.05, "{:?}", errors);
+        assert!(errors[1] > errors[0] && errors[1] > errors[2], "{:?}", errors);
+    }
+
+    #[test]
+    fn fidelity_of_mosaic() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+        let emoimg = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        let score = emoimg.fidelity(&img);
+        assert!(score.psnr > 10.0, "{:?}", score);
+        assert!(score.ssim > 0.9, "{:?}", score);
+        // The mosaic of red and blue fits an image of blue and red worse
+        let swapped = img.rotate180();
+        let worse = emoimg.fidelity(&swapped);
+        assert!(worse.psnr < score.psnr && worse.ssim < score.ssim, "{:?}", worse);
+    }
+}
@@ -0,0 +1,157 @@
+//! Conversion of frame sequences, eg. the decoded frames of a video. Demuxing and muxing
+//! is left to the caller.
+
+use image::{DynamicImage, RgbaImage};
+use rayon;
+use rayon::prelude::*;
+use std::iter;
+use std::rc::Rc;
+
+use {EmoimageBuilder, EmotimError, ChunkSignature, RenderOptions};
+use {grid_size, install, pad_to_multiple};
+use emoticons::Emoticons;
+use hsl;
+
+/// The chunks of the previous frame with their analysis (`None` for empty chunks).
+type AnalyzedFrame = (RgbaImage, Vec<Option<(ChunkSignature, hsl::HslHistogram)>>);
+
+/// Iterator over the rendered mosaics of a sequence of frames, see
+/// `EmoimageBuilder::emojify_frames()`.
+pub struct FrameEmojifier<I> {
+    builder : EmoimageBuilder,
+    frames : I,
+    emoticons : Emoticons,
+    emoticon_bins : Vec<hsl::BinnedHistogram>,
+    pool : Option<rayon::ThreadPool>,
+    previous : Option<AnalyzedFrame>,
+    reused_chunks : usize,
+}
+
+impl EmoimageBuilder {
+    /// Converts each frame and renders the mosaic (like `Emoimage::to_image()`), lazily one
+    /// frame after the other. Chunks whose pixels did not change since the previous frame
+    /// keep their analysis, so mostly static videos are matched much faster.
+    ///
+    /// The emoticons are prepared once for all frames. `merge_flat_chunks` is ignored.
+    /// Fails only if the thread pool for `threads()` can not be created.
+    pub fn emojify_frames<I>(&self, frames : I, emoticons : &Emoticons) -> Result<FrameEmojifier<I>, EmotimError>
+        where I : Iterator<Item = DynamicImage> {
+        let emoticons = self.filtered_emoticons(emoticons).into_owned();
+        // Only the histograms differ, the images stay the same for rendering
+        let emoticons : Emoticons = if self.linear_light {
+            emoticons.iter().map(|e| Rc::new(e.with_linear_light())).collect()
+        } else {
+            emoticons
+        };
+        let emoticon_bins = if self.uses_bins() {
+            emoticons.iter().map(|e| e.hsl.binned_histogram(self.bins)).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(FrameEmojifier {
+            builder : self.clone(),
+            frames : frames,
+            emoticons : emoticons,
+            emoticon_bins : emoticon_bins,
+            pool : self.thread_pool()?,
+            previous : None,
+            reused_chunks : 0,
+        })
+    }
+}
+
+impl<I> FrameEmojifier<I> {
+    /// Number of chunks whose analysis was taken from the previous frame so far.
+    pub fn reused_chunks(&self) -> usize {
+        self.reused_chunks
+    }
+
+    fn emojify(&mut self, img : DynamicImage) -> Result<DynamicImage, EmotimError> {
+        let builder = &self.builder;
+        builder.check(&img, &self.emoticons)?;
+        let frac = builder.frac;
+        let img = if builder.pad { pad_to_multiple(&img, frac, builder.pad_fill) } else { img };
+        let (width, height) = grid_size(&img, frac);
+        let rgba = builder.input_pixels(&img);
+        let use_bins = builder.uses_bins();
+
+        let previous = self.previous.take().filter(|p| p.0.dimensions() == rgba.dimensions());
+        let analyzed : Vec<Option<Option<(ChunkSignature, hsl::HslHistogram)>>> = install(&self.pool, || {
+            (0..width * height).into_par_iter()
+                               .map(|i| {
+                                   let (left, top) = (i % width * frac, i / width * frac);
+                                   match previous {
+                                       Some((ref before, _)) if chunk_unchanged(before, &rgba, left, top, frac) => None,
+                                       _ => Some(builder.analyze_region(&rgba, left, top, frac, use_bins)),
+                                   }
+                               })
+                               .collect()
+        });
+        self.reused_chunks += analyzed.iter().filter(|a| a.is_none()).count();
+        let before = previous.map(|p| p.1).unwrap_or_default();
+        let chunks : Vec<Option<(ChunkSignature, hsl::HslHistogram)>> =
+            analyzed.into_iter()
+                    .zip(before.into_iter().map(Some).chain(iter::repeat_with(|| None)))
+                    .map(|(fresh, before)| fresh.unwrap_or_else(|| before.unwrap_or(None)))
+                    .collect();
+
+        let refs = chunks.iter().map(|c| c.as_ref().map(|c| (&c.0, &c.1))).collect();
+        let emoimage = builder.match_chunks(width, height, refs, &self.pool, &self.emoticons, &self.emoticon_bins);
+        self.previous = Some((rgba, chunks));
+        emoimage.try_render(&RenderOptions::default())
+    }
+}
+
+impl<I> Iterator for FrameEmojifier<I>
+    where I : Iterator<Item = DynamicImage> {
+    type Item = Result<DynamicImage, EmotimError>;
+
+    fn next(&mut self) -> Option<Result<DynamicImage, EmotimError>> {
+        let frame = self.frames.next()?;
+        Some(self.emojify(frame))
+    }
+}
+
+/// True if the chunks of size `frac` at `left`, `top` of both images have the same pixels.
+fn chunk_unchanged(before : &RgbaImage, after : &RgbaImage, left : u32, top : u32, frac : u32) -> bool {
+    (top..top + frac).all(|y| (left..left + frac).all(|x| before.get_pixel(x, y) == after.get_pixel(x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImage, Rgba};
+    use emoticons;
+
+    #[test]
+    fn static_chunks_are_reused() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // The right half turns from green to blue, the left half stays red
+        let frame = |right : Rgba<u8>| DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 16, |x, _| {
+            if x < 16 { Rgba([255, 0, 0, 255]) } else { right }
+        }));
+        let frames = vec![frame(Rgba([0, 255, 0, 255])), frame(Rgba([0, 0, 255, 255]))];
+        let builder = EmoimageBuilder::new().frac(8);
+        let mut emojifier = builder.emojify_frames(frames.clone().into_iter(), &emos).unwrap();
+        let first = emojifier.next().unwrap().unwrap();
+        assert_eq!(emojifier.reused_chunks(), 0);
+        let second = emojifier.next().unwrap().unwrap();
+        assert_eq!(emojifier.reused_chunks(), 4);
+        assert!(emojifier.next().is_none());
+        // Same result as converting each frame on its own
+        for (mosaic, frame) in [&first, &second].iter().zip(frames.iter()) {
+            let single = builder.build(frame, &emos).unwrap();
+            assert_eq!(mosaic.raw_pixels(), single.to_image().raw_pixels());
+        }
+        assert_eq!(second.get_pixel(0, 0), emos[0].img.get_pixel(0, 0));
+        assert_eq!(second.get_pixel(31, 0), emos[8].img.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn invalid_frames_fail() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let frames = vec![DynamicImage::ImageRgba8(RgbaImage::new(4, 4))];
+        let mut emojifier = EmoimageBuilder::new().frac(8).emojify_frames(frames.into_iter(), &emos).unwrap();
+        assert!(emojifier.next().unwrap().is_err());
+    }
+}
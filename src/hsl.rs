@@ -13,9 +13,23 @@
 //! See [wikipedias HSL_and_HSV](https://en.wikipedia.org/wiki/HSL_and_HSV) for more information.
 
 
-use image::{Pixel, Rgba, GenericImage, DynamicImage, RgbaImage};
+use image::{Pixel, Rgba, GenericImage, DynamicImage, RgbaImage, GrayImage, Luma};
 use std::f32;
 use std::fmt;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Number of bins per channel of a `HslHistogram` (the values of a reduced depth `Hsl` pixel).
+pub const BINS_PER_CHANNEL : usize = 16;
+/// Number of bins of a `HslHistogram`.
+pub const TOTAL_BINS : usize = BINS_PER_CHANNEL * BINS_PER_CHANNEL * BINS_PER_CHANNEL;
+
+/// Default bandwidth of `HslHistogram::similarity_by_soft_maxima()` in bins.
+pub const SOFT_MAXIMA_BANDWIDTH : f32 = 1.5;
+/// Minimum difference of lightness or alpha (0 to 255) to a neighbour for a pixel to count
+/// as edge, see `HslImage::edge_density()`.
+pub const EDGE_THRESHOLD : u8 = 48;
 
 const MAX_NUM_OF_MAXIMA : usize = 2;
 const MIN_VAL_OF_MAXIMA : f32 = 1.;
@@ -37,7 +51,7 @@ const MIN_VAL_OF_MAXIMA : f32 = 1.;
 ///
 /// If the lightness is at 0 or 255, the hue has no meaning.
 /// If the chroma is at 0, the hue has no meaning (grey values).
-#[derive(Debug,Clone,PartialEq)]
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
 pub struct Hsl {
     /// Hue (color angle: 0: red, 255: red, 128: turquoise, 64: green)
     pub h2 : u8,
@@ -63,7 +77,44 @@ impl Hsl {
                  a)
     }
 
+    /// Converts with the standard hexagonal HSL formulas (as in CSS) instead of the
+    /// approximation of `From<Rgba<u8>>`: `c2` is the chroma `max - min`, `l` is
+    /// `(max + min) / 2` and `h2` the hexagonal hue. Values are rounded.
+    ///
+    /// Slower, but `saturation_exact()` of the result is the true HSL saturation.
+    pub fn from_rgba_exact(pixel : Rgba<u8>) -> Hsl {
+        let (r, g, b, a) = pixel.channels4();
+        let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let chroma = max - min;
+        // hue in sixths of the circle
+        let h_tick = if chroma == 0. { 0. }
+                     else if max == r { ((g - b) / chroma + 6.) % 6. }
+                     else if max == g { (b - r) / chroma + 2. }
+                     else { (r - g) / chroma + 4. };
+        Hsl {
+            h2 : (h_tick / 6. * 255.).round() as u8,
+            c2 : (chroma * 255.).round() as u8,
+            l : ((max + min) / 2. * 255.).round() as u8,
+            a : a,
+        }
+    }
+
+    /// True HSL saturation (0 to 255) of a full depth pixel created by `from_rgba_exact()`:
+    /// `chroma / (1 - |2 l - 1|)`. 0 for black and white.
+    pub fn saturation_exact(&self) -> u8 {
+        let l = self.l as f32 / 255.;
+        let divisor = 1. - (2. * l - 1.).abs();
+        if divisor <= 0. {
+            return 0;
+        }
+        (self.c2 as f32 / divisor).round().min(255.) as u8
+    }
+
     /// Return saturation (since c2 is chroma, not saturation)
+    ///
+    /// This is a rough approximation, see `saturation_exact()` for the correct value.
     pub fn saturation(&self) -> u8 {
         (self.c2 as f32 / ( 1. - (2. * (self.l as f32) - 1.).abs() )) as u8
     }
@@ -112,7 +163,11 @@ impl Hsl {
         let hue = self.h2 as f32 * f32::consts::PI / 8.;
         let l = self.l as f32 * f32::consts::PI / 16. - f32::consts::PI / 2.0;
         assert!(-f32::consts::PI <= l && l <= f32::consts::PI);
-        let c = self.saturation() as f32 * f32::consts::PI / 32.;
+        // saturation of the bin centers, with chroma and lightness from 0 to 1
+        let lightness = (self.l as f32 + 0.5) / 16.;
+        let chroma = (self.c2 as f32 + 0.5) / 16.;
+        let saturation = (chroma / (1. - (2. * lightness - 1.).abs())).min(1.);
+        let c = saturation * f32::consts::PI / 2.;
         (hue, c, l)
     }
 
@@ -175,13 +230,23 @@ impl fmt::Display for Hsl {
     }
 }
 
-impl From<Rgba<u8>> for Hsl {
-    /// Converts an `Rgba` pixel into an `Hsl` pixel
-    fn from(pixel : Rgba<u8>) -> Hsl {
+/// Converts a gamma encoded sRGB channel into linear light, from 0.0 to 1.0.
+pub fn srgb_to_linear(v : u8) -> f32 {
+    let v = v as f32 / 255.0;
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+impl Hsl {
+    /// Like `From<Rgba<u8>>`, but linearizes the sRGB channels first (see
+    /// `srgb_to_linear()`), so that chroma and lightness are proportional to the light
+    /// intensity instead of the gamma encoded values. Eg. sRGB mid-grey has a lightness of 55.
+    pub fn from_rgba_linear(pixel : Rgba<u8>) -> Hsl {
         let (r, g, b, a) = pixel.channels4();
-        let r = r as f32 / 255.0;
-        let g = g as f32 / 255.0;
-        let b = b as f32 / 255.0;
+        Hsl::from_channels(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a)
+    }
+
+    /// Conversion of `From<Rgba<u8>>` for channels from 0.0 to 1.0.
+    fn from_channels(r : f32, g : f32, b : f32, a : u8) -> Hsl {
         // (alpha,beta) is a vector within the orthogonal axes (x,y) of the hexagon projection.
         // x (alpha) and red point to 0°
         // y (beta) points to 90°
@@ -216,6 +281,14 @@ impl From<Rgba<u8>> for Hsl {
     }
 }
 
+impl From<Rgba<u8>> for Hsl {
+    /// Converts an `Rgba` pixel into an `Hsl` pixel
+    fn from(pixel : Rgba<u8>) -> Hsl {
+        let (r, g, b, a) = pixel.channels4();
+        Hsl::from_channels(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, a)
+    }
+}
+
 /// An image consisting of HSL pixels
 pub struct HslImage {
     /// The pixels. This vec has len width*height.
@@ -232,11 +305,17 @@ impl HslImage {
     /// Convert RGBA image into HSL color space
     pub fn from_image<T>(rgba_img : &T) -> HslImage
         where T : GenericImage<Pixel = Rgba<u8>> {
+        HslImage::from_image_with(rgba_img, false)
+    }
+
+    /// Like `from_image()`, but converts via `Hsl::from_rgba_linear()` if `linear_light`.
+    pub fn from_image_with<T>(rgba_img : &T, linear_light : bool) -> HslImage
+        where T : GenericImage<Pixel = Rgba<u8>> {
 
         let size = (rgba_img.width() * rgba_img.height()) as usize;
         let mut hslpixels = Vec::with_capacity( size );
         for (_,_,pixel) in rgba_img.pixels() {
-            hslpixels.push( Hsl::from(pixel) );
+            hslpixels.push( if linear_light { Hsl::from_rgba_linear(pixel) } else { Hsl::from(pixel) } );
         }
 
         HslImage {
@@ -262,6 +341,12 @@ impl HslImage {
                                                      raw).unwrap())
     }
 
+    /// Convert into RGBA color space after reducing the color depth to the 4096 colors
+    /// the matcher sees, i.e. `reduce_dynamic().extend_dynamic().to_rgba()`.
+    pub fn to_reduced_rgba(&self) -> DynamicImage {
+        self.reduce_dynamic().extend_dynamic().to_rgba()
+    }
+
     /// Convert full depth HSL color space image into reduced depth HSL color space image
     /// with a total of 16^3 = 4096 different colors.
     ///
@@ -284,6 +369,100 @@ impl HslImage {
         })
     }
 
+    /// Quantizes hue, chroma and lightness of each pixel to `levels` evenly spaced
+    /// values (each value is moved to the center of its level). Produces visible
+    /// banding, like a poster.
+    pub fn posterize(&mut self, levels : u8) {
+        let levels = levels.max(1) as u32;
+        let quantize = |v : u8| {
+            let band = v as u32 * levels / 256;
+            ((2 * band + 1) * 256 / (2 * levels)) as u8
+        };
+        for p in &mut self.pixels {
+            p.h2 = quantize(p.h2);
+            p.c2 = quantize(p.c2);
+            p.l = quantize(p.l);
+        }
+    }
+
+    /// Mean of the visible pixels in the hue/chroma plane (hue as angle, chroma as radius
+    /// from 0 to 255) as cartesian `(x, y)`. Near `(0, 0)` for images without color cast.
+    pub fn color_cast(&self) -> (f32, f32) {
+        let (mut x, mut y, mut visible) = (0.0, 0.0, 0);
+        for p in self.pixels.iter().filter(|p| p.a > 204) {
+            let angle = p.h2 as f32 / 256.0 * 2.0 * f32::consts::PI;
+            x += p.c2 as f32 * angle.cos();
+            y += p.c2 as f32 * angle.sin();
+            visible += 1;
+        }
+        if visible == 0 {
+            return (0.0, 0.0);
+        }
+        (x / visible as f32, y / visible as f32)
+    }
+
+    /// Gray world white balance: Shifts all pixels in the hue/chroma plane by the negated
+    /// `color_cast()`, so that the mean becomes neutral. Lightness and alpha are kept.
+    pub fn white_balance(&mut self) {
+        let (cast_x, cast_y) = self.color_cast();
+        for p in &mut self.pixels {
+            let angle = p.h2 as f32 / 256.0 * 2.0 * f32::consts::PI;
+            let x = p.c2 as f32 * angle.cos() - cast_x;
+            let y = p.c2 as f32 * angle.sin() - cast_y;
+            let hue = y.atan2(x).rem_euclid(2.0 * f32::consts::PI) / (2.0 * f32::consts::PI) * 256.0;
+            p.h2 = (hue.round() as u32 % 256) as u8;
+            p.c2 = x.hypot(y).round().min(255.0) as u8;
+        }
+    }
+
+    /// Mean chroma of all visible pixels, from 0.0 (grey) to 1.0 (fully saturated).
+    pub fn mean_chroma(&self) -> f32 {
+        let visible : Vec<&Hsl> = self.pixels.iter().filter(|p| p.a > 204).collect();
+        if visible.is_empty() {
+            return 0.0;
+        }
+        visible.iter().map(|p| p.c2 as f32).sum::<f32>() / visible.len() as f32 / 255.0
+    }
+
+    /// Fraction of pixels (0.0: flat, 1.0: busy) on an edge, that is whose lightness or
+    /// alpha differs by more than `EDGE_THRESHOLD` from the right or lower neighbour.
+    /// Alpha counts, so that the outlines of line art emoticons are edges, too.
+    pub fn edge_density(&self) -> f32 {
+        if self.pixels.is_empty() {
+            return 0.0;
+        }
+        let differs = |a : &Hsl, b : &Hsl| {
+            (a.l as i16 - b.l as i16).abs() > EDGE_THRESHOLD as i16
+            || (a.a as i16 - b.a as i16).abs() > EDGE_THRESHOLD as i16
+        };
+        let mut edges = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let p = self.get(x, y);
+                if (x + 1 < self.width && differs(&p, &self.get(x + 1, y)))
+                   || (y + 1 < self.height && differs(&p, &self.get(x, y + 1))) {
+                    edges += 1;
+                }
+            }
+        }
+        edges as f32 / self.pixels.len() as f32
+    }
+
+    /// Calculate a histogram with a configurable number of bins per channel.
+    pub fn binned_histogram(&self, bins : Bins) -> BinnedHistogram {
+        BinnedHistogram::from_hsl_image(self, bins)
+    }
+
+    /// An image without pixels, eg. for emoticons that dropped their HSL image,
+    /// see `Emoticon::discard()`.
+    pub fn empty() -> HslImage {
+        HslImage {
+            pixels : Vec::new(),
+            height : 0,
+            width : 0,
+        }
+    }
+
     /// Get Pixel value at `(x, y)`
     pub fn get(&self, x : u32, y : u32) -> Hsl {
         self.pixels[( y * self.width + x ) as usize ].clone()
@@ -315,23 +494,402 @@ impl HslImageWithReducedDepth {
         }
     }
 
+    /// Fraction of pixels that are not visible (from 0.0 to 1.0).
+    pub fn transparency(&self) -> f32 {
+        if self.0.pixels.is_empty() {
+            return 1.0;
+        }
+        let transparent = self.0.pixels.iter().filter(|p| p.a == 0).count();
+        transparent as f32 / self.0.pixels.len() as f32
+    }
+
     /// Calculate a histogram, smooth it and find local maxima
     pub fn histogram(&self) -> HslHistogram {
         HslHistogram::from_reduced_depth_hsl_image(&self)
     }
 
+    /// Like `histogram()`, but only keeps the parts given by `mode`.
+    pub fn histogram_with_mode(&self, mode : HistogramMode) -> HslHistogram {
+        HslHistogram::from_reduced_depth_hsl_image_with_mode(self, mode)
+    }
+
+}
+
+/// Number of bins per channel for a `BinnedHistogram`.
+///
+/// Each channel of a full depth `Hsl` pixel (0..255) is divided into the given number
+/// of bins. Every value from 1 to 255 is allowed. Human color discrimination is finer
+/// in hue than in lightness, so eg. 32 hue bins and 8 lightness bins can be a good choice.
+///
+/// Only `ComparisationMethod::Correlation` supports other bins than the default 16 per
+/// channel, see `EmoimageBuilder::bins()`. The other methods work on the `HslHistogram`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bins {
+    pub hue : u8,
+    pub chroma : u8,
+    pub lightness : u8,
+}
+
+impl Default for Bins {
+    /// 16 bins per channel, like `HslImage::reduce_dynamic()`
+    fn default() -> Bins {
+        Bins { hue : BINS_PER_CHANNEL as u8, chroma : BINS_PER_CHANNEL as u8, lightness : BINS_PER_CHANNEL as u8 }
+    }
+}
+
+impl Bins {
+    /// Total number of bins.
+    pub fn count(&self) -> usize {
+        self.hue as usize * self.chroma as usize * self.lightness as usize
+    }
+
+    /// Index of the bin of a full depth pixel. Index via: `(h * chroma + c) * lightness + l`
+    pub fn index(&self, pixel : &Hsl) -> usize {
+        let h = pixel.h2 as usize * self.hue as usize / 256;
+        let c = pixel.c2 as usize * self.chroma as usize / 256;
+        let l = pixel.l as usize * self.lightness as usize / 256;
+        (h * self.chroma as usize + c) * self.lightness as usize + l
+    }
+}
+
+/// A box in reduced depth HSL color space (0 to 15 per channel), inclusive. The hue
+/// range wraps around if its start is greater than its end, eg. `(14, 1)` for reds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ColorRange {
+    pub hue : (u8, u8),
+    pub chroma : (u8, u8),
+    pub lightness : (u8, u8),
+}
+
+impl ColorRange {
+    /// Whether the reduced depth `color` lies within this range.
+    pub fn contains(&self, color : &Hsl) -> bool {
+        let within = |v : u8, (start, end) : (u8, u8)| start <= v && v <= end;
+        let hue = if self.hue.0 <= self.hue.1 {
+            within(color.h2, self.hue)
+        } else {
+            color.h2 >= self.hue.0 || color.h2 <= self.hue.1
+        };
+        hue && within(color.c2, self.chroma) && within(color.l, self.lightness)
+    }
+}
+
+/// Histogram with a configurable number of bins per channel. See `Bins`.
+///
+/// In contrast to `HslHistogram`, this is calculated from a full depth `HslImage`. It
+/// has no maxima and is only correlated, in place of the `HslHistogram` of `Correlation`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BinnedHistogram {
+    /// Number of bins per channel
+    pub bins : Bins,
+    /// Gaussian 3d smoothed color distribution. Index via `Bins::index()`
+    pub smoothed : Vec<u32>,
+}
+
+impl BinnedHistogram {
+
+    /// Calculate a histogram of all visible pixels and smooth it.
+    pub fn from_hsl_image(img : &HslImage, bins : Bins) -> BinnedHistogram {
+        let mut distribution = vec![0 ; bins.count()];
+        for p in &img.pixels {
+            if p.a > 204 { // same threshold as in `Hsl::reduce_dynamic()`
+                distribution[bins.index(p)] += 1;
+            }
+        }
+        BinnedHistogram {
+            bins : bins,
+            smoothed : BinnedHistogram::smooth(&distribution, bins),
+        }
+    }
+
+    /// Smooth via the same gaussian kernel as `HslHistogram` (see there).
+    /// Hue wraps around, neighbours outside of the chroma and lightness range are skipped.
+    fn smooth(distribution : &[u32], bins : Bins) -> Vec<u32> {
+        let (nh, nc, nl) = (bins.hue as isize, bins.chroma as isize, bins.lightness as isize);
+        let mut smoothed = vec![0 ; bins.count()];
+        for ih in 0..nh {
+            for ic in 0..nc {
+                for il in 0..nl {
+                    let mut sum = 0;
+                    for dh in -1..2isize {
+                        for dc in -1..2isize {
+                            for dl in -1..2isize {
+                                let (h, c, l) = ((ih + dh + nh) % nh, ic + dc, il + dl);
+                                if c < 0 || c >= nc || l < 0 || l >= nl {
+                                    continue;
+                                }
+                                let factor = (2 - dh.abs()) * (2 - dc.abs()) * (2 - dl.abs());
+                                sum += factor as u32 * distribution[((h * nc + c) * nl + l) as usize];
+                            }
+                        }
+                    }
+                    smoothed[((ih * nc + ic) * nl + il) as usize] = sum;
+                }
+            }
+        }
+        smoothed
+    }
+
+    /// Digest of the smoothed distribution, for caches.
+    pub fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Calculate similarity between two histogramms with equal `Bins` by correlating them.
+    pub fn similarity_by_correlation(&self, other : &BinnedHistogram) -> f32 {
+        assert_eq!(self.bins, other.bins);
+        self.smoothed.iter()
+                     .zip(other.smoothed.iter())
+                     .map(|(&a, &b)| a as f32 * b as f32)
+                     .sum()
+    }
+}
+
+/// Mean color of the visible pixels of an RGBA image, in reduced color depth.
+///
+/// The result is visible (`a` = 1) if at least half of the pixels are visible.
+pub fn mean_color<T>(rgba_img : &T) -> Hsl
+    where T : GenericImage<Pixel = Rgba<u8>> {
+
+    let mut rgb = [0u32 ; 3];
+    let mut visible = 0u32;
+    for (_, _, pixel) in rgba_img.pixels() {
+        let (r, g, b, a) = pixel.channels4();
+        if a > 204 { // same threshold as in `Hsl::reduce_dynamic()`
+            rgb[0] += r as u32;
+            rgb[1] += g as u32;
+            rgb[2] += b as u32;
+            visible += 1;
+        }
+    }
+    if visible == 0 || visible * 2 < rgba_img.width() * rgba_img.height() {
+        return Hsl::new(0, 0, 0, 0);
+    }
+    let mean = Rgba::<u8>::from_channels((rgb[0] / visible) as u8,
+                                         (rgb[1] / visible) as u8,
+                                         (rgb[2] / visible) as u8,
+                                         255);
+    Hsl::from(mean).reduce_dynamic()
+}
+
+/// Number of cells per row and per column of a `SpatialSignature`.
+pub const SPATIAL_GRID : u32 = 4;
+
+/// Downsampled version of an image: The mean color of each cell of a
+/// `SPATIAL_GRID` x `SPATIAL_GRID` grid, in reduced color depth.
+///
+/// In contrast to the histogram, this keeps the spatial structure of an image.
+/// A cell is visible (`a` = 1) if at least half of its pixels are visible.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpatialSignature {
+    /// The mean colors. Index via: cells[y * SPATIAL_GRID + x]
+    pub cells : Vec<Hsl>,
+}
+
+impl SpatialSignature {
+
+    /// Calculate the mean color of each cell of an RGBA image
+    pub fn from_image<T>(rgba_img : &T) -> SpatialSignature
+        where T : GenericImage<Pixel = Rgba<u8>> {
+
+        let grid = SPATIAL_GRID as usize;
+        // sum of r, g, b and number of visible pixels, number of all pixels
+        let mut sums = vec![([0u32 ; 3], 0u32, 0u32) ; grid * grid];
+        let (width, height) = rgba_img.dimensions();
+        for (x, y, pixel) in rgba_img.pixels() {
+            let cell = (y * SPATIAL_GRID / height) as usize * grid + (x * SPATIAL_GRID / width) as usize;
+            let (r, g, b, a) = pixel.channels4();
+            let sum = &mut sums[cell];
+            sum.2 += 1;
+            if a > 204 { // same threshold as in `Hsl::reduce_dynamic()`
+                sum.0[0] += r as u32;
+                sum.0[1] += g as u32;
+                sum.0[2] += b as u32;
+                sum.1 += 1;
+            }
+        }
+
+        let cells = sums.iter().map(|&(rgb, visible, all)| {
+            if visible == 0 || visible * 2 < all {
+                return Hsl::new(0, 0, 0, 0);
+            }
+            let mean = Rgba::<u8>::from_channels((rgb[0] / visible) as u8,
+                                                 (rgb[1] / visible) as u8,
+                                                 (rgb[2] / visible) as u8,
+                                                 255);
+            Hsl::from(mean).reduce_dynamic()
+        }).collect();
+
+        SpatialSignature { cells : cells }
+    }
+
+    /// Calculate the similarity by comparing the colors cell by cell.
+    /// Cells that are not visible in both signatures do not count.
+    pub fn similarity(&self, other : &SpatialSignature) -> f32 {
+        let mut similarity = 0.0;
+        for (mine, others) in self.cells.iter().zip(other.cells.iter()) {
+            if mine.a == 1 && others.a == 1 {
+                similarity += mine.similarity(others);
+            }
+        }
+        similarity / self.cells.len() as f32
+    }
+}
+
+/// Compact representation of a `HslHistogram`: position and size of all maxima.
+///
+/// Two histograms with the same signature are treated as equal by the similarity cache.
+/// The size is stored as the bit pattern of the `f32` to be hashable.
+pub type HistogramSignature = Vec<(u8, u8, u8, u32)>;
+
+/// Color distribution in three dimensional color space (`TOTAL_BINS` colors).
+///
+/// Index via: distribution[h2][c2][l]
+pub type Distribution = [[[u32 ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL];
+
+/// How the similarities of all pairs of maxima are combined into one similarity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aggregate {
+    /// Sum of all pairs. Rewards many overlapping (even weak) maxima.
+    Sum,
+    /// Best single pair. Rewards sharing one dominant color.
+    Max,
+    /// Mean of all pairs.
+    Mean,
+}
+
+/// Similarity of two lists of maxima, see `HslHistogram::similarity_by_maxima()`.
+fn similarity_of_maxima(mine : &[(Hsl, f32)], others : &[(Hsl, f32)], aggregate : Aggregate) -> f32 {
+    let mut distance = 0.0;
+    // compare each with every maxima, multiply by distance and max(max)
+    for mymax in mine {
+        for othermax in others {
+            let mut d = mymax.0.similarity(&othermax.0);
+            d *= (mymax.1 * othermax.1).sqrt().sqrt().sqrt().sqrt().sqrt().sqrt().sqrt().sqrt();
+            distance = match aggregate {
+                Aggregate::Sum | Aggregate::Mean => distance + d,
+                Aggregate::Max => if d > distance { d } else { distance },
+            };
+        }
+    }
+    if aggregate == Aggregate::Mean && !mine.is_empty() && !others.is_empty() {
+        distance /= (mine.len() * others.len()) as f32;
+    }
+    distance
+}
+
+/// Soft version of `similarity_of_maxima()`: Each pair of maxima contributes the product
+/// of their relative weights times a gaussian of their distance (in bins) with standard
+/// deviation `bandwidth`. Ranges from 0 to 1.
+fn soft_similarity_of_maxima(mine : &[(Hsl, f32)], others : &[(Hsl, f32)], bandwidth : f32) -> f32 {
+    let my_total : f32 = mine.iter().map(|m| m.1).sum();
+    let other_total : f32 = others.iter().map(|m| m.1).sum();
+    if my_total <= 0. || other_total <= 0. {
+        return 0.0;
+    }
+    let mut similarity = 0.0;
+    for mymax in mine {
+        for othermax in others {
+            let weight = mymax.1 / my_total * othermax.1 / other_total;
+            let gaussian = (-squared_bin_distance(&mymax.0, &othermax.0) / (2. * bandwidth * bandwidth)).exp();
+            similarity += weight * gaussian;
+        }
+    }
+    similarity
+}
+
+/// Squared euclidean distance of two reduced depth pixels in bins. The hue wraps around
+/// and counts only as much as the lower chroma of both, since grey has no hue.
+fn squared_bin_distance(a : &Hsl, b : &Hsl) -> f32 {
+    let bins = BINS_PER_CHANNEL as f32;
+    let dh = (a.h2 as f32 - b.h2 as f32).abs();
+    let dh = dh.min(bins - dh) * a.c2.min(b.c2) as f32 / (bins - 1.);
+    let dc = a.c2 as f32 - b.c2 as f32;
+    let dl = a.l as f32 - b.l as f32;
+    dh * dh + dc * dc + dl * dl
+}
+
+/// Distribution of the lightness: `distribution` summed over hue and chroma.
+fn lightness_profile(distribution : &Distribution) -> [u32 ; BINS_PER_CHANNEL] {
+    let mut sums = [0u32 ; BINS_PER_CHANNEL];
+    for plane in distribution.iter() {
+        for row in plane.iter() {
+            for (sum, &v) in sums.iter_mut().zip(row.iter()) {
+                *sum += v;
+            }
+        }
+    }
+    sums
+}
+
+/// Distribution of the chroma: `distribution` summed over hue and lightness.
+fn chroma_profile(distribution : &Distribution) -> [u32 ; BINS_PER_CHANNEL] {
+    let mut sums = [0u32 ; BINS_PER_CHANNEL];
+    for plane in distribution.iter() {
+        for (sum, row) in sums.iter_mut().zip(plane.iter()) {
+            *sum += row.iter().sum::<u32>();
+        }
+    }
+    sums
+}
+
+/// Normalized correlation of two profiles (0.0 to 1.0). 0.0 if one of them is empty.
+fn cosine_similarity(a : &[u32 ; BINS_PER_CHANNEL], b : &[u32 ; BINS_PER_CHANNEL]) -> f32 {
+    let dot = |a : &[u32 ; BINS_PER_CHANNEL], b : &[u32 ; BINS_PER_CHANNEL]| -> f32 {
+        a.iter().zip(b.iter()).map(|(&a, &b)| a as f32 * b as f32).sum()
+    };
+    let norm = (dot(a, a) * dot(b, b)).sqrt();
+    if norm == 0.0 {
+        return 0.0;
+    }
+    dot(a, b) / norm
+}
+
+/// The 27 offsets of a bin and its direct neighbours (including itself).
+fn neighbour_offsets() -> impl Iterator<Item = (isize, isize, isize)> {
+    (0..27).map(|i| (i / 9 - 1, i / 3 % 3 - 1, i % 3 - 1))
+}
+
+/// Neighbour of a bin of a `Distribution`. The hue wraps around, beyond the chroma and
+/// lightness borders there are no bins.
+fn neighbour(ih : usize, ic : usize, il : usize, dh : isize, dc : isize, dl : isize) -> Option<(usize, usize, usize)> {
+    let bins = BINS_PER_CHANNEL as isize;
+    let (c, l) = (ic as isize + dc, il as isize + dl);
+    if c < 0 || c >= bins || l < 0 || l >= bins {
+        return None;
+    }
+    Some((((ih as isize + dh + bins) % bins) as usize, c as usize, l as usize))
+}
+
+/// Which parts of a `HslHistogram` are kept after it has been built.
+///
+/// Each distribution needs 16KB. For a large set of emoticons it pays off to keep only
+/// what the chosen comparison needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistogramMode {
+    /// Keep everything.
+    Full,
+    /// Keep the smoothed distribution and the maxima. Enough for `similarity_by_correlation()`.
+    Correlation,
+    /// Keep only the maxima. Enough for `similarity_by_maxima()`.
+    ///
+    /// Emoticons loaded with this mode also drop their HSL images, see
+    /// `Emoticon::discard()` and `MaximaHistogram`.
+    Maxima,
+    /// Like `Maxima`, but the owner keeps a `SparseHistogram` of the smoothed distribution
+    /// for correlation. See `emoticons::read_emoticons_with_mode()`.
+    Sparse,
 }
 
 /// Histogram over all colors of a reduced color depth HSL image.
 pub struct HslHistogram {
-    /// Distribution of the colors in three dimensional color space (4096 colors).
-    ///
-    /// Index via: distribution[h2][c2][l]
-    pub distribution : [[[u32 ; 16] ; 16] ; 16],
-    /// Gaussian 3d smoothed (28n kernel) color distribution
-    ///
-    /// Index via: smoothed[h2][c2][l]
-    pub smoothed : [[[u32 ; 16] ; 16] ; 16],
+    /// Distribution of the colors. `None` unless built with `HistogramMode::Full`.
+    pub distribution : Option<Box<Distribution>>,
+    /// Gaussian 3d smoothed (28n kernel) color distribution.
+    /// `None` if built with `HistogramMode::Maxima`.
+    pub smoothed : Option<Box<Distribution>>,
     /// List of significant local maxima in the smoothed histogram.
     /// The first tuple gives the color (aka position), the second tuple gives an estimate of
     /// the number of pixels that have this or a similar color.
@@ -342,23 +900,56 @@ impl HslHistogram {
 
     /// Calculate a histogram, smooth it and find local maxima
     pub fn from_reduced_depth_hsl_image(img : &HslImageWithReducedDepth) -> HslHistogram {
-        let mut ret = HslHistogram {
-            distribution : [[[0 ; 16] ; 16] ; 16],
-            smoothed     : [[[0 ; 16] ; 16] ; 16],
-            maxima       : Vec::with_capacity(5),
-        };
+        HslHistogram::from_reduced_depth_hsl_image_with_mode(img, HistogramMode::Full)
+    }
+
+    /// Calculate a histogram, smooth it and find local maxima. Keep only the parts given by `mode`.
+    pub fn from_reduced_depth_hsl_image_with_mode(img : &HslImageWithReducedDepth,
+                                                  mode : HistogramMode) -> HslHistogram {
+        let mut distribution = Box::new([[[0 ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL]);
         for h in &img.0.pixels {
             if h.a == 0 { // check alpha (a can only be 0 or 1 in reduced color space)
                 continue;
             }
-            ret.distribution[h.h2 as usize][h.c2 as usize][h.l as usize] += 1;
+            distribution[h.h2 as usize][h.c2 as usize][h.l as usize] += 1;
         }
-        ret.smooth();
-        ret.find_maxima();
+        let mut smoothed = Box::new([[[0 ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL] ; BINS_PER_CHANNEL]);
+        HslHistogram::smooth(&distribution, &mut smoothed);
+        let mut ret = HslHistogram {
+            maxima       : HslHistogram::find_maxima(&smoothed),
+            distribution : Some(distribution),
+            smoothed     : Some(smoothed),
+        };
+        ret.sort_maxima();
+        ret.discard(mode);
         ret
     }
 
-    /// Smooth via gaussian kernel
+    /// Whether the parts needed for `mode` have been kept.
+    pub fn retains(&self, mode : HistogramMode) -> bool {
+        match mode {
+            HistogramMode::Full => self.distribution.is_some() && self.smoothed.is_some(),
+            HistogramMode::Correlation => self.smoothed.is_some(),
+            HistogramMode::Maxima | HistogramMode::Sparse => true,
+        }
+    }
+
+    /// Frees the parts that are not needed for `mode`.
+    pub fn discard(&mut self, mode : HistogramMode) {
+        match mode {
+            HistogramMode::Full => {},
+            HistogramMode::Correlation => {
+                self.distribution = None;
+            },
+            HistogramMode::Maxima | HistogramMode::Sparse => {
+                self.distribution = None;
+                self.smoothed = None;
+            },
+        }
+    }
+
+    /// Smooth via gaussian kernel. The hue wraps around, the chroma and lightness
+    /// borders are padded with zeros.
     ///            1-----2------1
     ///       2    | 4     2    |
     ///  1------2------1        |
@@ -376,43 +967,18 @@ impl HslHistogram {
     /// ^ ih    > ic     / il
     ///
     /// sum = 8*1 + 12*2 + 6*4 + 8 = 64
-    fn smooth(&mut self) {
-        for ih in 1..15 {
-            for ic in 1..15 {
-                for il in 1..15 {
-                    self.smoothed[ih][ic][il] =
-                        // top (ih += 1)
-                        1 * 1 * 1 * self.distribution[ih+1][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                        1 * 1 * 2 * self.distribution[ih+1][ic-1][il+0] +
-                        1 * 1 * 1 * self.distribution[ih+1][ic-1][il+1] +                    // back (il += 1)
-                        1 * 2 * 1 * self.distribution[ih+1][ic+0][il-1] + // mid ( ic += 0)  // front (il -=1)
-                        1 * 2 * 2 * self.distribution[ih+1][ic+0][il+0] +
-                        1 * 2 * 1 * self.distribution[ih+1][ic+0][il+1] +                    // back (il += 1)
-                        1 * 1 * 1 * self.distribution[ih+1][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                        1 * 1 * 2 * self.distribution[ih+1][ic+1][il+0] +
-                        1 * 1 * 1 * self.distribution[ih+1][ic+1][il+1] +                    // back (il += 1)
-
-                        // mid (ih += 0)
-                        2 * 1 * 1 * self.distribution[ih+0][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                        2 * 1 * 2 * self.distribution[ih+0][ic-1][il+0] +
-                        2 * 1 * 1 * self.distribution[ih+0][ic-1][il+1] +                    // back (il += 1)
-                        2 * 2 * 1 * self.distribution[ih+0][ic+0][il-1] + // mid ( ic += 0) // front (il -=1)
-                        2 * 2 * 2 * self.distribution[ih+0][ic+0][il+0] +
-                        2 * 2 * 1 * self.distribution[ih+0][ic+0][il+1] +                    // back (il += 1)
-                        2 * 1 * 1 * self.distribution[ih+0][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                        2 * 1 * 2 * self.distribution[ih+0][ic+1][il+0] +
-                        2 * 1 * 1 * self.distribution[ih+0][ic+1][il+1] +                    // back (il += 1)
-
-                        // bot (ih -= 1)
-                        1 * 1 * 1 * self.distribution[ih-1][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                        1 * 1 * 2 * self.distribution[ih-1][ic-1][il+0] +
-                        1 * 1 * 1 * self.distribution[ih-1][ic-1][il+1] +                    // back (il += 1)
-                        1 * 2 * 1 * self.distribution[ih-1][ic+0][il-1] + // mid ( ic += 0) // front (il -=1)
-                        1 * 2 * 2 * self.distribution[ih-1][ic+0][il+0] +
-                        1 * 2 * 1 * self.distribution[ih-1][ic+0][il+1] +                    // back (il += 1)
-                        1 * 1 * 1 * self.distribution[ih-1][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                        1 * 1 * 2 * self.distribution[ih-1][ic+1][il+0] +
-                        1 * 1 * 1 * self.distribution[ih-1][ic+1][il+1] ;                    // back (il += 1)
+    fn smooth(distribution : &Distribution, smoothed : &mut Distribution) {
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    let mut sum = 0;
+                    for (dh, dc, dl) in neighbour_offsets() {
+                        if let Some((h, c, l)) = neighbour(ih, ic, il, dh, dc, dl) {
+                            let factor = (2 - dh.abs()) * (2 - dc.abs()) * (2 - dl.abs());
+                            sum += factor as u32 * distribution[h][c][l];
+                        }
+                    }
+                    smoothed[ih][ic][il] = sum;
                 }
             }
         }
@@ -421,93 +987,42 @@ impl HslHistogram {
     /// Finds maxima within the smoothed histogram.
     ///
     /// Strategy:
-    /// Look at all 27 neighbours of a non-border color. Set as maxima if no
-    /// neighbours are greater. (For neighbours to the right-bot-back direction,
-    /// use >= instead of >, so that two equal values will only generate one maximum).
+    /// Look at all 27 neighbours of a color (hue wraps around, bins beyond the chroma
+    /// and lightness borders count as 0). Set as maxima if no neighbours are greater.
+    /// (For neighbours to the right-bot-back direction, use >= instead of >, so that two
+    /// equal values will only generate one maximum).
     ///
     /// After finding maximas, calculate the size of the corresponding maxima
     /// by adding up all direct neighbours values. Take into account that the smoothed
     /// values are not normalized: divide by 64
     ///
-    /// Sore the maximas. Only keep 5. Discard little maximas.
-    fn find_maxima(&mut self) {
-
-        for ih in 1..15 {
-            for ic in 1..15 {
-                for il in 1..15 {
-                    let center = self.smoothed[ih][ic][il];
+    /// The maxima still have to be sorted and filtered via `sort_maxima()`.
+    fn find_maxima(smoothed : &Distribution) -> Vec<(Hsl, f32)> {
+        let mut maxima = Vec::with_capacity(5);
+
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    let center = smoothed[ih][ic][il];
                     if center == 0 { continue; }
-                    let found_greater =
-                        // top (ih += 1)
-                             if self.smoothed[ih+1][ic-1][il-1] > center { true } // left ( ic -= 1) // front (il -=1)
-                        else if self.smoothed[ih+1][ic-1][il+0] > center { true }
-                        else if self.smoothed[ih+1][ic-1][il+1] > center { true }                    // back (il += 1)
-                        else if self.smoothed[ih+1][ic+0][il-1] > center { true } // mid ( ic += 0)  // front (il -=1)
-                        else if self.smoothed[ih+1][ic+0][il+0] > center { true }
-                        else if self.smoothed[ih+1][ic+0][il+1] > center { true }                    // back (il += 1)
-                        else if self.smoothed[ih+1][ic+1][il-1] > center { true } // right ( ic += 1) // front (il -=1)
-                        else if self.smoothed[ih+1][ic+1][il+0] > center { true }
-                        else if self.smoothed[ih+1][ic+1][il+1] > center { true }                    // back (il += 1)
-
-                        // mid (ih += 0)
-                        else if self.smoothed[ih+0][ic-1][il-1] > center { true } // left ( ic -= 1) // front (il -=1)
-                        else if self.smoothed[ih+0][ic-1][il+0] > center { true }
-                        else if self.smoothed[ih+0][ic-1][il+1] > center { true }                    // back (il += 1)
-                        else if self.smoothed[ih+0][ic+0][il-1] > center { true } // mid ( ic += 0) // front (il -=1)
-                        //else if self.smoothed[ih+0][ic+0][il+0] > center { true }
-                        else if self.smoothed[ih+0][ic+0][il+1] >= center { true }                    // back (il += 1)
-                        else if self.smoothed[ih+0][ic+1][il-1] > center { true } // right ( ic += 1) // front (il -=1)
-                        else if self.smoothed[ih+0][ic+1][il+0] >= center { true }
-                        else if self.smoothed[ih+0][ic+1][il+1] >= center { true }                    // back (il += 1)
-
-                        // bot (ih -= 1)
-                        else if self.smoothed[ih-1][ic-1][il-1] > center { true } // left ( ic -= 1) // front (il -=1)
-                        else if self.smoothed[ih-1][ic-1][il+0] > center { true }
-                        else if self.smoothed[ih-1][ic-1][il+1] > center { true }                    // back (il += 1)
-                        else if self.smoothed[ih-1][ic+0][il-1] > center { true } // mid ( ic += 0) // front (il -=1)
-                        else if self.smoothed[ih-1][ic+0][il+0] >= center { true }
-                        else if self.smoothed[ih-1][ic+0][il+1] >= center { true }                    // back (il += 1)
-                        else if self.smoothed[ih-1][ic+1][il-1] > center { true } // right ( ic += 1) // front (il -=1)
-                        else if self.smoothed[ih-1][ic+1][il+0] >= center { true }
-                        else if self.smoothed[ih-1][ic+1][il+1] >= center { true }                    // back (il += 1)
-                        else { false } ;
+                    let mut found_greater = false;
+                    let mut sum = center;
+                    for (dh, dc, dl) in neighbour_offsets().filter(|&o| o != (0, 0, 0)) {
+                        let value = match neighbour(ih, ic, il, dh, dc, dl) {
+                            Some((h, c, l)) => smoothed[h][c][l],
+                            None => 0,
+                        };
+                        // ties are won by the neighbours in the bot-right-back direction
+                        let wins_ties = dh <= 0 && dc >= 0 && dl >= 0;
+                        if value > center || (wins_ties && value == center) {
+                            found_greater = true;
+                            break;
+                        }
+                        sum += value;
+                    }
 
                     if ! found_greater {
-                        let sum =
-                            // top (ih += 1)
-                            self.smoothed[ih+1][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                            self.smoothed[ih+1][ic-1][il+0] +
-                            self.smoothed[ih+1][ic-1][il+1] +                    // back (il += 1)
-                            self.smoothed[ih+1][ic+0][il-1] + // mid ( ic += 0)  // front (il -=1)
-                            self.smoothed[ih+1][ic+0][il+0] +
-                            self.smoothed[ih+1][ic+0][il+1] +                    // back (il += 1)
-                            self.smoothed[ih+1][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                            self.smoothed[ih+1][ic+1][il+0] +
-                            self.smoothed[ih+1][ic+1][il+1] +                    // back (il += 1)
-
-                            // mid (ih += 0)
-                            self.smoothed[ih+0][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                            self.smoothed[ih+0][ic-1][il+0] +
-                            self.smoothed[ih+0][ic-1][il+1] +                    // back (il += 1)
-                            self.smoothed[ih+0][ic+0][il-1] + // mid ( ic += 0) // front (il -=1)
-                            self.smoothed[ih+0][ic+0][il+0] +
-                            self.smoothed[ih+0][ic+0][il+1] +                    // back (il += 1)
-                            self.smoothed[ih+0][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                            self.smoothed[ih+0][ic+1][il+0] +
-                            self.smoothed[ih+0][ic+1][il+1] +                    // back (il += 1)
-
-                            // bot (ih -= 1)
-                            self.smoothed[ih-1][ic-1][il-1] + // left ( ic -= 1) // front (il -=1)
-                            self.smoothed[ih-1][ic-1][il+0] +
-                            self.smoothed[ih-1][ic-1][il+1] +                    // back (il += 1)
-                            self.smoothed[ih-1][ic+0][il-1] + // mid ( ic += 0) // front (il -=1)
-                            self.smoothed[ih-1][ic+0][il+0] +
-                            self.smoothed[ih-1][ic+0][il+1] +                    // back (il += 1)
-                            self.smoothed[ih-1][ic+1][il-1] + // right ( ic += 1) // front (il -=1)
-                            self.smoothed[ih-1][ic+1][il+0] +
-                            self.smoothed[ih-1][ic+1][il+1] ;                    // back (il += 1)
-
-                        self.maxima.push((Hsl{
+                        maxima.push((Hsl{
                                                 h2 : ih as u8,
                                                 c2 : ic as u8,
                                                 l  : il as u8,
@@ -519,7 +1034,20 @@ impl HslHistogram {
                 }
             }
         }
-        self.sort_maxima();
+        maxima
+    }
+
+    /// Up to `n` local maxima of the smoothed distribution, biggest first. Unlike
+    /// `maxima`, this is not limited to the two biggest ones. Little maxima are discarded.
+    ///
+    /// Panics if the smoothed distribution has been discarded.
+    pub fn biggest_maxima(&self, n : usize) -> Vec<(Hsl, f32)> {
+        let smoothed = self.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let mut maxima = HslHistogram::find_maxima(smoothed);
+        maxima.retain(|m| m.1 >= MIN_VAL_OF_MAXIMA);
+        maxima.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        maxima.truncate(n);
+        maxima
     }
 
     /// Sort the maxima. Smallest first. Only keep 5. Discard little maximas.
@@ -530,28 +1058,178 @@ impl HslHistogram {
         }
     }
 
+    /// The most frequent color (in reduced color depth).
+    ///
+    /// This is the biggest maximum or, if there are no significant maxima, the
+    /// most frequent color of the unsmoothed (or, if discarded, the smoothed) distribution.
+    /// `None` if the image has no visible pixels or if both distributions have been discarded.
+    pub fn dominant_color(&self) -> Option<Hsl> {
+        if let Some(maximum) = self.maxima.last() {
+            return Some(maximum.0.clone());
+        }
+        let distribution = self.distribution.as_ref().or(self.smoothed.as_ref())?;
+        let mut dominant = None;
+        let mut highest_count = 0;
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    if distribution[ih][ic][il] > highest_count {
+                        highest_count = distribution[ih][ic][il];
+                        dominant = Some(Hsl::new(ih as u8, ic as u8, il as u8, 1));
+                    }
+                }
+            }
+        }
+        dominant
+    }
+
+    /// The dominant color (see `dominant_color()`) and the share (0.0 to 1.0) of the visible
+    /// pixels whose color is at most one bin away from it in each channel.
+    /// `None` if the image has no visible pixels or if the distribution has been discarded.
+    pub fn dominant_share(&self) -> Option<(Hsl, f32)> {
+        let distribution = self.distribution.as_ref()?;
+        let dominant = self.dominant_color()?;
+        let mut total = 0;
+        let mut near = 0;
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    let count = distribution[ih][ic][il];
+                    total += count;
+                    if squared_bin_distance(&dominant, &Hsl::new(ih as u8, ic as u8, il as u8, 1)) <= 3. {
+                        near += count;
+                    }
+                }
+            }
+        }
+        if total == 0 {
+            return None;
+        }
+        Some((dominant, near as f32 / total as f32))
+    }
+
+    /// Digest of the smoothed distribution, for caches of comparisons that correlate the
+    /// whole distribution. `None` if it has been discarded.
+    pub fn smoothed_digest(&self) -> Option<u64> {
+        let smoothed = self.smoothed.as_ref()?;
+        let mut hasher = DefaultHasher::new();
+        smoothed.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Returns the sorted maxima as compact, hashable signature.
+    pub fn signature(&self) -> HistogramSignature {
+        self.maxima.iter()
+                   .map(|&(ref c, size)| (c.h2, c.c2, c.l, size.to_bits()))
+                   .collect()
+    }
+
     /// Calculate similarity between two histograms by comparing maxima.
     pub fn similarity_by_maxima(&self, other : &HslHistogram) -> f32 {
-        let mut distance = 0.0;
-        // compare each with every maxima, multiply by distance and max(max)
-        for mymax in &self.maxima {
-            for othermax in &other.maxima {
-                let mut d = mymax.0.similarity(&othermax.0);
-                d *= (mymax.1 * othermax.1).sqrt().sqrt().sqrt().sqrt().sqrt().sqrt().sqrt().sqrt();
-                distance += d;
+        similarity_of_maxima(&self.maxima, &other.maxima, Aggregate::Sum)
+    }
+
+    /// Like `similarity_by_maxima()`, but combines the similarities of the pairs of
+    /// maxima as given by `aggregate`.
+    pub fn similarity_by_maxima_with(&self, other : &HslHistogram, aggregate : Aggregate) -> f32 {
+        similarity_of_maxima(&self.maxima, &other.maxima, aggregate)
+    }
+
+    /// Calculate similarity between two histogramms by their maxima, but each pair of
+    /// maxima is weighted by a gaussian of their distance (with standard deviation
+    /// `bandwidth` in bins). Unlike `similarity_by_maxima()`, colors near a bin edge
+    /// do not jump between matches.
+    pub fn similarity_by_soft_maxima(&self, other : &HslHistogram, bandwidth : f32) -> f32 {
+        soft_similarity_of_maxima(&self.maxima, &other.maxima, bandwidth)
+    }
+
+    /// Sums blocks of `factor`³ adjacent bins of the smoothed distribution, eg. `2` for
+    /// 8³ bins. `factor` has to divide `BINS_PER_CHANNEL`.
+    ///
+    /// Panics if `factor` does not divide `BINS_PER_CHANNEL` or if the smoothed distribution
+    /// has been discarded.
+    pub fn downsample(&self, factor : usize) -> CoarseHistogram {
+        assert!(factor > 0 && BINS_PER_CHANNEL.is_multiple_of(factor), "factor {} does not divide {}", factor, BINS_PER_CHANNEL);
+        let smoothed = self.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let bins = BINS_PER_CHANNEL / factor;
+        let mut coarse = vec![0 ; bins * bins * bins];
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    coarse[(ih / factor * bins + ic / factor) * bins + il / factor] += smoothed[ih][ic][il];
+                }
+            }
+        }
+        CoarseHistogram {
+            bins_per_channel : bins,
+            smoothed : coarse,
+        }
+    }
+
+    /// Renders the smoothed distribution as grayscale image for debugging (white: highest value).
+    ///
+    /// Each hue `h2` is a 16x16 tile with chroma as rows and lightness as columns (like
+    /// the `Display` output). The tiles are stacked vertically, so the image is 16x256 pixels.
+    /// Uses the unsmoothed distribution if the smoothed one has been discarded.
+    pub fn to_debug_image(&self) -> DynamicImage {
+        let size = BINS_PER_CHANNEL as u32;
+        let mut img = GrayImage::new(size, size * size);
+        let distribution = match self.smoothed.as_ref().or(self.distribution.as_ref()) {
+            Some(distribution) => distribution,
+            None => return DynamicImage::ImageLuma8(img),
+        };
+        let max = distribution.iter().flat_map(|p| p.iter()).flat_map(|r| r.iter()).cloned().max().unwrap_or(0);
+        if max > 0 {
+            for (ih, plane) in distribution.iter().enumerate() {
+                for (ic, row) in plane.iter().enumerate() {
+                    for (il, &v) in row.iter().enumerate() {
+                        let value = (v as u64 * 255 / max as u64) as u8;
+                        img.put_pixel(il as u32, (ih * BINS_PER_CHANNEL + ic) as u32, Luma([value]));
+                    }
+                }
             }
         }
-        distance
+        DynamicImage::ImageLuma8(img)
+    }
+
+    /// Calculate similarity between two histogramms by correlating their lightness
+    /// distributions. Hue and chroma are ignored.
+    ///
+    /// The correlation is normalized (cosine similarity, 0.0 to 1.0), since grey colors lie
+    /// at the border of the histogram, where smoothing loses most of their weight.
+    ///
+    /// Panics if one of the histograms has been built with `HistogramMode::Maxima`.
+    pub fn similarity_by_lightness(&self, other : &HslHistogram) -> f32 {
+        let mine = self.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let others = other.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        cosine_similarity(&lightness_profile(mine), &lightness_profile(others))
+    }
+
+    /// Calculate similarity between two histogramms by correlating their chroma
+    /// distributions. Hue and lightness are ignored, so this matches by "how colorful"
+    /// rather than by "what color".
+    ///
+    /// Normalized like `similarity_by_lightness()`.
+    ///
+    /// Panics if one of the histograms has been built with `HistogramMode::Maxima`.
+    pub fn similarity_by_chroma(&self, other : &HslHistogram) -> f32 {
+        let mine = self.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let others = other.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        cosine_similarity(&chroma_profile(mine), &chroma_profile(others))
     }
 
     /// Calculate similarity between two histogramms by correlating them.
+    ///
+    /// Panics if one of the histograms has been built with `HistogramMode::Maxima`.
     pub fn similarity_by_correlation(&self, other : &HslHistogram) -> f32 {
+        let mine = self.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let others = other.smoothed.as_ref().expect("smoothed distribution has been discarded");
         let mut correlation = 0.0;
-        for ih in 0..16 {
-            for ic in 0..16 {
-                for il in 0..16 {
-                    correlation += self.smoothed[ih][ic][il] as f32 *
-                                   other.smoothed[ih][ic][il] as f32;
+        for ih in 0..BINS_PER_CHANNEL {
+            for ic in 0..BINS_PER_CHANNEL {
+                for il in 0..BINS_PER_CHANNEL {
+                    correlation += mine[ih][ic][il] as f32 *
+                                   others[ih][ic][il] as f32;
                 }
             }
         }
@@ -562,15 +1240,19 @@ impl HslHistogram {
 
 impl fmt::Display for HslHistogram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-       for ih in 0..16 {
+       let smoothed = match self.smoothed {
+           Some(ref smoothed) => smoothed,
+           None => return write!(f, "maxima: {:?}", self.maxima),
+       };
+       for ih in 0..BINS_PER_CHANNEL {
             try!(write!(f, "\n\nh2:{}\n      l:    ", ih));
-            for il in 0..16 {
+            for il in 0..BINS_PER_CHANNEL {
                 try!(write!(f, "{:4}", il));
             }
-            for ic in 0..16 {
+            for ic in 0..BINS_PER_CHANNEL {
                 try!(write!(f, "\n c2:{:4}  # ", ic));
-                for il in 0..16 {
-                    try!(write!(f, "{:4}", self.smoothed[ih][ic][il]));
+                for il in 0..BINS_PER_CHANNEL {
+                    try!(write!(f, "{:4}", smoothed[ih][ic][il]));
                 }
                 try!(write!(f, " #"));
             }
@@ -579,13 +1261,167 @@ impl fmt::Display for HslHistogram {
     }
 }
 
+/// Memory saving version of a `HslHistogram` for large sets of emoticons: Only the
+/// non zero bins of the smoothed distribution are stored.
+///
+/// Most bins of an emoticon histogram are zero, so this needs a fraction of the
+/// 16KB of a dense distribution.
+#[derive(Debug, Clone)]
+pub struct SparseHistogram {
+    /// Non zero bins of the smoothed distribution. The key is the flat index
+    /// `(h2 * BINS_PER_CHANNEL + c2) * BINS_PER_CHANNEL + l`.
+    pub smoothed : HashMap<u16, u32>,
+    /// Same as `HslHistogram::maxima`.
+    pub maxima : Vec<(Hsl, f32)>,
+}
+
+impl SparseHistogram {
+    /// Copies the non zero bins of the smoothed distribution of `hist`.
+    ///
+    /// Panics if the smoothed distribution has been discarded.
+    pub fn from_histogram(hist : &HslHistogram) -> SparseHistogram {
+        let dense = hist.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        let mut smoothed = HashMap::new();
+        for (ih, plane) in dense.iter().enumerate() {
+            for (ic, row) in plane.iter().enumerate() {
+                for (il, &v) in row.iter().enumerate() {
+                    if v != 0 {
+                        smoothed.insert(((ih * BINS_PER_CHANNEL + ic) * BINS_PER_CHANNEL + il) as u16, v);
+                    }
+                }
+            }
+        }
+        SparseHistogram {
+            smoothed : smoothed,
+            maxima : hist.maxima.clone(),
+        }
+    }
+
+    /// Same as `HslHistogram::similarity_by_correlation()`, iterates only over the non zero bins.
+    pub fn similarity_by_correlation(&self, other : &SparseHistogram) -> f32 {
+        let (smaller, larger) = if self.smoothed.len() <= other.smoothed.len() {
+            (&self.smoothed, &other.smoothed)
+        } else {
+            (&other.smoothed, &self.smoothed)
+        };
+        smaller.iter()
+               .filter_map(|(bin, &a)| larger.get(bin).map(|&b| a as f32 * b as f32))
+               .sum()
+    }
+
+    /// Same as `HslHistogram::similarity_by_correlation()` with a dense histogram.
+    /// Iterates only over the non zero bins of this histogram.
+    ///
+    /// Panics if the smoothed distribution of `other` has been discarded.
+    pub fn similarity_by_correlation_with_dense(&self, other : &HslHistogram) -> f32 {
+        let dense = other.smoothed.as_ref().expect("smoothed distribution has been discarded");
+        self.smoothed.iter().map(|(&bin, &a)| {
+            let bin = bin as usize;
+            let l = bin % BINS_PER_CHANNEL;
+            let c = bin / BINS_PER_CHANNEL % BINS_PER_CHANNEL;
+            let h = bin / BINS_PER_CHANNEL / BINS_PER_CHANNEL;
+            a as f32 * dense[h][c][l] as f32
+        }).sum()
+    }
+
+    /// Same as `HslHistogram::similarity_by_maxima()`.
+    pub fn similarity_by_maxima(&self, other : &SparseHistogram) -> f32 {
+        similarity_of_maxima(&self.maxima, &other.maxima, Aggregate::Sum)
+    }
+}
+
+/// Compact stand-in for an image when only maxima are compared (`ComparisationMethod::Maxima`
+/// and `SoftMaxima`): The maxima and the size of the image, without the pixels of the
+/// `HslImage` and the `HslImageWithReducedDepth` and without the distributions.
+///
+/// A few bytes instead of 32KB for the distributions plus 4 bytes per pixel for each
+/// HSL image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaximaHistogram {
+    /// Width of the image
+    pub width : u32,
+    /// Height of the image
+    pub height : u32,
+    /// Same as `HslHistogram::maxima`.
+    pub maxima : Vec<(Hsl, f32)>,
+}
+
+impl MaximaHistogram {
+    /// Calculates the histogram of `img` and keeps only its maxima.
+    pub fn from_hsl_image(img : &HslImage) -> MaximaHistogram {
+        MaximaHistogram::from_histogram(&img.reduce_dynamic().histogram(), img.width, img.height)
+    }
+
+    /// Copies the maxima of `hist`, which belongs to an image of size `width` × `height`.
+    pub fn from_histogram(hist : &HslHistogram, width : u32, height : u32) -> MaximaHistogram {
+        MaximaHistogram {
+            width : width,
+            height : height,
+            maxima : hist.maxima.clone(),
+        }
+    }
+
+    /// Same as `HslHistogram::similarity_by_maxima_with()`.
+    pub fn similarity_by_maxima_with(&self, other : &HslHistogram, aggregate : Aggregate) -> f32 {
+        similarity_of_maxima(&self.maxima, &other.maxima, aggregate)
+    }
+
+    /// Same as `HslHistogram::similarity_by_soft_maxima()`.
+    pub fn similarity_by_soft_maxima(&self, other : &HslHistogram, bandwidth : f32) -> f32 {
+        soft_similarity_of_maxima(&self.maxima, &other.maxima, bandwidth)
+    }
+}
+
+/// A `HslHistogram` with `BINS_PER_CHANNEL / factor` bins per channel, made by summing
+/// blocks of `factor`³ adjacent bins of the smoothed distribution. See `HslHistogram::downsample()`.
+///
+/// Comparing coarse histograms is much cheaper, so a coarse pass can prune the
+/// candidates before the full resolution pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoarseHistogram {
+    /// Number of bins per channel
+    pub bins_per_channel : usize,
+    /// Index via `(h * bins_per_channel + c) * bins_per_channel + l`
+    pub smoothed : Vec<u32>,
+}
+
+impl CoarseHistogram {
+    /// Like `HslHistogram::similarity_by_correlation()`.
+    ///
+    /// Panics if the histograms have different numbers of bins.
+    pub fn similarity_by_correlation(&self, other : &CoarseHistogram) -> f32 {
+        assert_eq!(self.bins_per_channel, other.bins_per_channel);
+        self.smoothed.iter()
+                     .zip(other.smoothed.iter())
+                     .map(|(&a, &b)| a as f32 * b as f32)
+                     .sum()
+    }
+
+    /// Histogram intersection: The shared fraction of both distributions, from 0.0 to 1.0.
+    /// Unlike the correlation, it does not depend on the number of pixels.
+    ///
+    /// Panics if the histograms have different numbers of bins.
+    pub fn similarity_by_intersection(&self, other : &CoarseHistogram) -> f32 {
+        assert_eq!(self.bins_per_channel, other.bins_per_channel);
+        let my_total : u32 = self.smoothed.iter().sum();
+        let other_total : u32 = other.smoothed.iter().sum();
+        if my_total == 0 || other_total == 0 {
+            return 0.0;
+        }
+        self.smoothed.iter()
+                     .zip(other.smoothed.iter())
+                     .map(|(&a, &b)| (a as f32 / my_total as f32).min(b as f32 / other_total as f32))
+                     .sum()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::path::{Path};
     use std::fs::File;
     use image;
-    use image::Rgba;
+    use image::{Rgba, RgbaImage};
 
     #[test]
     fn convert_and_back() {
@@ -599,7 +1435,8 @@ mod tests {
         println!("\nPx 25,25 @ hsl     {:?}", hsl.get(25, 25));
 
         let ref mut fout = File::create(&Path::new("out/hsltest_convert_and_back_reduced.png")).unwrap();
-        let _ = hslreduced.extend_dynamic().to_rgba().save(fout, image::PNG).unwrap();
+        let _ = hsl.to_reduced_rgba().save(fout, image::PNG).unwrap();
+        assert!(hsl.to_reduced_rgba().raw_pixels() == hslreduced.extend_dynamic().to_rgba().raw_pixels());
     }
 
     fn check_similarity(a : Hsl, b : Hsl) -> String {
@@ -639,6 +1476,206 @@ mod tests {
         fout.write_all(&ascihist.as_bytes()).unwrap();
     }
 
+    #[test]
+    fn histogram_debug_image() {
+        let green = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([60, 160, 60, 255])));
+        let reduced = green.reduce_dynamic();
+        let hist = reduced.histogram();
+        let img = hist.to_debug_image();
+        assert_eq!(img.dimensions(), (16, 256));
+        let peak = &reduced.0.pixels[0];
+        let y = peak.h2 as u32 * 16 + peak.c2 as u32;
+        assert_eq!(img.get_pixel(peak.l as u32, y).data[0], 255);
+    }
+
+    #[test]
+    fn posterize() {
+        let mut img = HslImage::from_image(&RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 255])));
+        img.pixels[0] = Hsl::new(10, 100, 130, 255);
+        img.pixels[1] = Hsl::new(250, 127, 128, 255);
+        img.posterize(2);
+        assert_eq!(img.pixels[0], Hsl::new(64, 64, 192, 255));
+        assert_eq!(img.pixels[1], Hsl::new(192, 64, 192, 255));
+
+        // Levels that do not divide 256 still give exactly that many bands
+        let mut img = HslImage::from_image(&RgbaImage::from_pixel(256, 1, Rgba([0, 0, 0, 255])));
+        for (i, p) in img.pixels.iter_mut().enumerate() {
+            *p = Hsl::new(i as u8, i as u8, i as u8, 255);
+        }
+        img.posterize(3);
+        let mut bands : Vec<u8> = img.pixels.iter().map(|p| p.l).collect();
+        bands.dedup();
+        assert_eq!(bands, vec![42, 128, 213]);
+        assert_eq!((img.pixels[85].l, img.pixels[86].l, img.pixels[255].l), (42, 128, 213));
+    }
+
+    #[test]
+    fn maxima_histogram_matches_like_full_one() {
+        let img = HslImage::from_image(&RgbaImage::from_fn(16, 8, |x, _| {
+            if x < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+        let full = img.reduce_dynamic().histogram();
+        let compact = MaximaHistogram::from_hsl_image(&img);
+        assert_eq!((compact.width, compact.height), (16, 8));
+        assert_eq!(compact.maxima, full.maxima);
+        let chunk = HslImage::from_image(&RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]))).reduce_dynamic().histogram();
+        assert_eq!(compact.similarity_by_maxima_with(&chunk, Aggregate::Sum), full.similarity_by_maxima(&chunk));
+        assert_eq!(compact.similarity_by_soft_maxima(&chunk, 1.5), full.similarity_by_soft_maxima(&chunk, 1.5));
+    }
+
+    #[test]
+    fn white_balance() {
+        // A warm cast over a grey wall with a blue spot
+        let mut img = HslImage::from_image(&RgbaImage::from_fn(16, 1, |x, _| {
+            if x < 15 { Rgba([200, 170, 140, 255]) } else { Rgba([90, 110, 200, 255]) }
+        }));
+        let (x, y) = img.color_cast();
+        assert!(x.hypot(y) > 10.0);
+        let tint = img.pixels[0].c2;
+        img.white_balance();
+        let (x, y) = img.color_cast();
+        assert!(x.hypot(y) < 2.0, "{} {}", x, y);
+        // The grey lost most of its tint, the lightness stays
+        assert!(img.pixels[0].c2 * 2 < tint, "{:?}", img.pixels[0]);
+        assert_eq!(img.pixels[0].l, HslImage::from_image(&RgbaImage::from_pixel(1, 1, Rgba([200, 170, 140, 255]))).pixels[0].l);
+    }
+
+    #[test]
+    fn sparse_histogram() {
+        let green = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([60, 160, 60, 255]))).reduce_dynamic().histogram();
+        let grey = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([90, 100, 90, 255]))).reduce_dynamic().histogram();
+        let sparse_green = SparseHistogram::from_histogram(&green);
+        let sparse_grey = SparseHistogram::from_histogram(&grey);
+        assert!(sparse_green.smoothed.len() <= 27);
+        assert_eq!(sparse_green.similarity_by_correlation(&sparse_grey), green.similarity_by_correlation(&grey));
+        assert_eq!(sparse_green.similarity_by_correlation_with_dense(&grey), green.similarity_by_correlation(&grey));
+        assert_eq!(sparse_green.similarity_by_maxima(&sparse_grey), green.similarity_by_maxima(&grey));
+    }
+
+    #[test]
+    fn coarse_histogram() {
+        let green = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([60, 160, 60, 255]))).reduce_dynamic().histogram();
+        let grey = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([90, 100, 90, 255]))).reduce_dynamic().histogram();
+        let blue = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]))).reduce_dynamic().histogram();
+        // Factor 1 keeps the bins
+        assert_eq!(green.downsample(1).similarity_by_correlation(&grey.downsample(1)), green.similarity_by_correlation(&grey));
+        let coarse = green.downsample(2);
+        assert_eq!(coarse.bins_per_channel, 8);
+        assert_eq!(coarse.smoothed.len(), 512);
+        let total = |h : &HslHistogram| h.smoothed.as_ref().unwrap().iter().flat_map(|p| p.iter()).flat_map(|r| r.iter()).sum::<u32>();
+        assert_eq!(coarse.smoothed.iter().sum::<u32>(), total(&green));
+        assert!((coarse.similarity_by_intersection(&coarse) - 1.0).abs() < 1e-5);
+        assert_eq!(coarse.similarity_by_intersection(&blue.downsample(2)), 0.0);
+        assert!(coarse.similarity_by_correlation(&grey.downsample(2)) >= green.similarity_by_correlation(&grey));
+    }
+
+    #[test]
+    fn binned_histogram() {
+        let bins = Bins { hue : 32, chroma : 8, lightness : 4 };
+        let red = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let orange = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([255, 128, 0, 255])));
+        let red_hist = red.binned_histogram(bins);
+        let orange_hist = orange.binned_histogram(bins);
+        assert_eq!(red_hist.smoothed.len(), 32 * 8 * 4);
+        let peak = bins.index(&red.get(0, 0));
+        assert_eq!(red_hist.smoothed[peak], 8 * 16);
+        assert!(red_hist.similarity_by_correlation(&red_hist) > red_hist.similarity_by_correlation(&orange_hist));
+    }
+
+    #[test]
+    fn exact_conversion_matches_css() {
+        // rgb, hue (degree), saturation (%), lightness (%)
+        let table : [([u8 ; 3], f32, f32, f32) ; 12] = [
+            ([255, 0, 0], 0., 100., 50.),
+            ([0, 255, 0], 120., 100., 50.),
+            ([0, 0, 255], 240., 100., 50.),
+            ([255, 255, 0], 60., 100., 50.),
+            ([255, 0, 255], 300., 100., 50.),
+            ([0, 0, 0], 0., 0., 0.),
+            ([255, 255, 255], 0., 0., 100.),
+            ([128, 128, 128], 0., 0., 50.2),
+            ([128, 0, 0], 0., 100., 25.1),
+            ([255, 165, 0], 38.8, 100., 50.),
+            ([240, 128, 128], 0., 78.9, 72.2),
+            ([70, 130, 180], 207.3, 44., 49.),
+        ];
+        for &(rgb, h, s, l) in &table {
+            let hsl = Hsl::from_rgba_exact(Rgba([rgb[0], rgb[1], rgb[2], 255]));
+            let close = |value : u8, expected : f32| (value as f32 - expected).abs() <= 1.;
+            assert!(close(hsl.h2, h / 360. * 255.), "hue of {:?}: {}", rgb, hsl.h2);
+            assert!(close(hsl.saturation_exact(), s / 100. * 255.), "saturation of {:?}: {}", rgb, hsl.saturation_exact());
+            assert!(close(hsl.l, l / 100. * 255.), "lightness of {:?}: {}", rgb, hsl.l);
+            assert_eq!(hsl.a, 255);
+        }
+    }
+
+    #[test]
+    fn soft_maxima() {
+        let red = [(Hsl::new(0, 12, 8, 1), 10.)];
+        let orange = [(Hsl::new(1, 12, 8, 1), 10.)];
+        let crimson = [(Hsl::new(15, 12, 8, 1), 10.)];
+        let blue = [(Hsl::new(10, 12, 8, 1), 10.)];
+        assert_eq!(soft_similarity_of_maxima(&red, &red, 1.5), 1.0);
+        // The hue wraps around
+        assert_eq!(soft_similarity_of_maxima(&red, &orange, 1.5), soft_similarity_of_maxima(&red, &crimson, 1.5));
+        assert!(soft_similarity_of_maxima(&red, &orange, 1.5) > soft_similarity_of_maxima(&red, &blue, 1.5));
+        assert!(soft_similarity_of_maxima(&red, &orange, 3.0) > soft_similarity_of_maxima(&red, &orange, 1.5));
+        // Grey has no hue
+        let grey = [(Hsl::new(0, 0, 8, 1), 1.)];
+        assert_eq!(soft_similarity_of_maxima(&grey, &[(Hsl::new(10, 0, 8, 1), 5.)], 1.5), 1.0);
+    }
+
+    #[test]
+    fn maxima_aggregation() {
+        let red = [(Hsl::new(0, 12, 8, 1), 10.)];
+        // Many maxima that are all a bit off
+        let reddish = [(Hsl::new(0, 12, 7, 1), 10.), (Hsl::new(0, 12, 9, 1), 10.), (Hsl::new(0, 12, 10, 1), 10.)];
+        let sum = |other : &[(Hsl, f32)]| similarity_of_maxima(&red, other, Aggregate::Sum);
+        let max = |other : &[(Hsl, f32)]| similarity_of_maxima(&red, other, Aggregate::Max);
+        assert!(sum(&reddish) > sum(&red));
+        assert!(max(&reddish) < max(&red));
+        assert_eq!(max(&red), sum(&red));
+        assert!((similarity_of_maxima(&red, &reddish, Aggregate::Mean) * 3. - sum(&reddish)).abs() < 1e-5);
+        assert_eq!(similarity_of_maxima(&red, &[], Aggregate::Mean), 0.0);
+    }
+
+    #[test]
+    fn color_range() {
+        let blues = ColorRange { hue : (9, 11), chroma : (4, 15), lightness : (2, 13) };
+        assert!(blues.contains(&Hsl::new(10, 12, 8, 1)));
+        assert!(!blues.contains(&Hsl::new(8, 12, 8, 1)));
+        assert!(!blues.contains(&Hsl::new(10, 2, 8, 1)));
+        assert!(!blues.contains(&Hsl::new(10, 12, 14, 1)));
+        let reds = ColorRange { hue : (14, 1), chroma : (0, 15), lightness : (0, 15) };
+        assert!(reds.contains(&Hsl::new(15, 12, 8, 1)) && reds.contains(&Hsl::new(0, 12, 8, 1)));
+        assert!(!reds.contains(&Hsl::new(5, 12, 8, 1)));
+    }
+
+    #[test]
+    fn edge_density() {
+        let flat = HslImage::from_image(&RgbaImage::from_pixel(4, 4, Rgba([128, 128, 128, 255])));
+        assert_eq!(flat.edge_density(), 0.0);
+        let stripes = RgbaImage::from_fn(4, 4, |x, _| if x % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        // Only the last column has no right neighbour
+        assert_eq!(HslImage::from_image(&stripes).edge_density(), 0.75);
+        // An outline on transparent background counts, too
+        let outline = RgbaImage::from_fn(4, 4, |x, _| if x == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) });
+        assert_eq!(HslImage::from_image(&outline).edge_density(), 0.25);
+    }
+
+    #[test]
+    fn linear_light() {
+        let grey = Rgba([128, 128, 128, 255]);
+        assert_eq!(Hsl::from(grey).l, 128);
+        assert_eq!(Hsl::from_rgba_linear(grey).l, 55);
+        // Black, white and the hue are the same in both modes
+        assert_eq!(Hsl::from_rgba_linear(Rgba([0, 0, 0, 255])).l, 0);
+        assert!(Hsl::from_rgba_linear(Rgba([255, 255, 255, 255])).l >= 254);
+        assert_eq!(Hsl::from_rgba_linear(Rgba([0, 0, 200, 255])).h2, Hsl::from(Rgba([0, 0, 200, 255])).h2);
+        let img = RgbaImage::from_pixel(2, 2, grey);
+        assert!(HslImage::from_image_with(&img, true).pixels.iter().all(|p| p.l == 55));
+    }
+
     #[test]
     fn test_to_scale_used_by_paper() {
         let (h, s, l) = Hsl::new(10, 0, 0, 0).to_scale_used_by_paper();
@@ -0,0 +1,106 @@
+//! Pixel data from other sources than encoded images, eg. frames of a video decoder or a
+//! GPU readback, converted into images that can be matched.
+
+use image::{DynamicImage, RgbaImage};
+#[cfg(feature = "ndarray")]
+use ndarray::ArrayView3;
+
+use {Emoimage, ComparisationMethod, EmotimError, EmoimageBuilder};
+use emoticons::Emoticons;
+
+/// Wraps a raw RGBA buffer (row by row, 4 bytes per pixel) of the given dimensions into
+/// an image. Fails with `InvalidBufferSize` if the buffer does not have
+/// `width * height * 4` bytes.
+pub fn image_from_rgba(width : u32, height : u32, data : &[u8]) -> Result<DynamicImage, EmotimError> {
+    let expected = width as usize * height as usize * 4;
+    if data.len() != expected {
+        return Err(EmotimError::InvalidBufferSize { expected : expected, actual : data.len() });
+    }
+    Ok(DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, data.to_vec()).unwrap()))
+}
+
+/// Converts an array of shape `(height, width, channels)` into an image. 3 channels are
+/// read as RGB (opaque), 4 as RGBA. The array may have any memory layout, eg. a slice
+/// of a larger array. Fails with `InvalidBufferSize` for other numbers of channels.
+///
+/// Needs the feature `ndarray`.
+#[cfg(feature = "ndarray")]
+pub fn image_from_array(pixels : ArrayView3<u8>) -> Result<DynamicImage, EmotimError> {
+    let (height, width, channels) = pixels.dim();
+    if channels != 3 && channels != 4 {
+        return Err(EmotimError::InvalidBufferSize { expected : height * width * 4, actual : pixels.len() });
+    }
+    let img = RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let alpha = if channels == 4 { pixels[[y, x, 3]] } else { 255 };
+        ::image::Rgba([pixels[[y, x, 0]], pixels[[y, x, 1]], pixels[[y, x, 2]], alpha])
+    });
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+impl Emoimage {
+    /// Does the calculation for a raw RGBA buffer, see `image_from_rgba()`.
+    pub fn from_rgba(width : u32,
+                     height : u32,
+                     data : &[u8],
+                     frac : u32,
+                     emoticons : &Emoticons,
+                     method : ComparisationMethod) -> Result<Emoimage, EmotimError> {
+        let img = image_from_rgba(width, height, data)?;
+        EmoimageBuilder::new().frac(frac).method(method).build(&img, emoticons)
+    }
+
+    /// Does the calculation for an array of shape `(height, width, channels)`, see
+    /// `image_from_array()`.
+    ///
+    /// Needs the feature `ndarray`.
+    #[cfg(feature = "ndarray")]
+    pub fn from_array(pixels : ArrayView3<u8>,
+                      frac : u32,
+                      emoticons : &Emoticons,
+                      method : ComparisationMethod) -> Result<Emoimage, EmotimError> {
+        let img = image_from_array(pixels)?;
+        EmoimageBuilder::new().frac(frac).method(method).build(&img, emoticons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImage, Rgba};
+    use emoticons;
+
+    #[test]
+    fn raw_rgba() {
+        let mut data = Vec::new();
+        for x in 0..16 {
+            data.extend_from_slice(if x < 8 { &[255, 0, 0, 255] } else { &[0, 0, 255, 255] });
+        }
+        let data : Vec<u8> = data.iter().cycle().take(16 * 8 * 4).cloned().collect();
+        let img = image_from_rgba(16, 8, &data).unwrap();
+        assert_eq!(img.get_pixel(9, 3), Rgba([0, 0, 255, 255]));
+        assert!(image_from_rgba(16, 9, &data).is_err());
+
+        let emos = emoticons::hue_wheel(12, 8);
+        let emoimg = Emoimage::from_rgba(16, 8, &data, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let expected = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        assert!(emoimg.diff(&expected).is_identical());
+    }
+
+    #[test]
+    #[cfg(feature = "ndarray")]
+    fn array() {
+        use ndarray::Array3;
+        let rgb = Array3::from_shape_fn((8, 16, 3), |(_, x, c)| if (x < 8) == (c == 0) { 255 } else { 0 });
+        let img = image_from_array(rgb.view()).unwrap();
+        assert_eq!(img.get_pixel(2, 5), Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(9, 5), Rgba([0, 255, 255, 255]));
+        // Transposed views work, too
+        let transposed = rgb.view().permuted_axes([1, 0, 2]);
+        assert_eq!(image_from_array(transposed).unwrap().get_pixel(5, 2), Rgba([255, 0, 0, 255]));
+        assert!(image_from_array(Array3::<u8>::zeros((2, 2, 2)).view()).is_err());
+
+        let emos = emoticons::hue_wheel(12, 8);
+        assert!(Emoimage::from_array(rgb.view(), 8, &emos, ComparisationMethod::Correlation).is_ok());
+    }
+}
@@ -6,9 +6,9 @@
 //!     use emotim::*;
 //!     let emos = read_emoticons();
 //!     let mut ii = read_input_image("Munch_Schrei_6.jpg");
-//!     let emoimg = Emoimage::new(&mut ii, 20, &emos, ComparisationMethod::Correlation);
+//!     let emoimg = Emoimage::new(&mut ii, 20, &emos, ComparisationMethod::Correlation).unwrap();
 //!     println!("{}", emoimg);
-//!     emoimg.save(&Path::new("out/munch_max.png"));
+//!     emoimg.save(&Path::new("out/munch_max.png")).unwrap();
 //! ```
 //!
 //! This crate is much much faster in release mode.
@@ -18,142 +18,3238 @@
 #![feature(test)]
 
 extern crate image;
+extern crate png;
+extern crate flate2;
+extern crate rayon;
+extern crate exif;
 extern crate test;
+#[cfg(feature = "webp")]
+extern crate webp;
+#[cfg(feature = "avif")]
+extern crate ravif;
+#[cfg(feature = "svg")]
+extern crate resvg;
+#[cfg(feature = "ndarray")]
+extern crate ndarray;
 
 pub mod emoticons;
+pub mod error;
+pub mod fidelity;
+pub mod frames;
 pub mod hsl;
+pub mod input;
+pub mod oklab;
+pub mod output;
+pub mod render;
 
-use image::{GenericImage, DynamicImage, RgbaImage};
+use image::{GenericImage, DynamicImage, RgbaImage, Rgba, FilterType, Pixel};
 
 use std::path::{PathBuf, Path};
+use std::fs;
 use std::fs::File;
 use std::rc::Rc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
 use std::fmt;
 use std::io;
-use std::io::Write;
+use std::io::{Cursor, Write};
+
+use rayon::prelude::*;
 
 use emoticons::Emoticons;
 pub use emoticons::read_emoticons;
+pub use error::EmotimError;
+pub use fidelity::FidelityScore;
+pub use input::image_from_rgba;
+pub use output::{OutputFormat, TilePlacement};
+pub use render::{RenderOptions, Shadow, color_mosaic, compare_methods};
 
 /// Reads a normal image from `assets/input/<filename>`.
+///
+/// Grayscale and palette images are converted to RGBA. The EXIF orientation is
+/// applied, see `decode_input_image()`.
 pub fn read_input_image(filename : &str) -> DynamicImage {
     let mut inputimagepath = PathBuf::new();
     inputimagepath.push("assets/input");
     inputimagepath.push(filename);
-    image::open(&inputimagepath).expect(&format!("image {} not found", inputimagepath.display()))
+    let data = fs::read(&inputimagepath).expect(&format!("image {} not found", inputimagepath.display()));
+    let img = decode_input_image(&data).unwrap_or_else(|e| panic!("image {} is corrupt: {}", inputimagepath.display(), e));
+    DynamicImage::ImageRgba8(img.to_rgba())
+}
+
+/// Decodes an encoded image (eg. the content of a jpg file) and rotates and flips it
+/// according to its EXIF orientation, so that photos taken in portrait mode stand upright.
+pub fn decode_input_image(data : &[u8]) -> Result<DynamicImage, EmotimError> {
+    let img = image::load_from_memory(data)?;
+    Ok(apply_orientation(img, exif_orientation(data)))
+}
+
+/// EXIF orientation (1 to 8) of an encoded image. 1 (upright) if there is none.
+fn exif_orientation(data : &[u8]) -> u32 {
+    exif::Reader::new().read_from_container(&mut Cursor::new(data))
+                       .ok()
+                       .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                                            .and_then(|field| field.value.get_uint(0)))
+                       .unwrap_or(1)
+}
+
+/// Rotates and flips `img` so that an image with the given EXIF orientation is upright.
+/// Unknown orientations leave the image as it is.
+pub fn apply_orientation(img : DynamicImage, orientation : u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Converts an encoded image (eg. the content of a jpg file) into an emoticon version
+/// and returns it encoded as png. Does not touch the filesystem.
+pub fn emojify_bytes(input : &[u8],
+                     frac : u32,
+                     emoticons : &Emoticons,
+                     method : ComparisationMethod) -> Result<Vec<u8>, EmotimError> {
+    let img = decode_input_image(input)?;
+    let emoimg = EmoimageBuilder::new().frac(frac).method(method).build(&img, emoticons)?;
+    let mut png = Cursor::new(Vec::new());
+    emoimg.try_render(&RenderOptions::default())?.save(&mut png, image::PNG)?;
+    Ok(png.into_inner())
 }
 
 /// An image made out of emoticons
 pub struct Emoimage {
     pub width : u32,
     pub height : u32,
-    pub emopixels : Vec<Rc<emoticons::Emoticon>>,
+    /// The chosen emoticon for each chunk, row by row. `None` for empty cells,
+    /// eg. chunks that are mostly transparent.
+    pub emopixels : Vec<Option<Rc<emoticons::Emoticon>>>,
+    /// Similarity between each chunk and its chosen emoticon (0 for empty cells).
+    /// Same layout as `emopixels`.
+    pub confidences : Vec<f32>,
+    /// The next best emoticons (and their similarity) for each chunk, best first.
+    /// Same layout as `emopixels`. See `EmoimageBuilder::alternatives()`.
+    pub alternatives : Vec<Vec<(Rc<emoticons::Emoticon>, f32)>>,
+    /// Number of chunks whose emoticon was taken from the similarity cache
+    /// instead of being compared against all emoticons. (For diagnostics)
+    pub cache_hits : u32,
+    /// Dominant color of each chunk of the input image (reduced color depth), `None`
+    /// for empty cells. Same layout as `emopixels`.
+    pub chunk_colors : Vec<Option<hsl::Hsl>>,
+    /// Size of each cell in chunks: usually 1. 2 for the top left cell of a merged block
+    /// of 2x2 flat chunks and 0 for the other three, which hold the same emoticon. Same
+    /// layout as `emopixels`. See `EmoimageBuilder::merge_flat_chunks()`.
+    pub spans : Vec<u8>,
+    /// Width and height of the chunks of the input image that the cells stand for.
+    pub frac : u32,
+    /// Position of the input image within the padded image that was split into chunks
+    /// (see `EmoimageBuilder::pad()`). `(0, 0)` without padding.
+    pub offset : (u32, u32),
+}
+
+/// Differences between two `Emoimage`s, see `Emoimage::diff()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmoimageDiff {
+    /// Number of cells with differing emoticons.
+    pub count : usize,
+    /// Positions `(w, h)` of the cells with differing emoticons, row by row.
+    pub positions : Vec<(u32, u32)>,
+}
+
+impl EmoimageDiff {
+    /// True if both images chose the same emoticon for each cell.
+    pub fn is_identical(&self) -> bool {
+        self.count == 0
+    }
 }
 
 /// Different methods to calculate the corresponding emoticons.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ComparisationMethod {
     Correlation,
-    Maxima
+    Maxima,
+    /// Compare the mean colors of a small grid of cells instead of histograms.
+    /// This respects the spatial structure of chunk and emoticon.
+    Spatial,
+    /// Compare only the mean color of chunk and emoticon. Fast, but ignores
+    /// everything but the average.
+    NearestColor,
+    /// Like `Correlation` for colorful chunks, but grey chunks (mean chroma below
+    /// `ADAPTIVE_CHROMA_THRESHOLD`) are matched by lightness only, so that their
+    /// hue, which is mostly noise, does not count.
+    Adaptive,
+    /// Compare only the chroma distributions, ignoring hue and lightness. Matches by
+    /// "how colorful" rather than by "what color", for stylized results.
+    Chroma,
+    /// Like `Maxima`, but maxima close to each other count, too (weighted by a gaussian
+    /// of their distance), so that colors near a bin edge match stably.
+    /// See `EmoimageBuilder::soft_maxima_bandwidth()`.
+    SoftMaxima,
+    /// Like `NearestColor`, but compares the mean colors in the perceptual OKLab space by
+    /// their euclidean distance, see `oklab::Oklab::similarity()`. The mean is taken in
+    /// linear light.
+    Oklab,
+    /// Like `Correlation`, but the similarity is multiplied by `coverage ^ gamma` (see
+    /// `Emoticon::coverage`), so that dense emoticons win among similarly colored ones.
+    /// `gamma` 0.0 is plain `Correlation`, higher values prefer dense emoticons more.
+    ColorCoverage { gamma : f32 },
 }
 
-impl Emoimage {
+/// Chunks with a lower mean chroma are treated as grey by `ComparisationMethod::Adaptive`.
+pub const ADAPTIVE_CHROMA_THRESHOLD : f32 = 0.1;
+
+/// Minimum share of the dominant color for `EmoimageBuilder::blank_uniform()`.
+pub const BLANK_UNIFORM_SHARE : f32 = 0.9;
+/// Maximum chroma (in reduced depth, 0 to 15) of the dominant color for `blank_uniform()`.
+pub const BLANK_UNIFORM_MAX_CHROMA : u8 = 1;
+/// Minimum lightness (in reduced depth, 0 to 15) of the dominant color for `blank_uniform()`.
+pub const BLANK_UNIFORM_MIN_LIGHTNESS : u8 = 12;
+/// Minimum chroma (in reduced depth, 0 to 15) of the dominant color of an emoticon for
+/// `EmoimageBuilder::hue_family()`. Greyer emoticons have no meaningful hue.
+pub const HUE_FAMILY_MIN_CHROMA : u8 = 2;
+/// Factor by which `EmoimageBuilder::candidate_k()` downsamples the histograms for the
+/// coarse pass (16³ to 8³ bins).
+pub const COARSE_FACTOR : usize = 2;
+
+impl ComparisationMethod {
+    /// The parts of the emoticon histograms that this method needs.
+    /// See `emoticons::read_emoticons_with_mode()`.
+    pub fn histogram_mode(&self) -> hsl::HistogramMode {
+        match *self {
+            ComparisationMethod::Correlation
+            | ComparisationMethod::Adaptive
+            | ComparisationMethod::Chroma
+            | ComparisationMethod::ColorCoverage { .. } => hsl::HistogramMode::Correlation,
+            // The others need the maxima for the dominant color only
+            _ => hsl::HistogramMode::Maxima,
+        }
+    }
+}
+
+/// Emoticons (as index into the list of emoticons) with their similarity to a chunk, best first.
+type Ranking = Vec<(usize, f32)>;
+
+/// Everything that is known about a chunk of the input image.
+///
+/// Chunks with equal signatures are matched to the same emoticons. The binned histogram
+/// is compared by its digest in `distribution` only.
+struct ChunkSignature {
+    maxima : hsl::HistogramSignature,
+    /// Digest of the smoothed distribution (the binned one, if calculated), only calculated
+    /// for the methods that correlate it (see `ComparisationMethod::histogram_mode()`).
+    /// Chunks with equal maxima may differ there.
+    distribution : Option<u64>,
+    /// Only calculated for `ComparisationMethod::Spatial`
+    spatial : Option<hsl::SpatialSignature>,
+    /// Only calculated if non default `Bins` are used
+    binned : Option<hsl::BinnedHistogram>,
+    /// Only calculated for `ComparisationMethod::NearestColor`
+    mean : Option<hsl::Hsl>,
+    /// Bits of the mean color, only calculated for `ComparisationMethod::Oklab`
+    oklab : Option<[u32 ; 3]>,
+    /// Only calculated for `ComparisationMethod::Adaptive`, otherwise false
+    grey : bool,
+    /// Bits of the edge density, only calculated if `edge_weight` is not 0
+    edges : Option<u32>,
+}
+
+/// The fields of a `ChunkSignature` that are compared and hashed.
+type SignatureKey<'a> = (&'a hsl::HistogramSignature, Option<u64>, &'a Option<hsl::SpatialSignature>,
+                         &'a Option<hsl::Hsl>, Option<[u32 ; 3]>, bool, Option<u32>);
+
+impl ChunkSignature {
+    fn key(&self) -> SignatureKey<'_> {
+        (&self.maxima, self.distribution, &self.spatial, &self.mean, self.oklab, self.grey, self.edges)
+    }
+}
+
+impl PartialEq for ChunkSignature {
+    fn eq(&self, other : &ChunkSignature) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ChunkSignature {}
+
+impl Hash for ChunkSignature {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.key().hash(state);
+    }
+}
+
+/// Collects the parameters for the calculation of an `Emoimage` and checks them
+/// before doing any work.
+///
+/// ```no_run
+///     use emotim::*;
+///     let emos = read_emoticons();
+///     let ii = read_input_image("schrei.jpg");
+///     let emoimg = EmoimageBuilder::new()
+///         .frac(15)
+///         .method(ComparisationMethod::Maxima)
+///         .build(&ii, &emos)
+///         .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct EmoimageBuilder {
+    frac : u32,
+    method : ComparisationMethod,
+    alternatives : usize,
+    pad : bool,
+    pad_fill : Rgba<u8>,
+    bins : hsl::Bins,
+    transparent_threshold : f32,
+    colorfulness_bias : f32,
+    min_coverage : f32,
+    coverage_bias : f32,
+    threads : Option<usize>,
+    posterize_levels : Option<u8>,
+    no_adjacent_repeat : bool,
+    soft_maxima_bandwidth : f32,
+    merge_flat_chunks : Option<f32>,
+    blank_uniform : bool,
+    blank_below : Option<f32>,
+    edge_weight : f32,
+    maxima_aggregate : hsl::Aggregate,
+    mood : Vec<Vec<char>>,
+    mood_strength : f32,
+    weights : HashMap<String, f32>,
+    candidate_k : Option<usize>,
+    tiebreak_seed : Option<u64>,
+    tiebreak_epsilon : f32,
+    hue_family : Option<(u8, u8)>,
+    linear_light : bool,
+    /// Width, height and pixels of the label image of `regions()`
+    region_labels : Option<(u32, u32, Vec<u8>)>,
+    region_groups : HashMap<u8, String>,
+    auto_white_balance : bool,
+}
+
+impl Default for EmoimageBuilder {
+    fn default() -> EmoimageBuilder {
+        EmoimageBuilder {
+            frac : 20,
+            method : ComparisationMethod::Correlation,
+            alternatives : 1,
+            pad : false,
+            pad_fill : Rgba([0, 0, 0, 0]),
+            bins : hsl::Bins::default(),
+            transparent_threshold : 0.5,
+            colorfulness_bias : 0.0,
+            min_coverage : 0.0,
+            coverage_bias : 0.0,
+            threads : None,
+            posterize_levels : None,
+            no_adjacent_repeat : false,
+            soft_maxima_bandwidth : hsl::SOFT_MAXIMA_BANDWIDTH,
+            merge_flat_chunks : None,
+            blank_uniform : false,
+            blank_below : None,
+            edge_weight : 0.0,
+            maxima_aggregate : hsl::Aggregate::Sum,
+            mood : Vec::new(),
+            mood_strength : 0.0,
+            weights : HashMap::new(),
+            candidate_k : None,
+            tiebreak_seed : None,
+            tiebreak_epsilon : 0.0,
+            hue_family : None,
+            linear_light : false,
+            region_labels : None,
+            region_groups : HashMap::new(),
+            auto_white_balance : false,
+        }
+    }
+}
+
+impl EmoimageBuilder {
+    /// Creates a builder with default parameters (`frac` 20, correlation method).
+    pub fn new() -> EmoimageBuilder {
+        EmoimageBuilder::default()
+    }
+
+    /// Width and height of the square chunks of the input image that are replaced by one emoticon.
+    pub fn frac(mut self, frac : u32) -> EmoimageBuilder {
+        self.frac = frac;
+        self
+    }
+
+    /// Method used to compare chunks and emoticons.
+    pub fn method(mut self, method : ComparisationMethod) -> EmoimageBuilder {
+        self.method = method;
+        self
+    }
+
+    /// Number of next best emoticons to remember for each chunk. (Default: 1)
+    pub fn alternatives(mut self, alternatives : usize) -> EmoimageBuilder {
+        self.alternatives = alternatives;
+        self
+    }
+
+    /// Pad the input image to a multiple of `frac` before chunking, so that no
+    /// remainder is cut off. The original image is centered. (Default: false)
+    pub fn pad(mut self, pad : bool) -> EmoimageBuilder {
+        self.pad = pad;
+        self
+    }
+
+    /// Color of the padding. (Default: transparent)
+    pub fn pad_fill(mut self, pad_fill : Rgba<u8>) -> EmoimageBuilder {
+        self.pad_fill = pad_fill;
+        self
+    }
+
+    /// Number of histogram bins per channel for `ComparisationMethod::Correlation`.
+    /// Other methods ignore this. (Default: 16 per channel)
+    pub fn bins(mut self, bins : hsl::Bins) -> EmoimageBuilder {
+        self.bins = bins;
+        self
+    }
+
+    /// Chunks with a higher fraction of transparent pixels are left empty.
+    /// Use 1.0 to fill all chunks. (Default: 0.5)
+    pub fn transparent_threshold(mut self, transparent_threshold : f32) -> EmoimageBuilder {
+        self.transparent_threshold = transparent_threshold;
+        self
+    }
+
+    /// Prefer vivid emoticons: The similarity of each emoticon is multiplied by
+    /// `1 + colorfulness_bias * mean_chroma`, so that among equally similar emoticons
+    /// the more saturated one wins. (Default: 0.0, no preference)
+    pub fn colorfulness_bias(mut self, colorfulness_bias : f32) -> EmoimageBuilder {
+        self.colorfulness_bias = colorfulness_bias;
+        self
+    }
+
+    /// Prefer emoticons that look like one of the `references`, eg. only warm or happy
+    /// ones for themed output: The similarity of each emoticon is multiplied by
+    /// `1 + strength * mood`, where `mood` is its highest similarity to a reference
+    /// (`Emoticon::similarity()` with `method`, relative to the self similarity of the reference).
+    ///
+    /// The references are identified by their codepoints and have to be part of the emoticons
+    /// passed to `build()`, the others are ignored. Ignored by `Emoimage::new_fast()`.
+    /// (Default: no references)
+    pub fn mood(mut self, references : &[Rc<emoticons::Emoticon>], strength : f32) -> EmoimageBuilder {
+        self.mood = references.iter().map(|r| r.codepoints()).collect();
+        self.mood_strength = strength;
+        self
+    }
+
+    /// Desirability of single emoticons: The similarity of each emoticon whose codepoints
+    /// (see `Emoticon::codepoints_hex()`, eg. `1f600` or `0023-20e3`) are a key is multiplied
+    /// by the value, eg. 1.2 to prefer it and 0.8 to avoid it without excluding it.
+    /// Ignored by `Emoimage::new_fast()`. (Default: no weights)
+    pub fn weights(mut self, weights : HashMap<String, f32>) -> EmoimageBuilder {
+        self.weights = weights;
+        self
+    }
+
+    /// Never choose emoticons whose `coverage` (fraction of visible pixels) is below
+    /// this, so that mostly transparent emoticons do not leave gaps. (Default: 0.0)
+    pub fn min_coverage(mut self, min_coverage : f32) -> EmoimageBuilder {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Prefer dense emoticons: The similarity of each emoticon is multiplied by
+    /// `1 + coverage_bias * coverage`. (Default: 0.0, no preference)
+    pub fn coverage_bias(mut self, coverage_bias : f32) -> EmoimageBuilder {
+        self.coverage_bias = coverage_bias;
+        self
+    }
+
+    /// Number of threads used for the calculation. Use this to bound the CPU usage
+    /// if several conversions run at the same time. (Default: rayon's global thread
+    /// pool, which has one thread per CPU)
+    pub fn threads(mut self, threads : usize) -> EmoimageBuilder {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Posterize the input before matching: hue, chroma and lightness of each pixel are
+    /// quantized to the given number of levels at full resolution, so that adjacent chunks
+    /// get the same emoticon in broad bands. Affects the histogram based methods only.
+    /// (Default: None)
+    pub fn posterize_levels(mut self, posterize_levels : Option<u8>) -> EmoimageBuilder {
+        self.posterize_levels = posterize_levels;
+        self
+    }
+
+    /// Never place the same emoticon in horizontally or vertically adjacent cells: Going
+    /// row by row, a cell whose best emoticon equals the one left of or above it gets its
+    /// next best one. Reduces blocks of identical emoticons in flat regions. (Default: false)
+    pub fn no_adjacent_repeat(mut self, no_adjacent_repeat : bool) -> EmoimageBuilder {
+        self.no_adjacent_repeat = no_adjacent_repeat;
+        self
+    }
+
+    /// Standard deviation (in histogram bins) of the gaussian that weights pairs of maxima
+    /// by their distance for `ComparisationMethod::SoftMaxima`. Higher values make the
+    /// matching smoother but less precise. (Default: `hsl::SOFT_MAXIMA_BANDWIDTH`)
+    pub fn soft_maxima_bandwidth(mut self, soft_maxima_bandwidth : f32) -> EmoimageBuilder {
+        self.soft_maxima_bandwidth = soft_maxima_bandwidth;
+        self
+    }
+
+    /// Adaptive tiling: Blocks of 2x2 chunks whose color variance (mean of the variances
+    /// of the RGB channels, 0.0 to 1.0) is below the given threshold are each replaced by
+    /// one emoticon of double size, so that flat backgrounds get coarser and details stay
+    /// fine. Eg. `Some(0.002)`. See `Emoimage::spans`. (Default: None)
+    pub fn merge_flat_chunks(mut self, merge_flat_chunks : Option<f32>) -> EmoimageBuilder {
+        self.merge_flat_chunks = merge_flat_chunks;
+        self
+    }
+
+    /// How `ComparisationMethod::Maxima` combines the similarities of all pairs of maxima:
+    /// `Max` prefers emoticons that strongly share one dominant color over emoticons
+    /// with many weakly overlapping maxima. (Default: `Aggregate::Sum`)
+    pub fn maxima_aggregate(mut self, maxima_aggregate : hsl::Aggregate) -> EmoimageBuilder {
+        self.maxima_aggregate = maxima_aggregate;
+        self
+    }
+
+    /// Leaves chunks empty that are nearly uniform light background: The dominant color
+    /// of the chunk has low chroma, high lightness and covers at least
+    /// `BLANK_UNIFORM_SHARE` of it. Gives a "subject on clean background" look. (Default: false)
+    pub fn blank_uniform(mut self, blank_uniform : bool) -> EmoimageBuilder {
+        self.blank_uniform = blank_uniform;
+        self
+    }
+
+    /// Leaves cells empty whose chosen emoticon has a similarity (see `Emoimage::confidences`)
+    /// below `floor` times the highest similarity of all cells, so that only the confidently
+    /// matched parts are filled: a sketchy, partial mosaic. Eg. `Some(0.5)` keeps the cells
+    /// that are at least half as similar as the best one. The floor is relative since the
+    /// scale of the similarities differs by method (and for `Correlation` by `frac`).
+    ///
+    /// If no cell is left, `build()` fails with `NoEmoticons`. (Default: None, all cells are filled)
+    pub fn blank_below(mut self, floor : Option<f32>) -> EmoimageBuilder {
+        self.blank_below = floor;
+        self
+    }
+
+    /// Blends the similarity of the edge densities (see `Emoticon::edge_density`) into
+    /// the color similarity: `(1 - edge_weight) * color + edge_weight * (1 - |difference|)`,
+    /// where `color` is divided by the best color similarity of the chunk.
+    /// Busy chunks get busy emoticons and flat chunks flat ones, which helps with line art
+    /// emoticons. Must be between 0.0 and 1.0. Ignored for precomputed histograms and
+    /// `Emoimage::new_fast()`. (Default: 0.0, colors only)
+    pub fn edge_weight(mut self, edge_weight : f32) -> EmoimageBuilder {
+        self.edge_weight = edge_weight;
+        self
+    }
+
+    /// Two phase matching for large emoticon sets: Each chunk is first compared with all
+    /// emoticons by the correlation of coarse histograms (see `COARSE_FACTOR`), then only
+    /// the best `k` candidates are compared with `method`. Much faster at a small loss of
+    /// quality. Emoticons without smoothed histograms always are candidates. (Default: None)
+    pub fn candidate_k(mut self, candidate_k : Option<usize>) -> EmoimageBuilder {
+        self.candidate_k = candidate_k;
+        self
+    }
+
+    /// Artistic variety: Among the emoticons whose similarity is within `epsilon` of the
+    /// best one, each cell picks one at random. `epsilon` is relative to the best similarity
+    /// (eg. 0.05 for 5%), since the scale of the similarities differs by method.
+    /// The same `seed` gives the same result. Without a seed, the emoticon that comes
+    /// first wins. (Default: None, 0.0)
+    pub fn random_tiebreak(mut self, seed : Option<u64>, epsilon : f32) -> EmoimageBuilder {
+        self.tiebreak_seed = seed;
+        self.tiebreak_epsilon = epsilon;
+        self
+    }
+
+    /// Monochromatic output: Only emoticons whose dominant hue (reduced depth, 0 to 15)
+    /// is at most `width` steps away from `hue` are used, eg. `Some((10, 1))` for blues.
+    /// Their lightness still renders the image tonally. Emoticons with a dominant chroma
+    /// below `HUE_FAMILY_MIN_CHROMA` are left out, too. (Default: None, all emoticons)
+    pub fn hue_family(mut self, hue_family : Option<(u8, u8)>) -> EmoimageBuilder {
+        self.hue_family = hue_family;
+        self
+    }
+
+    /// Converts the chunks and the emoticons to HSL in linear light instead of gamma
+    /// encoded sRGB (see `hsl::Hsl::from_rgba_linear()`), so that the histograms follow
+    /// the light intensity. The histograms of the emoticons are calculated again for each
+    /// run. Mean colors (`NearestColor`, `Spatial`) are not affected. (Default: false)
+    pub fn linear_light(mut self, linear_light : bool) -> EmoimageBuilder {
+        self.linear_light = linear_light;
+        self
+    }
+
+    /// Removes a global color cast (eg. from colored lighting) before splitting the image
+    /// into chunks: Gray world, each channel is scaled so that the red, green and blue
+    /// means of the visible pixels are equal. Otherwise every chunk of a tinted photo is
+    /// biased towards the emoticons of the cast. (Default: false)
+    pub fn auto_white_balance(mut self, auto_white_balance : bool) -> EmoimageBuilder {
+        self.auto_white_balance = auto_white_balance;
+        self
+    }
+
+    /// Themed regions: `labels` is a (usually low resolution) label image that is stretched
+    /// over the grid, each cell takes the label at its center. Cells whose label is a key of
+    /// `groups` only use emoticons of that group (see `Emoticon::group`, eg. `Food & Drink`),
+    /// the other cells use all emoticons. (Default: None, no regions)
+    pub fn regions(mut self, labels : image::GrayImage, groups : HashMap<u8, String>) -> EmoimageBuilder {
+        self.region_labels = Some((labels.width(), labels.height(), labels.into_raw()));
+        self.region_groups = groups;
+        self
+    }
+
+    /// Checks the parameters and does the calculation.
+    ///
+    /// Fails with `NoEmoticons` if all cells stay empty (eg. for a fully transparent image),
+    /// since there is nothing to render.
+    pub fn build(&self, img : &DynamicImage, emoticons : &Emoticons) -> Result<Emoimage, EmotimError> {
+        let filtered = self.filtered_emoticons(emoticons);
+        let emoticons = &*filtered;
+        self.check(img, emoticons)?;
+        let pool = self.thread_pool()?;
+        non_empty(self.in_linear_light(emoticons, |emoticons| self.calculate(img, emoticons, &pool)))
+    }
+
+    /// Runs `calculate` with the emoticons converted for `linear_light()` if requested.
+    /// The result refers to the original emoticons.
+    fn in_linear_light<F>(&self, emoticons : &Emoticons, calculate : F) -> Emoimage
+        where F : FnOnce(&Emoticons) -> Emoimage {
+        if !self.linear_light {
+            return calculate(emoticons);
+        }
+        let converted : Emoticons = emoticons.iter().map(|e| Rc::new(e.with_linear_light())).collect();
+        let mut emoimage = calculate(&converted);
+        let originals : HashMap<*const emoticons::Emoticon, &Rc<emoticons::Emoticon>> =
+            converted.iter().map(|e| &**e as *const _).zip(emoticons.iter()).collect();
+        let original = |e : &Rc<emoticons::Emoticon>| originals[&(&**e as *const _)].clone();
+        for e in emoimage.emopixels.iter_mut().flatten() {
+            *e = original(e);
+        }
+        for (e, _) in emoimage.alternatives.iter_mut().flatten() {
+            *e = original(e);
+        }
+        emoimage
+    }
+
+    /// Checks the parameters.
+    fn check(&self, img : &DynamicImage, emoticons : &Emoticons) -> Result<(), EmotimError> {
+        self.check_image(img)?;
+        self.check_emoticons(emoticons)?;
+        if self.bins.hue == 0 || self.bins.chroma == 0 || self.bins.lightness == 0 {
+            return Err(EmotimError::InvalidBins(self.bins));
+        }
+        if self.soft_maxima_bandwidth.is_nan() || self.soft_maxima_bandwidth <= 0.0 {
+            return Err(EmotimError::InvalidBandwidth(self.soft_maxima_bandwidth));
+        }
+        if !(0.0..=1.0).contains(&self.edge_weight) {
+            return Err(EmotimError::InvalidEdgeWeight(self.edge_weight));
+        }
+        if self.tiebreak_epsilon.is_nan() || self.tiebreak_epsilon < 0.0 {
+            return Err(EmotimError::InvalidEpsilon(self.tiebreak_epsilon));
+        }
+        if let ComparisationMethod::ColorCoverage { gamma } = self.method {
+            if gamma.is_nan() || gamma < 0.0 {
+                return Err(EmotimError::InvalidGamma(gamma));
+            }
+        }
+        Ok(())
+    }
+
+    /// The emoticons that are left after `hue_family()`. Borrows `emoticons` if none are
+    /// left out.
+    fn filtered_emoticons<'a>(&self, emoticons : &'a Emoticons) -> Cow<'a, Emoticons> {
+        match self.hue_family {
+            Some(family) => Cow::Owned(in_hue_family(emoticons, family)),
+            None => Cow::Borrowed(emoticons),
+        }
+    }
+
+    /// Checks that at least one emoticon is not excluded by `min_coverage`, and the same
+    /// for each group of `regions()`.
+    fn check_emoticons(&self, emoticons : &Emoticons) -> Result<(), EmotimError> {
+        if emoticons.iter().all(|e| e.coverage < self.min_coverage) {
+            return Err(EmotimError::NoEmoticons);
+        }
+        let needed = self.method.histogram_mode();
+        for e in emoticons.iter() {
+            // The sparse histogram and the bins stand in for the emoticon side of the
+            // correlation only, mood references are compared like chunks. The bins are
+            // calculated from the HSL image, which `Emoticon::discard()` may have dropped.
+            let reference = self.mood.contains(&e.codepoints());
+            let sparse = e.sparse.is_some() &&
+                matches!(self.method, ComparisationMethod::Correlation | ComparisationMethod::ColorCoverage { .. });
+            let bins = self.uses_bins() && !e.hsl.pixels.is_empty();
+            if !e.hist.retains(needed) && (reference || !(sparse || bins)) {
+                return Err(EmotimError::DiscardedHistogram { filename : e.filename.clone(), needed : needed });
+            }
+        }
+        if self.region_labels.is_some() {
+            for group in self.region_groups.values() {
+                if !emoticons.iter().any(|e| e.coverage >= self.min_coverage && e.group.as_ref() == Some(group)) {
+                    return Err(EmotimError::EmptyGroup(group.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The group of `regions()` of each cell of a grid of `width` × `height` cells, as
+    /// index into the returned list of the indices of the emoticons of each group. Empty if
+    /// there are no regions.
+    fn region_groups(&self, width : u32, height : u32, emoticons : &[&emoticons::Emoticon])
+                     -> (Vec<Option<usize>>, Vec<Vec<usize>>) {
+        let (label_width, label_height, labels) = match self.region_labels {
+            Some((w, h, ref labels)) if w > 0 && h > 0 => (w as u64, h as u64, labels),
+            _ => return (Vec::new(), Vec::new()),
+        };
+        let mut groups : Vec<(&String, Vec<usize>)> = Vec::new();
+        let cells = (0..width * height).map(|i| {
+            let x = (2 * (i % width) + 1) as u64 * label_width / (2 * width) as u64;
+            let y = (2 * (i / width) + 1) as u64 * label_height / (2 * height) as u64;
+            let group = self.region_groups.get(&labels[(y * label_width + x) as usize])?;
+            Some(match groups.iter().position(|g| g.0 == group) {
+                Some(index) => index,
+                None => {
+                    let members = emoticons.iter()
+                                           .enumerate()
+                                           .filter(|(_, e)| e.group.as_ref() == Some(group))
+                                           .map(|(i, _)| i)
+                                           .collect();
+                    groups.push((group, members));
+                    groups.len() - 1
+                },
+            })
+        }).collect();
+        (cells, groups.into_iter().map(|g| g.1).collect())
+    }
+
+    /// Checks the parameters that are needed to split the image into chunks.
+    fn check_image(&self, img : &DynamicImage) -> Result<(), EmotimError> {
+        if self.frac == 0 {
+            return Err(EmotimError::InvalidFrac(self.frac));
+        }
+        if !self.pad && (img.width() < self.frac || img.height() < self.frac) {
+            return Err(EmotimError::ImageTooSmall {
+                width : img.width(),
+                height : img.height(),
+                frac : self.frac,
+            });
+        }
+        if self.posterize_levels == Some(0) {
+            return Err(EmotimError::InvalidPosterizeLevels(0));
+        }
+        Ok(())
+    }
+
     /// Does the calculation.
-    pub fn new(img : &mut DynamicImage,
-               frac : u32,
-               emoticons : &Emoticons,
-               method : ComparisationMethod) -> Emoimage {
-
-        let height = img.height() / frac;
-        let width = img.width() / frac;
-        let mut pixels = Vec::with_capacity(width as usize * height as usize);
-
-        println!("Finding best emoticon for chunk of input image:");
-        for h in 0..height {
-            for w in 0..width {
-                // progress
-                print!("\r Chunk @ h:{} w:{}", h, w);
-                io::stdout().flush().ok();
-
-                let subimg = img.sub_image(w * frac, h * frac, frac, frac);
-                let subimghsv = hsl::HslImage::from_image(&subimg);
-                let subimghist = subimghsv.reduce_dynamic().histogram();
-
-                let mut the_chosen_one = None;
-                let mut highest_similarity = -10000.0;
-                for e in emoticons {
-                    let similarity = match method {
-                        ComparisationMethod::Correlation => e.hist.similarity_by_correlation(&subimghist),
-                        ComparisationMethod::Maxima => e.hist.similarity_by_maxima(&subimghist),
-                    };
-                    if similarity > highest_similarity {
-                        the_chosen_one = Some(e.clone());
-                        highest_similarity = similarity;
-                    }
+    ///
+    /// The chunks are analyzed and matched in parallel. Chunks with equal signatures
+    /// are matched only once.
+    fn calculate(&self, img : &DynamicImage, emoticons : &Emoticons, pool : &Option<rayon::ThreadPool>) -> Emoimage {
+        let frac = self.frac;
+        let (original_width, original_height) = img.dimensions();
+        let padded;
+        let img = if self.pad {
+            padded = pad_to_multiple(img, frac, self.pad_fill);
+            &padded
+        } else {
+            img
+        };
+        let (width, height) = grid_size(img, frac);
+        let rgba = self.input_pixels(img);
+        // Histograms with non default bins have to be calculated for this run
+        let use_bins = self.uses_bins();
+        let emoticon_bins : Vec<hsl::BinnedHistogram> = if use_bins {
+            emoticons.iter().map(|e| e.hsl.binned_histogram(self.bins)).collect()
+        } else {
+            Vec::new()
+        };
+
+        println!("Analyzing {} chunks of input image", width * height);
+        let mut chunks : Vec<Option<(ChunkSignature, hsl::HslHistogram)>> = install(pool, || {
+            (0..width * height).into_par_iter()
+                               .map(|i| self.analyze_region(&rgba, i % width * frac, i / width * frac, frac, use_bins))
+                               .collect()
+        });
+
+        // The top left chunk of each merged block gets the analysis of the whole block
+        let mut merged_blocks = Vec::new();
+        if let Some(threshold) = self.merge_flat_chunks {
+            let blocks = self.flat_blocks(&rgba, width, height, threshold, &chunks, pool);
+            let merged : Vec<_> = install(pool, || {
+                blocks.par_iter()
+                      .map(|&i| {
+                          let i = i as u32;
+                          self.analyze_region(&rgba, i % width * frac, i / width * frac, 2 * frac, use_bins)
+                      })
+                      .collect()
+            });
+            for (i, block) in blocks.into_iter().zip(merged) {
+                if block.is_some() {
+                    chunks[i] = block;
+                    merged_blocks.push(i);
                 }
-                pixels.push(the_chosen_one.unwrap());
             }
+            println!("Merged {} blocks of flat chunks", merged_blocks.len());
         }
-        println!("\r Done.");
-        Emoimage {
-            width : width,
-            height : height,
-            emopixels : pixels
+
+        let chunks = chunks.iter().map(|c| c.as_ref().map(|c| (&c.0, &c.1))).collect();
+        let mut emoimage = self.match_chunks(width, height, chunks, pool, emoticons, &emoticon_bins);
+        emoimage.merge_blocks(&merged_blocks);
+        emoimage.offset = ((img.width() - original_width) / 2, (img.height() - original_height) / 2);
+        emoimage
+    }
+
+    /// Indices of the top left chunks of all aligned blocks of 2x2 non empty chunks
+    /// whose color variances are below `threshold`.
+    fn flat_blocks(&self,
+                   img : &RgbaImage,
+                   width : u32,
+                   height : u32,
+                   threshold : f32,
+                   chunks : &[Option<(ChunkSignature, hsl::HslHistogram)>],
+                   pool : &Option<rayon::ThreadPool>) -> Vec<usize> {
+        let frac = self.frac;
+        let flat : Vec<bool> = install(pool, || {
+            (0..width * height).into_par_iter()
+                               .map(|i| chunks[i as usize].is_some()
+                                        && color_variance(img, i % width * frac, i / width * frac, frac) < threshold)
+                               .collect()
+        });
+        let width = width as usize;
+        let mut blocks = Vec::new();
+        for h in (0..height as usize / 2).map(|h| 2 * h) {
+            for w in (0..width / 2).map(|w| 2 * w) {
+                let i = h * width + w;
+                if flat[i] && flat[i + 1] && flat[i + width] && flat[i + width + 1] {
+                    blocks.push(i);
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Matches the analyzed chunks (`None` for empty ones) with the emoticons.
+    ///
+    /// Chunks with equal signatures are matched only once.
+    fn match_chunks(&self,
+                    width : u32,
+                    height : u32,
+                    chunks : Vec<Option<(&ChunkSignature, &hsl::HslHistogram)>>,
+                    pool : &Option<rayon::ThreadPool>,
+                    emoticons : &Emoticons,
+                    emoticon_bins : &[hsl::BinnedHistogram]) -> Emoimage {
+        let chunks : Vec<_> = if self.blank_uniform {
+            chunks.into_iter().map(|c| c.filter(|c| !is_uniform_background(c.1))).collect()
+        } else {
+            chunks
+        };
+        // `Rc` can not be shared between threads, plain references can
+        let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+
+        let (cell_groups, groups) = self.region_groups(width, height, &emoticon_refs);
+
+        // Chunks with the same signatures (in the same group) get the same emoticons
+        let mut distinct : Vec<(&ChunkSignature, &hsl::HslHistogram, Option<usize>)> = Vec::new();
+        let mut indices : HashMap<(&ChunkSignature, Option<usize>), usize> = HashMap::new();
+        let chunk_to_distinct : Vec<Option<usize>> = chunks.iter().enumerate().map(|(i, chunk)| {
+            let group = cell_groups.get(i).cloned().unwrap_or(None);
+            chunk.map(|chunk| *indices.entry((chunk.0, group)).or_insert_with(|| {
+                distinct.push((chunk.0, chunk.1, group));
+                distinct.len() - 1
+            }))
+        }).collect();
+        let cache_hits = (chunk_to_distinct.iter().flatten().count() - distinct.len()) as u32;
+
+        let factors = self.emoticon_factors(&emoticon_refs, pool);
+        let coarse : Vec<Option<hsl::CoarseHistogram>> = match self.candidate_k {
+            Some(k) if k < emoticons.len() => install(pool, || {
+                emoticon_refs.par_iter()
+                             .map(|e| e.hist.smoothed.as_ref().map(|_| e.hist.downsample(COARSE_FACTOR)))
+                             .collect()
+            }),
+            _ => Vec::new(),
+        };
+        println!("Finding best emoticons for {} distinct chunks", distinct.len());
+        let rankings : Vec<Ranking> = install(pool, || {
+            distinct.par_iter()
+                    .map(|&(signature, hist, group)| {
+                        let allowed = group.map(|g| &groups[g][..]);
+                        self.best_emoticons(hist, signature, &emoticon_refs, emoticon_bins, &factors, &coarse, allowed)
+                    })
+                    .collect()
+        });
+
+        println!(" Done. ({} chunks taken from cache)", cache_hits);
+        let rankings = chunk_to_distinct.iter().map(|c| c.map(|c| &rankings[c])).collect();
+        let colors = chunks.iter().map(|c| c.and_then(|c| c.1.dominant_color())).collect();
+        self.assemble(width, height, rankings, colors, emoticons, cache_hits)
+    }
+
+    /// Calculates only the histograms of the chunks, row by row. `None` for chunks
+    /// that are too transparent. Pass them to `match_histograms()`.
+    ///
+    /// Respects `frac`, `pad`, `auto_white_balance`, `transparent_threshold`, `posterize_levels` and `threads`.
+    pub fn analyze(&self, img : &DynamicImage) -> Result<Vec<Option<hsl::HslHistogram>>, EmotimError> {
+        self.check_image(img)?;
+        let padded;
+        let img = if self.pad {
+            padded = pad_to_multiple(img, self.frac, self.pad_fill);
+            &padded
+        } else {
+            img
+        };
+        let (width, height) = grid_size(img, self.frac);
+        let rgba = self.input_pixels(img);
+        let pool = self.thread_pool()?;
+        Ok(install(&pool, || {
+            (0..width * height).into_par_iter()
+                               .map(|i| {
+                                   let (_, subimghsv) = self.read_region(&rgba, i % width * self.frac, i / width * self.frac, self.frac);
+                                   self.reduced_histogram(&subimghsv)
+                               })
+                               .collect()
+        }))
+    }
+
+    /// Matches the histograms calculated by `analyze()` for an image of `width` × `height`
+    /// chunks with the emoticons. Only the methods that need nothing but the histogram are
+    /// supported: `Correlation`, `Maxima`, `SoftMaxima`, `Chroma` and `ColorCoverage`.
+    /// Default `bins` are used.
+    pub fn match_histograms(&self,
+                            histograms : &[Option<hsl::HslHistogram>],
+                            width : u32,
+                            height : u32,
+                            emoticons : &Emoticons) -> Result<Emoimage, EmotimError> {
+        match self.method {
+            ComparisationMethod::Correlation
+            | ComparisationMethod::Maxima
+            | ComparisationMethod::Chroma
+            | ComparisationMethod::SoftMaxima
+            | ComparisationMethod::ColorCoverage { .. } => {},
+            method => return Err(EmotimError::UnsupportedMethod(method)),
+        }
+        let expected = width as usize * height as usize;
+        if histograms.len() != expected {
+            return Err(EmotimError::HistogramCount { expected : expected, actual : histograms.len() });
+        }
+        let filtered = self.filtered_emoticons(emoticons);
+        let emoticons = &*filtered;
+        self.check_emoticons(emoticons)?;
+        let signatures : Vec<Option<ChunkSignature>> = histograms.iter().map(|h| h.as_ref().map(|h| ChunkSignature {
+            maxima : h.signature(),
+            distribution : self.distribution_digest(h),
+            spatial : None,
+            binned : None,
+            mean : None,
+            oklab : None,
+            grey : false,
+            edges : None,
+        })).collect();
+        let chunks = signatures.iter().zip(histograms.iter())
+                                      .map(|(s, h)| s.as_ref().zip(h.as_ref()))
+                                      .collect();
+        let pool = self.thread_pool()?;
+        non_empty(self.in_linear_light(emoticons, |emoticons| self.match_chunks(width, height, chunks, &pool, emoticons, &[])))
+    }
+
+    /// The pixels of `img`, white balanced if requested by `auto_white_balance()`.
+    fn input_pixels(&self, img : &DynamicImage) -> RgbaImage {
+        let mut rgba = img.to_rgba();
+        if self.auto_white_balance {
+            gray_world(&mut rgba);
+        }
+        rgba
+    }
+
+    /// Whether histograms with non default bins have to be calculated for each run.
+    fn uses_bins(&self) -> bool {
+        self.method == ComparisationMethod::Correlation && self.bins != hsl::Bins::default()
+    }
+
+    /// Creates the thread pool for `threads()`. `None` means the global pool.
+    fn thread_pool(&self) -> Result<Option<rayon::ThreadPool>, EmotimError> {
+        match self.threads {
+            Some(threads) => rayon::ThreadPoolBuilder::new().num_threads(threads)
+                                                            .build()
+                                                            .map(Some)
+                                                            .map_err(|e| EmotimError::ThreadPool(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Copies the square of size `size` at `left`, `top` (usually a chunk) and converts
+    /// it to HSL. Posterizes the HSL version if requested.
+    fn read_region(&self, img : &RgbaImage, left : u32, top : u32, size : u32) -> (RgbaImage, hsl::HslImage) {
+        let subimg = RgbaImage::from_fn(size, size, |x, y| *img.get_pixel(left + x, top + y));
+        let mut subimghsv = hsl::HslImage::from_image_with(&subimg, self.linear_light);
+        if let Some(levels) = self.posterize_levels {
+            subimghsv.posterize(levels);
+        }
+        (subimg, subimghsv)
+    }
+
+    /// Histogram of a chunk with reduced depth. `None` if the chunk is too transparent.
+    fn reduced_histogram(&self, subimghsv : &hsl::HslImage) -> Option<hsl::HslHistogram> {
+        let subimgreduced = subimghsv.reduce_dynamic();
+        if subimgreduced.transparency() > self.transparent_threshold {
+            return None;
+        }
+        Some(subimgreduced.histogram())
+    }
+
+    /// Calculates the signature and the histogram of the square of size `size` at `left`,
+    /// `top` (usually a chunk). `None` if it is too transparent.
+    fn analyze_region(&self, img : &RgbaImage, left : u32, top : u32, size : u32, use_bins : bool)
+                      -> Option<(ChunkSignature, hsl::HslHistogram)> {
+        let (subimg, subimghsv) = self.read_region(img, left, top, size);
+        let mut subimghist = self.reduced_histogram(&subimghsv)?;
+        // The chunks are kept until all are matched. Methods that compare only maxima do
+        // not need the smoothed distribution, unless for the coarse pass of `candidate_k`.
+        // The distribution stays for the dominant color.
+        if self.method.histogram_mode() == hsl::HistogramMode::Maxima && self.candidate_k.is_none() {
+            subimghist.smoothed = None;
         }
+        let binned = if use_bins { Some(subimghsv.binned_histogram(self.bins)) } else { None };
+        let signature = ChunkSignature {
+            maxima : subimghist.signature(),
+            distribution : match binned {
+                Some(ref binned) => Some(binned.digest()),
+                None => self.distribution_digest(&subimghist),
+            },
+            spatial : match self.method {
+                ComparisationMethod::Spatial => Some(hsl::SpatialSignature::from_image(&subimg)),
+                _ => None,
+            },
+            binned : binned,
+            mean : match self.method {
+                ComparisationMethod::NearestColor => Some(hsl::mean_color(&subimg)),
+                _ => None,
+            },
+            oklab : match self.method {
+                ComparisationMethod::Oklab => oklab::mean_oklab(&subimg).map(|mean| mean.to_bits()),
+                _ => None,
+            },
+            grey : self.method == ComparisationMethod::Adaptive
+                   && subimghsv.mean_chroma() < ADAPTIVE_CHROMA_THRESHOLD,
+            edges : if self.edge_weight != 0.0 { Some(subimghsv.edge_density().to_bits()) } else { None },
+        };
+        Some((signature, subimghist))
     }
 
-    /// Saves the calculated emoticons as image
-    pub fn save(&self, path : &Path) {
-        // Calculate dimensions
-        // Use first emoticon as base for height / width
-        let exampleemo = self.emopixels.first().unwrap();
-        let height = exampleemo.img.height() * self.height;
-        let width = exampleemo.img.width() * self.width;
-        let raw = vec![0 ; (height * width * 4) as usize];
-        let img = RgbaImage::from_raw(width, height, raw).unwrap();
-        let mut img = DynamicImage::ImageRgba8(img);
-        for h in 0..self.height {
-            for w in 0..self.width {
-                img.copy_from(&self.emopixels[(h * self.width + w) as usize].img,
-                              w * exampleemo.img.width(),
-                              h * exampleemo.img.height());
+    /// `HslHistogram::smoothed_digest()` if the method correlates the whole distribution.
+    fn distribution_digest(&self, hist : &hsl::HslHistogram) -> Option<u64> {
+        if self.method.histogram_mode() == hsl::HistogramMode::Correlation {
+            hist.smoothed_digest()
+        } else {
+            None
+        }
+    }
+
+    /// Like `calculate()`, but scales the image down to one pixel per chunk first and
+    /// matches the color of each pixel via `ComparisationMethod::NearestColor`.
+    /// Ignores `method`, `pad`, `bins` and `threads`.
+    fn calculate_fast(&self, img : &DynamicImage, emoticons : &Emoticons) -> Emoimage {
+        let (width, height) = grid_size(img, self.frac);
+        let small = img.resize_exact(width, height, FilterType::Lanczos3);
+        let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+        let mut cache : HashMap<hsl::Hsl, Ranking> = HashMap::new();
+        let mut cells = Vec::with_capacity(width as usize * height as usize);
+        let mut cache_hits = 0;
+
+        for (_, _, pixel) in small.pixels() {
+            if 1.0 - pixel.data[3] as f32 / 255.0 > self.transparent_threshold {
+                cells.push(None);
+                continue;
             }
+            let mut color = hsl::Hsl::from(pixel);
+            color.a = 255;
+            let color = color.reduce_dynamic();
+            if cache.contains_key(&color) {
+                cache_hits += 1;
+            } else {
+                let best = self.rank(&emoticon_refs, |_, e| e.mean_color.similarity(&color));
+                cache.insert(color.clone(), best);
+            }
+            cells.push(Some(color));
         }
-        let ref mut fout = File::create(path).unwrap();
-        let _ = img.save(fout, image::PNG).unwrap();
+        let rankings = cells.iter().map(|c| c.as_ref().map(|c| &cache[c])).collect();
+        self.assemble(width, height, rankings, cells, emoticons, cache_hits)
     }
-}
 
-impl fmt::Display for Emoimage {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for line in self.emopixels.chunks(self.width as usize) {
-            for s in line {
-                try!(write!(f, "{}", s));
+    /// `Emoimage::from_rankings()`, but avoids adjacent repeats first if requested.
+    fn assemble(&self,
+                width : u32,
+                height : u32,
+                rankings : Vec<Option<&Ranking>>,
+                chunk_colors : Vec<Option<hsl::Hsl>>,
+                emoticons : &Emoticons,
+                cache_hits : u32) -> Emoimage {
+        let picked;
+        let rankings = match self.tiebreak_seed {
+            Some(seed) => {
+                picked = self.pick_near_best(seed, &rankings);
+                picked.iter().map(|r| r.as_ref()).collect()
+            },
+            None => rankings,
+        };
+        let mut emoimage = if self.no_adjacent_repeat {
+            let rankings = self.avoid_adjacent_repeats(width, &rankings);
+            let rankings = rankings.iter().map(|r| r.as_ref()).collect();
+            Emoimage::from_rankings(width, height, rankings, chunk_colors, emoticons, cache_hits)
+        } else {
+            Emoimage::from_rankings(width, height, rankings, chunk_colors, emoticons, cache_hits)
+        };
+        if let Some(floor) = self.blank_below {
+            emoimage.blank_below(floor);
+        }
+        emoimage.frac = self.frac;
+        emoimage
+    }
+
+    /// The lowest similarity that is within `tiebreak_epsilon` of the `best` one.
+    fn near_best_floor(&self, best : f32) -> f32 {
+        best - self.tiebreak_epsilon * best.abs()
+    }
+
+    /// Moves a random one of the emoticons within `tiebreak_epsilon` of the best one to
+    /// the front of each ranking. The choice depends only on `seed` and the position of
+    /// the cell. Cuts the rankings to the ranked alternatives (see `rank()`).
+    fn pick_near_best(&self, seed : u64, rankings : &[Option<&Ranking>]) -> Vec<Option<Ranking>> {
+        let alternatives = if self.no_adjacent_repeat { self.alternatives.max(2) } else { self.alternatives };
+        rankings.iter().enumerate().map(|(i, ranking)| ranking.map(|ranking| {
+            let floor = self.near_best_floor(ranking[0].1);
+            let near = ranking.iter().take_while(|r| r.1 >= floor).count();
+            let pos = (splitmix64(seed ^ (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)) % near as u64) as usize;
+            let mut ranking = ranking.clone();
+            let chosen = ranking.remove(pos);
+            ranking.insert(0, chosen);
+            ranking.truncate(alternatives + 1);
+            ranking
+        })).collect()
+    }
+
+    /// Moves the best emoticon that differs from the chosen ones of the left and upper
+    /// neighbours to the front of each ranking, in scan order. Keeps the best one if all
+    /// ranked emoticons repeat a neighbour. Cuts the rankings to `alternatives + 1`.
+    fn avoid_adjacent_repeats(&self, width : u32, rankings : &[Option<&Ranking>]) -> Vec<Option<Ranking>> {
+        let width = width as usize;
+        let mut result : Vec<Option<Ranking>> = Vec::with_capacity(rankings.len());
+        for (i, ranking) in rankings.iter().enumerate() {
+            let ranking = ranking.map(|ranking| {
+                let chosen_at = |j : usize| result[j].as_ref().map(|r : &Ranking| r[0].0);
+                let left = if i % width > 0 { chosen_at(i - 1) } else { None };
+                let above = if i >= width { chosen_at(i - width) } else { None };
+                let pos = ranking.iter()
+                                 .position(|&(e, _)| Some(e) != left && Some(e) != above)
+                                 .unwrap_or(0);
+                let mut ranking = ranking.clone();
+                let chosen = ranking.remove(pos);
+                ranking.insert(0, chosen);
+                ranking.truncate(self.alternatives + 1);
+                ranking
+            });
+            result.push(ranking);
+        }
+        result
+    }
+
+    /// Matches a chunk of the solid `color` with the emoticons. `None` if the color is
+    /// too transparent.
+    fn rank_solid_color(&self, color : Rgba<u8>, emoticons : &[&emoticons::Emoticon]) -> Option<Ranking> {
+        let chunk = RgbaImage::from_pixel(SOLID_CHUNK_SIZE, SOLID_CHUNK_SIZE, color);
+        self.analyze_region(&chunk, 0, 0, SOLID_CHUNK_SIZE, false)
+            .map(|(signature, hist)| self.best_emoticons(&hist, &signature, emoticons, &[], &[], &[], None))
+    }
+
+    /// Compares the histogram of a chunk with all emoticons. Returns the best
+    /// emoticon followed by `self.alternatives` next best ones.
+    ///
+    /// `emoticon_bins` are the `BinnedHistogram`s of the emoticons if non default bins are used,
+    /// `factors` are the factors of `emoticon_factors()` (empty if all are 1),
+    /// `coarse` the downsampled emoticon histograms for `candidate_k` (empty if not used) and
+    /// `allowed` the sorted indices of the emoticons of the group of `regions()` (`None` for all).
+    #[allow(clippy::too_many_arguments)]
+    fn best_emoticons(&self,
+                      subimghist : &hsl::HslHistogram,
+                      signature : &ChunkSignature,
+                      emoticons : &[&emoticons::Emoticon],
+                      emoticon_bins : &[hsl::BinnedHistogram],
+                      factors : &[f32],
+                      coarse : &[Option<hsl::CoarseHistogram>],
+                      allowed : Option<&[usize]>) -> Ranking {
+        let candidates = match (self.candidates(subimghist, emoticons, coarse), allowed) {
+            (candidates, None) => candidates,
+            (None, Some(allowed)) => Some(allowed.to_vec()),
+            (Some(candidates), Some(allowed)) => {
+                let candidates : Vec<usize> = candidates.into_iter().filter(|i| allowed.binary_search(i).is_ok()).collect();
+                // The coarse pass may drop the whole group
+                Some(if candidates.is_empty() { allowed.to_vec() } else { candidates })
+            },
+        };
+        let edges = signature.edges.map(f32::from_bits);
+        let color = |i : usize, e : &emoticons::Emoticon| self.color_similarity(i, e, subimghist, signature, emoticon_bins);
+        // Most color similarities are not bounded by 1, so they are divided by the best one
+        // before the edge similarity is blended in. Otherwise the colors would dominate.
+        let colors : Vec<Option<f32>> = match edges {
+            Some(_) => emoticons.iter().enumerate().map(|(i, e)| {
+                let compared = e.coverage >= self.min_coverage
+                               && candidates.as_ref().is_none_or(|c| c.binary_search(&i).is_ok());
+                if compared { Some(color(i, e)) } else { None }
+            }).collect(),
+            None => Vec::new(),
+        };
+        let best_color = colors.iter().flatten().cloned().filter(|c| c.is_finite()).fold(0.0, f32::max);
+        let score = |i : usize, e : &emoticons::Emoticon| {
+            let mut similarity = match colors.get(i) {
+                Some(&Some(similarity)) => similarity,
+                _ => color(i, e),
+            };
+            if let Some(edges) = edges {
+                if best_color > 0.0 {
+                    similarity /= best_color;
+                }
+                similarity = (1.0 - self.edge_weight) * similarity
+                             + self.edge_weight * (1.0 - (edges - e.edge_density).abs());
             }
-            try!(write!(f, "\n"));
+            match factors.get(i) {
+                Some(factor) => similarity * factor,
+                None => similarity,
+            }
+        };
+        match candidates {
+            Some(candidates) => {
+                let subset : Vec<&emoticons::Emoticon> = candidates.iter().map(|&i| emoticons[i]).collect();
+                self.rank(&subset, |j, e| score(candidates[j], e))
+                    .into_iter()
+                    .map(|(j, s)| (candidates[j], s))
+                    .collect()
+            },
+            None => self.rank(emoticons, score),
         }
-        Ok(())
+    }
+
+    /// Indices of the emoticons that pass the coarse pass of `candidate_k()`, in their
+    /// original order. `None` if all emoticons have to be compared.
+    fn candidates(&self,
+                  subimghist : &hsl::HslHistogram,
+                  emoticons : &[&emoticons::Emoticon],
+                  coarse : &[Option<hsl::CoarseHistogram>]) -> Option<Vec<usize>> {
+        let k = self.candidate_k?;
+        if coarse.is_empty() || subimghist.smoothed.is_none() {
+            return None;
+        }
+        // Enough candidates for the alternatives, see `rank()`
+        let alternatives = if self.no_adjacent_repeat { self.alternatives.max(2) } else { self.alternatives };
+        let k = k.max(alternatives + 1);
+        let chunk = subimghist.downsample(COARSE_FACTOR);
+        let mut scored : Vec<(usize, f32)> = emoticons.iter()
+                                                      .zip(coarse)
+                                                      .enumerate()
+                                                      .filter(|(_, (e, _))| e.coverage >= self.min_coverage)
+                                                      .map(|(i, (_, c))| match *c {
+                                                          Some(ref c) => (i, c.similarity_by_correlation(&chunk)),
+                                                          None => (i, f32::INFINITY),
+                                                      })
+                                                      .collect();
+        // Stable, so that equal scores keep the order of `emoticons`
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        let mut candidates : Vec<usize> = scored.iter()
+                                                .enumerate()
+                                                .take_while(|&(n, s)| n < k || s.1 == f32::INFINITY)
+                                                .map(|(_, s)| s.0)
+                                                .collect();
+        candidates.sort();
+        Some(candidates)
+    }
+
+    /// The factor of each emoticon by which its similarity is multiplied: the product of
+    /// the mood factor (see `mood_factors()`) and the weight (see `weights()`). Empty if
+    /// there are neither references nor weights.
+    fn emoticon_factors(&self, emoticons : &[&emoticons::Emoticon], pool : &Option<rayon::ThreadPool>) -> Vec<f32> {
+        let mood = self.mood_factors(emoticons, pool);
+        if self.weights.is_empty() {
+            return mood;
+        }
+        emoticons.iter().enumerate().map(|(i, e)| {
+            mood.get(i).cloned().unwrap_or(1.0) * self.weights.get(&e.codepoints_hex()).cloned().unwrap_or(1.0)
+        }).collect()
+    }
+
+    /// The factor `1 + mood_strength * mood` of each emoticon, see `mood()`. Empty if
+    /// there are no references.
+    fn mood_factors(&self, emoticons : &[&emoticons::Emoticon], pool : &Option<rayon::ThreadPool>) -> Vec<f32> {
+        let references : Vec<&emoticons::Emoticon> = emoticons.iter()
+                                                              .filter(|e| self.mood.contains(&e.codepoints()))
+                                                              .cloned()
+                                                              .collect();
+        if references.is_empty() {
+            return Vec::new();
+        }
+        let self_similarities : Vec<f32> = references.iter().map(|r| r.similarity(r, self.method)).collect();
+        install(pool, || {
+            emoticons.par_iter()
+                     .map(|e| {
+                         let mood = references.iter()
+                                              .zip(self_similarities.iter())
+                                              .filter(|&(_, &s)| s > 0.0)
+                                              .map(|(r, &s)| (e.similarity(r, self.method) / s).clamp(0.0, 1.0))
+                                              .fold(0.0, f32::max);
+                         1.0 + self.mood_strength * mood
+                     })
+                     .collect()
+        })
+    }
+
+    /// Similarity of the colors of a chunk and an emoticon, according to `method`.
+    fn color_similarity(&self,
+                        i : usize,
+                        e : &emoticons::Emoticon,
+                        subimghist : &hsl::HslHistogram,
+                        signature : &ChunkSignature,
+                        emoticon_bins : &[hsl::BinnedHistogram]) -> f32 {
+        match self.method {
+            ComparisationMethod::Correlation => match signature.binned {
+                Some(ref binned) => emoticon_bins[i].similarity_by_correlation(binned),
+                None => e.similarity_by_correlation(subimghist),
+            },
+            ComparisationMethod::Maxima => e.hist.similarity_by_maxima_with(subimghist, self.maxima_aggregate),
+            ComparisationMethod::Spatial => e.spatial.similarity(signature.spatial.as_ref().unwrap()),
+            ComparisationMethod::NearestColor => e.mean_color.similarity(signature.mean.as_ref().unwrap()),
+            ComparisationMethod::Adaptive => if signature.grey {
+                e.hist.similarity_by_lightness(subimghist)
+            } else {
+                e.hist.similarity_by_correlation(subimghist)
+            },
+            ComparisationMethod::Chroma => e.hist.similarity_by_chroma(subimghist),
+            ComparisationMethod::SoftMaxima => e.hist.similarity_by_soft_maxima(subimghist, self.soft_maxima_bandwidth),
+            ComparisationMethod::Oklab => match (e.mean_oklab, signature.oklab) {
+                (Some(ref mean), Some(bits)) => mean.similarity(&oklab::Oklab::from_bits(bits)),
+                _ => 0.0,
+            },
+            ComparisationMethod::ColorCoverage { gamma } =>
+                e.similarity_by_correlation(subimghist) * e.coverage.powf(gamma),
+        }
+    }
+
+    /// Returns the emoticon with the highest `similarity(index, emoticon)` followed
+    /// by `self.alternatives` next best ones. Applies the colorfulness and coverage
+    /// biases and skips emoticons below `min_coverage`.
+    ///
+    /// With `no_adjacent_repeat`, at least three emoticons are ranked, so that there is
+    /// one left after excluding the left and the upper neighbour.
+    ///
+    /// With `random_tiebreak()`, all emoticons within `tiebreak_epsilon` of the best one
+    /// are ranked, too.
+    ///
+    /// On equal similarity, the emoticon that comes first in `emoticons` wins.
+    fn rank<F>(&self, emoticons : &[&emoticons::Emoticon], similarity : F) -> Ranking
+        where F : Fn(usize, &emoticons::Emoticon) -> f32 {
+        let alternatives = if self.no_adjacent_repeat { self.alternatives.max(2) } else { self.alternatives };
+        let mut best : Ranking = Vec::with_capacity(alternatives + 2);
+        for (i, e) in emoticons.iter().enumerate() {
+            if e.coverage < self.min_coverage {
+                continue;
+            }
+            let mut similarity = similarity(i, e);
+            if self.colorfulness_bias != 0.0 {
+                similarity *= 1.0 + self.colorfulness_bias * e.mean_chroma;
+            }
+            if self.coverage_bias != 0.0 {
+                similarity *= 1.0 + self.coverage_bias * e.coverage;
+            }
+            let near_best = |best : &Ranking, similarity : f32| {
+                self.tiebreak_seed.is_some() && similarity >= self.near_best_floor(best[0].1)
+            };
+            if best.len() > alternatives && similarity <= best.last().unwrap().1 && !near_best(&best, similarity) {
+                continue;
+            }
+            let pos = best.iter().position(|b| similarity > b.1).unwrap_or(best.len());
+            best.insert(pos, (i, similarity));
+            let near = best.iter().take_while(|b| near_best(&best, b.1)).count();
+            best.truncate(near.max(alternatives + 1));
+        }
+        best
     }
 }
 
+/// The emoticons whose dominant color is colorful and has a hue of at most `width`
+/// steps away from `hue`, see `EmoimageBuilder::filtered_emoticons()`.
+fn in_hue_family(emoticons : &Emoticons, (hue, width) : (u8, u8)) -> Emoticons {
+    emoticons.iter()
+             .filter(|e| e.dominant_color().is_some_and(|c| {
+                 // the reduced hue wraps around after 15
+                 let distance = (c.h2 as i32 - hue as i32).rem_euclid(16);
+                 c.c2 >= HUE_FAMILY_MIN_CHROMA && distance.min(16 - distance) <= width as i32
+             }))
+             .cloned()
+             .collect()
+}
 
+/// A well mixed pseudo random number derived from `x` (SplitMix64).
+fn splitmix64(x : u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
 
-#[cfg(test)]
-mod tests {
-    //use super::*;
-    use test::Bencher;
-    use image::{DynamicImage};
-    use image;
-    use std::path::Path;
+/// Runs `f` in `pool` or, if `None`, in the global thread pool.
+fn install<T, F>(pool : &Option<rayon::ThreadPool>, f : F) -> T
+    where T : Send, F : FnOnce() -> T + Send {
+    match *pool {
+        Some(ref pool) => pool.install(f),
+        None => f(),
+    }
+}
 
+/// Size of the solid chunks that `top_emoticons_for()` and `match_palette()` match.
+const SOLID_CHUNK_SIZE : u32 = 16;
 
-    fn open_image() -> DynamicImage {
-        let inputimagepath = Path::new("assets/emoticons2/00a9.png");
-        let img = image::open(&inputimagepath).unwrap();
-        img
+/// The best emoticon for each named color, eg. for the colors of a brand: Each color is
+/// matched like a solid chunk. Returns the names with the emoticons and their similarity,
+/// in the order of `colors`. Colors that are too transparent and an empty set of
+/// emoticons give no entry.
+pub fn match_palette(colors : &[(String, Rgba<u8>)],
+                     emoticons : &Emoticons,
+                     method : ComparisationMethod) -> Vec<(String, Rc<emoticons::Emoticon>, f32)> {
+    if emoticons.is_empty() {
+        return Vec::new();
     }
+    let builder = EmoimageBuilder::new().frac(SOLID_CHUNK_SIZE).method(method).alternatives(0);
+    let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+    colors.iter().filter_map(|(name, color)| {
+        builder.rank_solid_color(*color, &emoticon_refs)
+               .map(|ranking| (name.clone(), emoticons[ranking[0].0].clone(), ranking[0].1))
+    }).collect()
+}
 
-    #[bench]
-    fn bench_open_image(b: &mut Bencher) {
-        b.iter(|| open_image());
+/// Finds `n` distinct emoticons that represent the colors of the whole image, eg. for
+/// a sticker set. Returns them with their similarity, most important color first.
+///
+/// Each of the biggest maxima of the histogram of the whole image is matched like a
+/// solid chunk of its color. The emoticons are taken round robin from the rankings
+/// of the maxima, so that each color is represented and no emoticon appears twice.
+pub fn top_emoticons_for(img : &DynamicImage,
+                         n : usize,
+                         emoticons : &Emoticons,
+                         method : ComparisationMethod) -> Vec<(Rc<emoticons::Emoticon>, f32)> {
+    let hist = hsl::HslImage::from_image(&img.to_rgba()).reduce_dynamic().histogram();
+    let builder = EmoimageBuilder::new().frac(SOLID_CHUNK_SIZE).method(method).alternatives(n);
+    let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+    let rankings : Vec<Ranking> = hist.biggest_maxima(n).iter().filter_map(|(color, _)| {
+        // center of the bin in full color depth
+        let center = hsl::Hsl::new(color.h2 * 16 + 8, color.c2 * 16 + 8, color.l * 16 + 8, 255);
+        builder.rank_solid_color(center.to_rgba(), &emoticon_refs)
+    }).collect();
+
+    let mut chosen : Vec<(usize, f32)> = Vec::with_capacity(n);
+    let mut positions = vec![0 ; rankings.len()];
+    while chosen.len() < n {
+        let mut found = false;
+        for (ranking, pos) in rankings.iter().zip(positions.iter_mut()) {
+            while let Some(&(i, similarity)) = ranking.get(*pos) {
+                *pos += 1;
+                if chosen.iter().all(|c| c.0 != i) {
+                    chosen.push((i, similarity));
+                    found = true;
+                    break;
+                }
+            }
+            if chosen.len() == n {
+                break;
+            }
+        }
+        if !found {
+            break;
+        }
+    }
+    chosen.into_iter().map(|(i, similarity)| (emoticons[i].clone(), similarity)).collect()
+}
+
+/// The similarity of every emoticon to the chunk at column `x` and row `y` (in chunks)
+/// of `img`, best first. Shows why a chunk got a surprising emoticon. Empty if the
+/// chunk is outside of the image or too transparent.
+pub fn explain_chunk(img : &DynamicImage,
+                     frac : u32,
+                     x : u32,
+                     y : u32,
+                     emoticons : &Emoticons,
+                     method : ComparisationMethod) -> Vec<(Rc<emoticons::Emoticon>, f32)> {
+    let builder = EmoimageBuilder::new().frac(frac).method(method).alternatives(emoticons.len());
+    if builder.check_image(img).is_err() || emoticons.is_empty() {
+        return Vec::new();
+    }
+    let (width, height) = grid_size(img, frac);
+    if x >= width || y >= height {
+        return Vec::new();
+    }
+    let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+    match builder.analyze_region(&img.to_rgba(), x * frac, y * frac, frac, false) {
+        Some((signature, hist)) => builder.best_emoticons(&hist, &signature, &emoticon_refs, &[], &[], &[], None)
+                                          .into_iter()
+                                          .map(|(i, similarity)| (emoticons[i].clone(), similarity))
+                                          .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Maximum number of chunks that `emojifiability()` compares.
+pub const EMOJIFIABILITY_SAMPLES : u32 = 64;
+
+/// Predicts how well `img` can be converted: the mean similarity of the best emoticon
+/// over up to `EMOJIFIABILITY_SAMPLES` evenly spread chunks. Low values mean that the
+/// emoticons do not cover the colors of the image well, so that a caller can warn before
+/// converting.
+///
+/// As in `Emoimage::auto_frac()`, the similarities of `Correlation` and `Adaptive` are
+/// divided by `frac * frac`. Empty chunks are not counted. 0.0 if the image can not be
+/// split into chunks of size `frac` or all sampled chunks are empty.
+pub fn emojifiability(img : &DynamicImage,
+                      frac : u32,
+                      emoticons : &Emoticons,
+                      method : ComparisationMethod) -> f32 {
+    let builder = EmoimageBuilder::new().frac(frac).method(method);
+    if builder.check(img, emoticons).is_err() {
+        return 0.0;
+    }
+    let (width, height) = grid_size(img, frac);
+    let chunks = width * height;
+    let step = chunks.div_ceil(EMOJIFIABILITY_SAMPLES);
+    let rgba = img.to_rgba();
+    let emoticon_refs : Vec<&emoticons::Emoticon> = emoticons.iter().map(|e| &**e).collect();
+    let similarities : Vec<f32> = (0..chunks).into_par_iter().step_by(step as usize).filter_map(|i| {
+        builder.analyze_region(&rgba, i % width * frac, i / width * frac, frac, false)
+               .map(|(signature, hist)| builder.best_emoticons(&hist, &signature, &emoticon_refs, &[], &[], &[], None)[0].1)
+    }).collect();
+    if similarities.is_empty() {
+        return 0.0;
+    }
+    let mut score = similarities.iter().sum::<f32>() / similarities.len() as f32;
+    if method == ComparisationMethod::Correlation || method == ComparisationMethod::Adaptive {
+        score /= (frac * frac) as f32;
+    }
+    score
+}
+
+/// Number of chunks (= emoticons) the image is split into with chunk size `frac`.
+/// 0 if `frac` is 0. The remainder at the right and bottom border is cut off, padding
+/// (see `EmoimageBuilder::pad()`) adds at most one row and column.
+///
+/// The matching takes roughly `chunks × emoticons.len()` comparisons, less if
+/// chunks are equal. Use this to estimate the run time before converting.
+pub fn estimate_chunks(img : &DynamicImage, frac : u32) -> usize {
+    if frac == 0 {
+        return 0;
+    }
+    let (width, height) = grid_size(img, frac);
+    width as usize * height as usize
+}
+
+/// Whether the chunk is nearly uniform light background, see `EmoimageBuilder::blank_uniform()`.
+fn is_uniform_background(hist : &hsl::HslHistogram) -> bool {
+    match hist.dominant_share() {
+        Some((color, share)) => color.c2 <= BLANK_UNIFORM_MAX_CHROMA
+                                && color.l >= BLANK_UNIFORM_MIN_LIGHTNESS
+                                && share >= BLANK_UNIFORM_SHARE,
+        None => false,
+    }
+}
+
+/// Mean of the variances of the RGB channels (scaled to 0.0 - 1.0) of the square of
+/// size `size` at `left`, `top`.
+fn color_variance(img : &RgbaImage, left : u32, top : u32, size : u32) -> f32 {
+    let n = (size * size) as f32;
+    let mut sums = [0f32 ; 3];
+    let mut squares = [0f32 ; 3];
+    for y in top..top + size {
+        for x in left..left + size {
+            let pixel = img.get_pixel(x, y);
+            for c in 0..3 {
+                let v = pixel.data[c] as f32 / 255.0;
+                sums[c] += v;
+                squares[c] += v * v;
+            }
+        }
+    }
+    (0..3).map(|c| squares[c] / n - (sums[c] / n).powi(2)).sum::<f32>() / 3.0
+}
+
+/// Number of chunks per row and column.
+fn grid_size(img : &DynamicImage, frac : u32) -> (u32, u32) {
+    (img.width() / frac, img.height() / frac)
+}
+
+/// Enlarges `img` so that width and height are multiples of `frac`.
+/// The original image is centered, the border is filled with `fill`.
+fn pad_to_multiple(img : &DynamicImage, frac : u32, fill : Rgba<u8>) -> DynamicImage {
+    let width = img.width().div_ceil(frac) * frac;
+    let height = img.height().div_ceil(frac) * frac;
+    let mut padded = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, fill));
+    padded.copy_from(img, (width - img.width()) / 2, (height - img.height()) / 2);
+    padded
+}
+
+/// Scales each color channel of the pixels of `img` so that the means of the visible
+/// pixels (alpha above 80%) become the mean of all three channels. Works on the RGB
+/// values directly, so images without a cast stay unchanged.
+fn gray_world(img : &mut RgbaImage) {
+    let (mut sums, mut visible) = ([0u64 ; 3], 0u64);
+    for p in img.pixels().filter(|p| p.data[3] > 204) {
+        for (sum, &value) in sums.iter_mut().zip(p.data.iter()) {
+            *sum += value as u64;
+        }
+        visible += 1;
+    }
+    if visible == 0 {
+        return;
+    }
+    let gray = (sums[0] + sums[1] + sums[2]) as f32 / 3.0;
+    let gains : Vec<f32> = sums.iter().map(|&sum| if sum > 0 { gray / sum as f32 } else { 1.0 }).collect();
+    for p in img.pixels_mut() {
+        for (value, gain) in p.data.iter_mut().zip(gains.iter()) {
+            *value = (*value as f32 * gain).round().min(255.0) as u8;
+        }
+    }
+}
+
+/// Fails with `NoEmoticons` if all cells of `emoimage` are empty, which can not be rendered.
+fn non_empty(emoimage : Emoimage) -> Result<Emoimage, EmotimError> {
+    if emoimage.emopixels.iter().all(|e| e.is_none()) {
+        return Err(EmotimError::NoEmoticons);
+    }
+    Ok(emoimage)
+}
+
+/// Writes `content` to the file at `path`. Creates missing parent directories.
+fn write_creating_dirs(path : &Path, content : &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut fout = File::create(path)?;
+    fout.write_all(content)
+}
+
+/// Encodes `img` as png.
+fn encode_png(img : &DynamicImage) -> io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    img.save(&mut png, image::PNG).map_err(|e| match e {
+        image::ImageError::IoError(e) => e,
+        e => io::Error::other(e),
+    })?;
+    Ok(png)
+}
+
+impl Emoimage {
+    /// Assembles an `Emoimage` from the ranking and the chunk color of each cell (`None` for empty cells).
+    fn from_rankings(width : u32,
+                     height : u32,
+                     rankings : Vec<Option<&Ranking>>,
+                     chunk_colors : Vec<Option<hsl::Hsl>>,
+                     emoticons : &Emoticons,
+                     cache_hits : u32) -> Emoimage {
+        let mut pixels = Vec::with_capacity(rankings.len());
+        let mut confidences = Vec::with_capacity(rankings.len());
+        let mut alternatives = Vec::with_capacity(rankings.len());
+        for ranking in rankings {
+            match ranking {
+                Some(ranking) => {
+                    let (the_chosen_one, similarity) = ranking[0];
+                    pixels.push(Some(emoticons[the_chosen_one].clone()));
+                    confidences.push(similarity);
+                    alternatives.push(ranking[1..].iter().map(|&(i, s)| (emoticons[i].clone(), s)).collect());
+                },
+                None => {
+                    pixels.push(None);
+                    confidences.push(0.0);
+                    alternatives.push(Vec::new());
+                }
+            }
+        }
+        Emoimage {
+            width : width,
+            height : height,
+            emopixels : pixels,
+            confidences : confidences,
+            alternatives : alternatives,
+            cache_hits : cache_hits,
+            chunk_colors : chunk_colors,
+            spans : vec![1 ; width as usize * height as usize],
+            frac : 0,
+            offset : (0, 0),
+        }
+    }
+
+    /// Forces emoticons for colors, eg. a brand emoticon for all blue regions: Each cell
+    /// whose chunk color (see `chunk_colors`) lies within the range of one of the
+    /// `overrides` gets its emoticon, the first matching range wins. The emoticons do not
+    /// have to be part of the matched set. Confidences and alternatives are kept.
+    ///
+    /// Returns the number of overridden cells.
+    pub fn apply_overrides(&mut self, overrides : &[(hsl::ColorRange, Rc<emoticons::Emoticon>)]) -> usize {
+        let mut count = 0;
+        for (emo, color) in self.emopixels.iter_mut().zip(self.chunk_colors.iter()) {
+            let forced = color.as_ref().and_then(|c| overrides.iter().find(|o| o.0.contains(c)));
+            if let Some(rule) = forced {
+                *emo = Some(rule.1.clone());
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Empties the cells whose confidence is below `floor` times the highest one, see
+    /// `EmoimageBuilder::blank_below()`.
+    fn blank_below(&mut self, floor : f32) {
+        let best = self.emopixels.iter()
+                                 .zip(self.confidences.iter())
+                                 .filter(|&(e, c)| e.is_some() && c.is_finite())
+                                 .map(|(_, &c)| c)
+                                 .fold(f32::NEG_INFINITY, f32::max);
+        let floor = best - (1.0 - floor) * best.abs();
+        for i in 0..self.emopixels.len() {
+            if self.emopixels[i].is_some() && self.confidences[i] < floor {
+                self.emopixels[i] = None;
+                self.confidences[i] = 0.0;
+                self.alternatives[i].clear();
+                self.chunk_colors[i] = None;
+            }
+        }
+    }
+
+    /// Turns the 2x2 blocks of cells with the given top left indices into merged cells:
+    /// The other three cells get the emoticon of the top left one.
+    fn merge_blocks(&mut self, blocks : &[usize]) {
+        let width = self.width as usize;
+        for &i in blocks {
+            self.spans[i] = 2;
+            for j in [i + 1, i + width, i + width + 1] {
+                self.spans[j] = 0;
+                self.emopixels[j] = self.emopixels[i].clone();
+                self.confidences[j] = self.confidences[i];
+                self.alternatives[j] = self.alternatives[i].clone();
+            }
+        }
+    }
+
+    /// Does the calculation. Images of any color type (eg. grayscale) are accepted,
+    /// they are converted to RGBA internally.
+    ///
+    /// Same as `from_image()`. The image is not modified, `&mut` is only kept for compatibility.
+    ///
+    /// Returns an error if there are no emoticons or if the image is smaller than one chunk.
+    /// See `EmoimageBuilder` for more parameters.
+    pub fn new(img : &mut DynamicImage,
+               frac : u32,
+               emoticons : &Emoticons,
+               method : ComparisationMethod) -> Result<Emoimage, EmotimError> {
+        Emoimage::from_image(img, frac, emoticons, method)
+    }
+
+    /// Does the calculation. Like `new()`, but borrows the image immutably, so that
+    /// it can be shared.
+    pub fn from_image(img : &DynamicImage,
+                      frac : u32,
+                      emoticons : &Emoticons,
+                      method : ComparisationMethod) -> Result<Emoimage, EmotimError> {
+        EmoimageBuilder::new().frac(frac).method(method).build(img, emoticons)
+    }
+
+    /// Splits the image into chunks and calculates their histograms, without matching them.
+    /// Cache the result and pass it to `match_histograms()` to try different emoticon sets
+    /// or methods without analyzing the image again.
+    pub fn analyze(img : &DynamicImage, frac : u32) -> Result<Vec<Option<hsl::HslHistogram>>, EmotimError> {
+        EmoimageBuilder::new().frac(frac).analyze(img)
+    }
+
+    /// Matches the histograms of `analyze()` for an image of `width` × `height` chunks.
+    /// Same result as `from_image()` for `Correlation`, `Maxima`, `SoftMaxima` and
+    /// `Chroma`; other methods need the pixels and are rejected.
+    pub fn match_histograms(histograms : &[Option<hsl::HslHistogram>],
+                            width : u32,
+                            height : u32,
+                            emoticons : &Emoticons,
+                            method : ComparisationMethod) -> Result<Emoimage, EmotimError> {
+        EmoimageBuilder::new().method(method).match_histograms(histograms, width, height, emoticons)
+    }
+
+    /// Fast path for large images: Scales the image down to one pixel per chunk and
+    /// matches the color of each pixel with the mean color of the emoticons.
+    ///
+    /// Skips the histograms entirely, so it is much faster than `new()`, but the
+    /// result is coarser. See `ComparisationMethod::NearestColor`.
+    pub fn new_fast(img : &DynamicImage,
+                    frac : u32,
+                    emoticons : &Emoticons) -> Result<Emoimage, EmotimError> {
+        let builder = EmoimageBuilder::new().frac(frac).method(ComparisationMethod::NearestColor);
+        builder.check(img, emoticons)?;
+        non_empty(builder.calculate_fast(img, emoticons))
+    }
+
+    /// Runs the calculation for each of the `candidate_fracs` and returns the `frac` and
+    /// the result with the highest mean confidence. `None` if no candidate is usable
+    /// (eg. all are larger than the image).
+    ///
+    /// Correlation similarities grow with the number of pixels of a chunk, so for
+    /// `Correlation` and `Adaptive` the confidences are divided by `frac * frac` before
+    /// comparing. The other methods' similarities do not depend on the chunk size.
+    pub fn auto_frac(img : &DynamicImage,
+                     candidate_fracs : &[u32],
+                     emoticons : &Emoticons,
+                     method : ComparisationMethod) -> Option<(u32, Emoimage)> {
+        let mut best : Option<(u32, Emoimage, f32)> = None;
+        for &frac in candidate_fracs {
+            let emoimg = match EmoimageBuilder::new().frac(frac).method(method).build(img, emoticons) {
+                Ok(emoimg) => emoimg,
+                Err(_) => continue,
+            };
+            let mut score = emoimg.mean_confidence();
+            if method == ComparisationMethod::Correlation || method == ComparisationMethod::Adaptive {
+                score /= (frac * frac) as f32;
+            }
+            if best.as_ref().is_none_or(|b| score > b.2) {
+                best = Some((frac, emoimg, score));
+            }
+        }
+        best.map(|(frac, emoimg, _)| (frac, emoimg))
+    }
+
+    /// Mean of the confidences of all non empty cells. 0 if all cells are empty.
+    pub fn mean_confidence(&self) -> f32 {
+        let (sum, count) = self.emopixels.iter()
+                                         .zip(self.confidences.iter())
+                                         .filter(|&(e, _)| e.is_some())
+                                         .fold((0.0, 0), |(sum, count), (_, &c)| (sum + c, count + 1));
+        if count == 0 { 0.0 } else { sum / count as f32 }
+    }
+
+    /// Saves the calculated emoticons as png image. Creates missing parent directories.
+    pub fn save(&self, path : &Path) -> io::Result<()> {
+        self.save_with(path, &RenderOptions::default())
+    }
+
+    /// Like `save()`, but renders with the given options, e.g. with a gutter between cells.
+    pub fn save_with(&self, path : &Path, options : &RenderOptions) -> io::Result<()> {
+        let img = self.try_render(options).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        write_creating_dirs(path, &encode_png(&img)?)
+    }
+
+    /// Like `save()`, but encodes the png row of cells by row of cells (see
+    /// `write_png_banded()`) instead of rendering the whole image first. For mosaics too
+    /// large to hold in memory. Creates missing parent directories.
+    pub fn save_streaming(&self, path : &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        self.write_png_banded(io::BufWriter::new(File::create(path)?))
+    }
+
+    /// Saves the calculated emoticons as lossy webp image with the given `quality` (0 to 100).
+    /// The alpha channel is kept. Creates missing parent directories.
+    ///
+    /// Needs the feature `webp`.
+    #[cfg(feature = "webp")]
+    pub fn save_webp(&self, path : &Path, quality : f32) -> Result<(), EmotimError> {
+        let img = self.try_render(&RenderOptions::default())?.to_rgba();
+        let webp = webp::Encoder::from_rgba(&img, img.width(), img.height()).encode(quality);
+        write_creating_dirs(path, &webp)?;
+        Ok(())
+    }
+
+    /// Saves the calculated emoticons as avif image with the given `quality` (1 to 100)
+    /// for color and alpha channel. Creates missing parent directories.
+    ///
+    /// Needs the feature `avif`. Encoding is slow.
+    #[cfg(feature = "avif")]
+    pub fn save_avif(&self, path : &Path, quality : f32) -> Result<(), EmotimError> {
+        let img = self.try_render(&RenderOptions::default())?.to_rgba();
+        let pixels : Vec<ravif::RGBA8> = img.chunks(4)
+                                            .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                                            .collect();
+        let buffer = ravif::Img::new(&pixels[..], img.width() as usize, img.height() as usize);
+        let avif = ravif::Encoder::new()
+                                  .with_quality(quality)
+                                  .with_alpha_quality(quality)
+                                  .encode_rgba(buffer)
+                                  .map_err(|e| EmotimError::Encoding(e.to_string()))?;
+        write_creating_dirs(path, &avif.avif_file)?;
+        Ok(())
+    }
+
+    /// Compares the chosen emoticons (by filename) cell by cell. Useful to check
+    /// that a change does not alter the output.
+    ///
+    /// If the dimensions differ, cells that exist in only one of the images count as
+    /// different unless they are empty.
+    pub fn diff(&self, other : &Emoimage) -> EmoimageDiff {
+        let filename_at = |img : &Emoimage, w : u32, h : u32| {
+            if w >= img.width || h >= img.height {
+                return None;
+            }
+            img.emopixels[(h * img.width + w) as usize].as_ref().map(|e| e.filename.clone())
+        };
+        let mut positions = Vec::new();
+        for h in 0..self.height.max(other.height) {
+            for w in 0..self.width.max(other.width) {
+                if filename_at(self, w, h) != filename_at(other, w, h) {
+                    positions.push((w, h));
+                }
+            }
+        }
+        EmoimageDiff {
+            count : positions.len(),
+            positions : positions,
+        }
+    }
+
+    /// The same image in another emoticon style (eg. a different vendor set) without
+    /// matching again: Each emoticon, including the alternatives, is replaced by the one of
+    /// `new_set` with the same codepoints, or by the one with the nearest mean color (in
+    /// OKLab, see `Emoticon::mean_oklab`) if `new_set` has none. Similarities are kept. Cells
+    /// become empty if no emoticon of `new_set` qualifies.
+    pub fn remap(&self, new_set : &Emoticons) -> Emoimage {
+        let by_codepoints : HashMap<Vec<char>, &Rc<emoticons::Emoticon>> =
+            new_set.iter().map(|e| (e.codepoints(), e)).collect();
+        let mut replacements : HashMap<String, Option<Rc<emoticons::Emoticon>>> = HashMap::new();
+        let mut replace = |e : &Rc<emoticons::Emoticon>| {
+            replacements.entry(e.filename.clone()).or_insert_with(|| {
+                match by_codepoints.get(&e.codepoints()) {
+                    Some(&same) => Some(same.clone()),
+                    None => new_set.iter()
+                                   .filter_map(|n| match (n.mean_oklab, e.mean_oklab) {
+                                       (Some(ref a), Some(ref b)) => Some((n, a.distance(b))),
+                                       _ => None,
+                                   })
+                                   .fold(None, |best : Option<(&Rc<emoticons::Emoticon>, f32)>, n| match best {
+                                       Some(b) if b.1 <= n.1 => Some(b),
+                                       _ => Some(n),
+                                   })
+                                   .map(|b| b.0.clone()),
+                }
+            }).clone()
+        };
+        let emopixels : Vec<Option<Rc<emoticons::Emoticon>>> =
+            self.emopixels.iter().map(|e| e.as_ref().and_then(&mut replace)).collect();
+        let alternatives = self.alternatives.iter().map(|alternatives| {
+            alternatives.iter().filter_map(|&(ref e, s)| replace(e).map(|e| (e, s))).collect()
+        }).collect();
+        let confidences = self.confidences.iter()
+                                          .zip(emopixels.iter())
+                                          .map(|(&c, e)| if e.is_some() { c } else { 0.0 })
+                                          .collect();
+        Emoimage {
+            width : self.width,
+            height : self.height,
+            emopixels : emopixels,
+            confidences : confidences,
+            alternatives : alternatives,
+            cache_hits : self.cache_hits,
+            chunk_colors : self.chunk_colors.clone(),
+            spans : self.spans.clone(),
+            frac : self.frac,
+            offset : self.offset,
+        }
+    }
+
+    /// Saves one line per cell as CSV: grid position, codepoints of the chosen emoticon
+    /// (hex, joined by `-` like the filenames), similarity and dominant color of the chunk.
+    /// Empty cells have empty codepoints and color. Creates missing parent directories.
+    ///
+    /// ```text
+    /// x,y,codepoints,similarity,chunk_color
+    /// 0,0,0023-20e3,0.85,#a0c010
+    /// ```
+    pub fn save_csv(&self, path : &Path) -> io::Result<()> {
+        let mut csv = String::from("x,y,codepoints,similarity,chunk_color\n");
+        for (i, (emo, color)) in self.emopixels.iter().zip(self.chunk_colors.iter()).enumerate() {
+            let codepoints = match *emo {
+                Some(ref e) => e.codepoints_hex(),
+                None => String::new(),
+            };
+            let color = match *color {
+                Some(ref c) => {
+                    let (r, g, b, _) = c.extend_dynamic().to_rgba().channels4();
+                    format!("#{:02x}{:02x}{:02x}", r, g, b)
+                },
+                None => String::new(),
+            };
+            csv.push_str(&format!("{},{},{},{},{}\n", i as u32 % self.width, i as u32 / self.width,
+                                  codepoints, self.confidences[i], color));
+        }
+        write_creating_dirs(path, csv.as_bytes())
+    }
+
+    /// Returns all emoticons of `full_set` that have not been chosen for any chunk
+    /// of this image. Emoticons are identified by their filename.
+    pub fn unused_emoticons(&self, full_set : &Emoticons) -> Vec<Rc<emoticons::Emoticon>> {
+        let used : HashSet<&str> = self.emopixels.iter()
+                                                 .flat_map(|e| e.as_ref())
+                                                 .map(|e| e.filename.as_str())
+                                                 .collect();
+        full_set.iter()
+                .filter(|e| !used.contains(e.filename.as_str()))
+                .cloned()
+                .collect()
+    }
+
+    /// The emoticons used in this image with the number of cells they fill, most
+    /// frequent first. Emoticons are identified by their filename.
+    pub fn palette(&self) -> Vec<(Rc<emoticons::Emoticon>, usize)> {
+        let mut palette : Vec<(Rc<emoticons::Emoticon>, usize)> = Vec::new();
+        let mut positions : HashMap<&str, usize> = HashMap::new();
+        for e in self.emopixels.iter().flat_map(|e| e.as_ref()) {
+            match positions.get(e.filename.as_str()) {
+                Some(&i) => palette[i].1 += 1,
+                None => {
+                    positions.insert(&e.filename, palette.len());
+                    palette.push((e.clone(), 1));
+                }
+            }
+        }
+        // stable, so equally frequent emoticons stay in order of appearance
+        palette.sort_by_key(|p| ::std::cmp::Reverse(p.1));
+        palette
+    }
+
+    /// The emoticons as text, indexed via `[h][w]`. Each entry holds all codepoints of
+    /// the chosen emoticon (eg. a flag or a keycap sequence), empty cells are empty strings.
+    pub fn to_char_grid(&self) -> Vec<Vec<String>> {
+        self.rows(&self.emopixels)
+                      .map(|line| line.iter()
+                                      .map(|e| e.as_ref().map(|e| e.to_string()).unwrap_or_default())
+                                      .collect())
+                      .collect()
+    }
+
+    /// Describes the emoticon grid as JSON.
+    ///
+    /// `cells` and `confidences` are indexed via `[h][w]`. Each cell is the list of
+    /// unicode codepoints of the chosen emoticon, eg. `[35, 8419]` for `0023-20e3.png`,
+    /// or `null` for empty cells. Confidences that are not finite are `null`, too.
+    ///
+    /// ```text
+    /// {"width":2,"height":1,"cells":[[[127744],[127744]]],"confidences":[[0.9,0.8]]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut cells = Vec::with_capacity(self.height as usize);
+        let mut confidences = Vec::with_capacity(self.height as usize);
+        for (line, conf) in self.rows(&self.emopixels).zip(self.rows(&self.confidences)) {
+            let line : Vec<String> = line.iter().map(|e| match *e {
+                Some(ref e) => {
+                    let codepoints : Vec<String> = e.codepoints().iter().map(|&c| (c as u32).to_string()).collect();
+                    format!("[{}]", codepoints.join(","))
+                },
+                None => "null".to_string(),
+            }).collect();
+            let conf : Vec<String> = conf.iter()
+                                         .map(|c| if c.is_finite() { c.to_string() } else { "null".to_string() })
+                                         .collect();
+            cells.push(format!("[{}]", line.join(",")));
+            confidences.push(format!("[{}]", conf.join(",")));
+        }
+        format!("{{\"width\":{},\"height\":{},\"cells\":[{}],\"confidences\":[{}]}}",
+                self.width, self.height, cells.join(","), confidences.join(","))
+    }
+
+    /// `cells` (eg. `emopixels`) split into rows. Guards against a `width` of 0.
+    fn rows<'a, T>(&self, cells : &'a [T]) -> ::std::slice::Chunks<'a, T> {
+        cells.chunks(self.width.max(1) as usize)
+    }
+}
+
+impl fmt::Display for Emoimage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.rows(&self.emopixels) {
+            for s in line {
+                match *s {
+                    Some(ref s) => write!(f, "{}", s)?,
+                    None => write!(f, "  ")?,
+                }
+            }
+            try!(write!(f, "\n"));
+        }
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+    use image::{DynamicImage, RgbaImage, Rgba};
+    use image;
+    use std::path::Path;
+    use std::process;
+
+    /// A fresh directory for one test below the temp dir, removed again on drop. The
+    /// process id keeps concurrent test runs apart.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(test : &str) -> TempDir {
+            let path = ::std::env::temp_dir().join(format!("emotim_test_{}_{}", test, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        pub fn join<P : AsRef<Path>>(&self, path : P) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn solid_image(width : u32, height : u32, rgb : [u8 ; 3]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([rgb[0], rgb[1], rgb[2], 255])))
+    }
+
+    /// Red, yellow and blue must match the hue wheel tiles of the same color
+    fn assert_solid_colors_match_tile_of_same_hue(method : ComparisationMethod) {
+        let emos = emoticons::hue_wheel(12, 8);
+        for &(rgb, tile) in &[([255, 0, 0], 0), ([255, 255, 0], 2), ([0, 0, 255], 8)] {
+            let img = solid_image(16, 16, rgb);
+            let emoimg = EmoimageBuilder::new().frac(8).method(method).build(&img, &emos).unwrap();
+            for e in &emoimg.emopixels {
+                assert_eq!(e.as_ref().unwrap().filename, emos[tile].filename, "{:?} for rgb {:?}", method, rgb);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_chunks_stay_empty() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(16, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 0..8 {
+                img.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+        let emoimg = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        assert!(emoimg.emopixels[0].is_none());
+        assert!(emoimg.emopixels[1].is_some());
+    }
+
+    #[test]
+    fn diff_reports_differing_cells() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut red = solid_image(16, 16, [255, 0, 0]);
+        let mut mixed = solid_image(16, 16, [255, 0, 0]);
+        for y in 8..16 {
+            for x in 0..8 {
+                mixed.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let red = Emoimage::new(&mut red, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let mixed = Emoimage::new(&mut mixed, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        assert!(red.diff(&red).is_identical());
+        let diff = red.diff(&mixed);
+        assert_eq!(diff.count, 1);
+        assert_eq!(diff.positions, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn gutter_separates_cells() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(16, 8, [255, 0, 0]);
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let (cell_width, cell_height) = emoimg.cell_size().unwrap();
+        let plain = emoimg.to_image();
+        let options = RenderOptions { gutter : 3, gutter_color : Some(Rgba([1, 2, 3, 255])), ..Default::default() };
+        let framed = emoimg.render(&options);
+        assert_eq!((framed.width(), framed.height()), (2 * cell_width + 3, cell_height));
+        assert_eq!(framed.get_pixel(cell_width + 1, 0), Rgba([1, 2, 3, 255]));
+        assert_eq!(framed.get_pixel(cell_width + 3, 0), plain.get_pixel(cell_width, 0));
+    }
+
+    #[test]
+    fn render_to_input_dimensions() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(45, 30, [255, 0, 0]);
+        let emoimg = Emoimage::from_image(&img, 15, &emos, ComparisationMethod::Correlation).unwrap();
+        assert_eq!(emoimg.to_image().dimensions(), (24, 16));
+        let options = RenderOptions { target_dimensions : Some(img.dimensions()), ..Default::default() };
+        assert_eq!(emoimg.render(&options).dimensions(), (45, 30));
+    }
+
+    #[test]
+    fn render_with_shadow() {
+        // A red emoticon with a transparent margin of 4 pixels
+        let dot = RgbaImage::from_fn(16, 16, |x, y| {
+            if (4..12).contains(&x) && (4..12).contains(&y) { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) }
+        });
+        let emos = vec![Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(dot), "e100.png"))];
+        let emoimg = Emoimage::from_image(&solid_image(16, 16, [255, 0, 0]), 16, &emos, ComparisationMethod::Correlation).unwrap();
+        let shadow = Shadow { offset : (2, 2), blur : 0.0, opacity : 0.5 };
+        let img = emoimg.render(&RenderOptions { shadow : Some(shadow), ..Default::default() });
+        // The emoticon stays on top, the shadow shows below and right of it
+        assert_eq!(img.get_pixel(8, 8), Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(13, 13), Rgba([0, 0, 0, 128]));
+        assert_eq!(img.get_pixel(2, 2), Rgba([0, 0, 0, 0]));
+        // Blurred shadows fade out
+        let blurred = emoimg.render(&RenderOptions { shadow : Some(Shadow { blur : 1.5, ..shadow }), ..Default::default() });
+        assert_eq!(blurred.get_pixel(8, 8), Rgba([255, 0, 0, 255]));
+        let edge = blurred.get_pixel(14, 9).data[3];
+        assert!(edge > 0 && edge < 128, "{}", edge);
+    }
+
+    #[test]
+    fn render_cropped_to_content() {
+        // Red in the top left quarter only
+        let corner = RgbaImage::from_fn(16, 16, |x, y| {
+            if x < 8 && y < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) }
+        });
+        let emos = vec![Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(corner), "e100.png"))];
+        let emoimg = Emoimage::from_image(&solid_image(16, 16, [255, 0, 0]), 16, &emos, ComparisationMethod::Correlation).unwrap();
+        let plain = emoimg.to_image();
+        assert_eq!(plain.get_pixel(10, 10).data[3], 0);
+        let cropped = emoimg.render(&RenderOptions { crop_to_content : true, ..Default::default() });
+        assert_eq!(cropped.dimensions(), (16, 16));
+        assert_eq!(cropped.get_pixel(10, 10), Rgba([255, 0, 0, 255]));
+        assert_eq!(cropped.get_pixel(2, 2).data[3], 0);
+    }
+
+    #[test]
+    fn mask_cuts_out_the_subject() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let emoimg = Emoimage::from_image(&solid_image(16, 8, [255, 0, 0]), 8, &emos, ComparisationMethod::Correlation).unwrap();
+        // Smaller than the output: left half white, right half black
+        let mask = DynamicImage::ImageLuma8(image::GrayImage::from_fn(4, 2, |x, _| image::Luma([if x < 2 { 255 } else { 0 }])));
+        let cutout = emoimg.apply_mask(&mask).unwrap();
+        assert_eq!(cutout.dimensions(), (16, 8));
+        assert_eq!(cutout.get_pixel(2, 4), Rgba([255, 0, 0, 255]));
+        assert_eq!(cutout.get_pixel(13, 4).data[3], 0);
+        // Feathered edge
+        let edge = cutout.get_pixel(6, 4).data[3];
+        assert!(edge > 0 && edge < 255, "{}", edge);
+        for &(width, height) in &[(0, 2), (4, 0)] {
+            match emoimg.apply_mask(&DynamicImage::new_luma8(width, height)) {
+                Err(EmotimError::EmptyMask) => {},
+                _ => panic!("empty mask {}x{}", width, height),
+            }
+        }
+    }
+
+    #[test]
+    fn render_with_emoji_opacity() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let emoimg = Emoimage::from_image(&solid_image(16, 8, [255, 0, 0]), 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let faded = emoimg.render(&RenderOptions { emoji_opacity : Some(0.5), ..Default::default() });
+        assert_eq!(faded.get_pixel(3, 3), Rgba([255, 0, 0, 128]));
+        let opaque = emoimg.render(&RenderOptions { emoji_opacity : Some(1.0), ..Default::default() });
+        assert_eq!(opaque.raw_pixels(), emoimg.to_image().raw_pixels());
+        let options = RenderOptions { emoji_opacity : Some(0.5), blend_top2 : true, ..Default::default() };
+        assert_eq!(emoimg.render(&options).get_pixel(3, 3).data[3], 128);
+    }
+
+    #[test]
+    fn render_blend_top2_with_transparent_second() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut emoimg = Emoimage::from_image(&solid_image(8, 8, [255, 0, 0]), 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let transparent = Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(RgbaImage::new(8, 8)), "e100.png"));
+        // Equally good, so both get half of the weight
+        emoimg.alternatives[0] = vec![(transparent, emoimg.confidences[0])];
+        let blended = emoimg.render(&RenderOptions { blend_top2 : true, ..Default::default() });
+        let red = emos[0].img.get_pixel(3, 3);
+        let pixel = blended.get_pixel(3, 3);
+        assert_eq!(&pixel.data[..3], &red.data[..3]);
+        assert_eq!(pixel.data[3], 128);
+    }
+
+    #[test]
+    fn render_panorama_too_large() {
+        let emos = emoticons::hue_wheel(12, 16);
+        let mut emoimg = Emoimage::from_image(&solid_image(32, 16, [255, 0, 0]), 16, &emos, ComparisationMethod::Correlation).unwrap();
+        assert_eq!(emoimg.output_dimensions(&Default::default()).unwrap(), (32, 16));
+        // Pretend to be a very wide panorama, the cells are never touched
+        emoimg.width = 1 << 26;
+        match emoimg.try_render(&Default::default()) {
+            Err(EmotimError::OutputTooLarge { width, height }) => assert_eq!((width, height), (1 << 30, 16)),
+            _ => panic!("expected OutputTooLarge"),
+        }
+        let dir = TempDir::new("render_panorama_too_large");
+        let path = dir.join("panorama.png");
+        assert_eq!(emoimg.save(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // Resizing does not help if the canvas itself is too large
+        let options = RenderOptions { target_dimensions : Some((100, 100)), ..Default::default() };
+        assert!(emoimg.output_dimensions(&options).is_err());
+    }
+
+    #[test]
+    fn render_to_min_output_dimension() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(45, 30, [255, 0, 0]);
+        let emoimg = Emoimage::from_image(&img, 15, &emos, ComparisationMethod::Correlation).unwrap();
+        let plain = emoimg.to_image();
+        let options = RenderOptions { min_output_dimension : Some(50), ..Default::default() };
+        let enlarged = emoimg.render(&options);
+        assert_eq!(enlarged.dimensions(), (72, 48));
+        // Nearest neighbour: each pixel becomes a 3x3 block
+        assert_eq!(enlarged.get_pixel(5, 8), plain.get_pixel(1, 2));
+        let options = RenderOptions { min_output_dimension : Some(24), ..Default::default() };
+        assert_eq!(emoimg.render(&options).dimensions(), (24, 16));
+    }
+
+    #[test]
+    fn render_cover_fills_the_canvas() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Red left, blue right: 2x1 cells of 8x8
+        let mut img = solid_image(16, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 8..16 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let mosaic = emoimg.to_image();
+        // Lanczos3 rings a little
+        let close = |a : Rgba<u8>, b : Rgba<u8>| a.data.iter().zip(b.data.iter()).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 2);
+        // Wider than the mosaic: the height is cropped
+        let wide = emoimg.render_cover(64, 16);
+        assert_eq!(wide.dimensions(), (64, 16));
+        assert!(close(wide.get_pixel(8, 8), mosaic.get_pixel(2, 4)));
+        assert!(close(wide.get_pixel(56, 8), mosaic.get_pixel(14, 4)));
+        // Taller than the mosaic: the width is cropped, only the border of both cells is left
+        let tall = emoimg.render_cover(10, 40);
+        assert_eq!(tall.dimensions(), (10, 40));
+        let (left, right) = (tall.get_pixel(0, 20), tall.get_pixel(9, 20));
+        assert!(left.data[0] > left.data[2] && right.data[2] > right.data[0]);
+    }
+
+    #[test]
+    fn thumbnail_has_one_pixel_per_cell() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(24, 16, [255, 0, 0]);
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let thumbnail = emoimg.thumbnail();
+        assert_eq!(thumbnail.dimensions(), (3, 2));
+        let color = emos[0].dominant_color().unwrap().extend_dynamic().to_rgba();
+        assert_eq!(thumbnail.get_pixel(2, 1), color);
+    }
+
+    #[test]
+    fn matching_precomputed_histograms_equals_build() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(32, 24, [255, 0, 0]);
+        for y in 0..24 {
+            for x in 0..y {
+                img.put_pixel(x, y, Rgba([0, (x * 8) as u8, 255, 255]));
+            }
+        }
+        let histograms = Emoimage::analyze(&img, 8).unwrap();
+        assert_eq!(histograms.len(), 12);
+        for &method in &[ComparisationMethod::Correlation, ComparisationMethod::Maxima, ComparisationMethod::Chroma, ComparisationMethod::SoftMaxima] {
+            let built = Emoimage::from_image(&img, 8, &emos, method).unwrap();
+            let matched = Emoimage::match_histograms(&histograms, 4, 3, &emos, method).unwrap();
+            assert!(built.diff(&matched).is_identical());
+            assert_eq!(built.confidences, matched.confidences);
+        }
+        assert!(Emoimage::match_histograms(&histograms, 4, 4, &emos, ComparisationMethod::Maxima).is_err());
+        assert!(Emoimage::match_histograms(&histograms, 4, 3, &emos, ComparisationMethod::Spatial).is_err());
+    }
+
+    #[test]
+    fn equal_maxima_do_not_share_correlation_results() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let red = solid_image(8, 8, [255, 0, 0]);
+        let blue = solid_image(8, 8, [0, 0, 255]);
+        let red_hist = Emoimage::analyze(&red, 8).unwrap().remove(0).unwrap();
+        let mut blue_hist = Emoimage::analyze(&blue, 8).unwrap().remove(0).unwrap();
+        // Same maxima, but the distribution of blue
+        blue_hist.maxima = red_hist.maxima.clone();
+        let histograms = vec![Some(red_hist), Some(blue_hist)];
+        let matched = Emoimage::match_histograms(&histograms, 2, 1, &emos, ComparisationMethod::Correlation).unwrap();
+        assert_eq!(matched.cache_hits, 0);
+        assert_eq!(matched.emopixels[0].as_ref().unwrap().filename, "e000.png");
+        assert_eq!(matched.emopixels[1].as_ref().unwrap().filename, "e008.png");
+    }
+
+    #[test]
+    fn min_coverage_excludes_sparse_emoticons() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        // Red tile with a transparent left half
+        let mut sparse = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        for y in 0..8 {
+            for x in 0..4 {
+                sparse.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+        let sparse = emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(sparse), "e100.png");
+        assert_eq!(sparse.coverage, 0.5);
+        assert_eq!(emos[0].coverage, 1.0);
+        emos.insert(0, Rc::new(sparse));
+        let img = solid_image(8, 8, [255, 0, 0]);
+        let emoimg = EmoimageBuilder::new().frac(8).min_coverage(0.9).build(&img, &emos).unwrap();
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "e000.png");
+        assert!(EmoimageBuilder::new().frac(8).min_coverage(1.1).build(&img, &emos).is_err());
+    }
+
+    #[test]
+    fn target_colors_follow_the_input() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(16, 8, [0, 0, 255]);
+        for y in 0..8 {
+            for x in 8..16 {
+                img.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let colors = emoimg.target_colors();
+        assert_eq!(colors.len(), 2);
+        assert!(colors[0].data[2] > colors[0].data[0] && colors[0].data[3] == 255);
+        assert_eq!(colors[1], Rgba([0, 0, 0, 0]));
+        let target = emoimg.to_target_image(4);
+        assert_eq!(target.dimensions(), (8, 4));
+        assert_eq!(target.get_pixel(3, 3), colors[0]);
+    }
+
+    /// Encodes `img` as jpg with an EXIF segment that only contains the `orientation`.
+    fn jpg_with_orientation(img : &DynamicImage, orientation : u8) -> Vec<u8> {
+        let rgb = img.to_rgb();
+        let mut jpg = Vec::new();
+        image::jpeg::JPEGEncoder::new_with_quality(&mut jpg, 100)
+            .encode(&rgb, rgb.width(), rgb.height(), image::ColorType::RGB(8)).unwrap();
+        // Big endian TIFF header, one IFD with one SHORT entry (tag 0x0112), no next IFD
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01".to_vec();
+        tiff.extend_from_slice(&[0, orientation, 0, 0, 0, 0, 0, 0]);
+        let mut app1 = b"Exif\x00\x00".to_vec();
+        app1.extend_from_slice(&tiff);
+        let length = app1.len() + 2;
+        let mut segment = vec![0xff, 0xe1, (length >> 8) as u8, length as u8];
+        segment.extend_from_slice(&app1);
+        // After the SOI marker
+        jpg.splice(2..2, segment);
+        jpg
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() {
+        // Landscape image with a red left half and a blue right half
+        let mut img = solid_image(32, 16, [255, 0, 0]);
+        for y in 0..16 {
+            for x in 16..32 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let upright = decode_input_image(&jpg_with_orientation(&img, 1)).unwrap();
+        assert_eq!(upright.dimensions(), (32, 16));
+        // Rotated by 90° clockwise: red ends up on top
+        let rotated = decode_input_image(&jpg_with_orientation(&img, 6)).unwrap();
+        assert_eq!(rotated.dimensions(), (16, 32));
+        let top = rotated.get_pixel(8, 4);
+        let bottom = rotated.get_pixel(8, 28);
+        assert!(top.data[0] > 200 && top.data[2] < 50, "{:?}", top.data);
+        assert!(bottom.data[2] > 200 && bottom.data[0] < 50, "{:?}", bottom.data);
+    }
+
+    #[test]
+    fn chunk_estimate_matches_grid() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(50, 30, [255, 0, 0]);
+        assert_eq!(estimate_chunks(&img, 0), 0);
+        assert_eq!(estimate_chunks(&img, 100), 0);
+        assert_eq!(estimate_chunks(&img, 8), 6 * 3);
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        assert_eq!(emoimg.emopixels.len(), estimate_chunks(&img, 8));
+    }
+
+    #[test]
+    fn no_adjacent_repeat_alternates_in_flat_regions() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(32, 16, [255, 0, 0]);
+        let emoimg = EmoimageBuilder::new().frac(8).no_adjacent_repeat(true).build(&img, &emos).unwrap();
+        let chosen : Vec<&str> = emoimg.emopixels.iter().map(|e| &e.as_ref().unwrap().filename[..]).collect();
+        assert_eq!(chosen[0], "e000.png");
+        for i in 0..8 {
+            if i % 4 > 0 {
+                assert!(chosen[i] != chosen[i - 1]);
+            }
+            if i >= 4 {
+                assert!(chosen[i] != chosen[i - 4]);
+            }
+            assert_eq!(emoimg.alternatives[i].len(), 1);
+        }
+    }
+
+    #[test]
+    fn palette_and_legend() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(32, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 24..32 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let palette = emoimg.palette();
+        assert_eq!(palette.len(), 2);
+        assert_eq!((palette[0].0.filename.as_str(), palette[0].1), ("e000.png", 3));
+        assert_eq!((palette[1].0.filename.as_str(), palette[1].1), ("e008.png", 1));
+
+        let legend = emoimg.render_with_legend().unwrap();
+        assert_eq!(legend.width(), 32);
+        assert!(legend.height() > 8);
+        assert_eq!(legend.get_pixel(0, 0), emoimg.to_image().get_pixel(0, 0));
+        // Some text is drawn below the mosaic
+        assert!(legend.pixels().any(|(_, y, p)| y >= 8 && p == Rgba([0, 0, 0, 255])));
+
+        // A fully transparent input can not be converted at all
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(16, 8));
+        match Emoimage::from_image(&transparent, 8, &emos, ComparisationMethod::Correlation) {
+            Err(EmotimError::NoEmoticons) => {},
+            _ => panic!("expected NoEmoticons"),
+        }
+        // Cells emptied afterwards
+        let mut empty = emoimg;
+        for e in &mut empty.emopixels {
+            *e = None;
+        }
+        match empty.render_with_legend() {
+            Err(EmotimError::NoEmoticons) => {},
+            _ => panic!("expected NoEmoticons"),
+        }
+    }
+
+    #[test]
+    fn flat_chunks_are_merged() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Flat red on the left, blue and green stripes on the right, 4x2 chunks
+        let mut img = solid_image(32, 16, [255, 0, 0]);
+        for y in 0..16 {
+            for x in 16..32 {
+                img.put_pixel(x, y, if x % 2 == 0 { Rgba([0, 0, 255, 255]) } else { Rgba([0, 255, 0, 255]) });
+            }
+        }
+        let emoimg = EmoimageBuilder::new().frac(8).merge_flat_chunks(Some(0.002)).build(&img, &emos).unwrap();
+        assert_eq!(emoimg.spans, vec![2, 0, 1, 1, 0, 0, 1, 1]);
+        for &i in &[1, 4, 5] {
+            assert!(Rc::ptr_eq(emoimg.emopixels[i].as_ref().unwrap(), emoimg.emopixels[0].as_ref().unwrap()));
+        }
+        // The merged emoticon is drawn once in double size
+        let plain = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        assert_eq!(plain.spans, vec![1 ; 8]);
+        assert_eq!(emoimg.to_image().dimensions(), plain.to_image().dimensions());
+        assert_eq!(emoimg.to_image().get_pixel(12, 12), plain.to_image().get_pixel(4, 4));
+    }
+
+    #[test]
+    fn banded_png_equals_rendered_image() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(32, 24, [255, 0, 0]);
+        for y in 0..24 {
+            for x in 16..32 {
+                img.put_pixel(x, y, if x % 2 == 0 { Rgba([0, 0, 255, 255]) } else { Rgba([0, 255, 0, 255]) });
+            }
+        }
+        let emoimg = EmoimageBuilder::new().frac(8).merge_flat_chunks(Some(0.002)).build(&img, &emos).unwrap();
+        assert!(emoimg.spans.contains(&2));
+        let mut png = Vec::new();
+        emoimg.write_png_banded(&mut png).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        let rendered = emoimg.to_image();
+        assert_eq!(decoded.dimensions(), rendered.dimensions());
+        assert!(decoded.to_rgba().into_raw() == rendered.to_rgba().into_raw());
+
+        let dir = TempDir::new("banded_png_equals_rendered_image");
+        let path = dir.join("banded.png");
+        emoimg.save_streaming(&path).unwrap();
+        assert_eq!(image::open(&path).unwrap().dimensions(), rendered.dimensions());
+    }
+
+    #[test]
+    fn blank_below_leaves_unclear_cells_empty() {
+        let emos = vec![emoticons::hue_wheel(12, 8)[0].clone()];
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 8, |x, _| {
+            if x < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+        let plain = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        assert!(plain.emopixels.iter().all(|e| e.is_some()));
+        let sketchy = EmoimageBuilder::new().frac(8).blank_below(Some(0.5)).build(&img, &emos).unwrap();
+        assert!(sketchy.emopixels[0].is_some());
+        assert!(sketchy.emopixels[1].is_none() && sketchy.chunk_colors[1].is_none());
+        assert_eq!(sketchy.confidences[1], 0.0);
+        assert_eq!(sketchy.confidences[0], plain.confidences[0]);
+        // The floor is relative to the best cell, whatever the scale of the method
+        assert!(plain.confidences[0] > 100.0 * plain.confidences[1].max(1.0));
+        for &method in &[ComparisationMethod::Correlation, ComparisationMethod::NearestColor] {
+            let kept = EmoimageBuilder::new().frac(8).method(method).blank_below(Some(0.99)).build(&img, &emos).unwrap();
+            assert!(kept.emopixels[0].is_some() && kept.emopixels[1].is_none(), "{:?}", method);
+        }
+        match EmoimageBuilder::new().frac(8).blank_below(Some(1.5)).build(&img, &emos) {
+            Err(EmotimError::NoEmoticons) => {},
+            _ => panic!("all cells blank"),
+        }
+    }
+
+    #[test]
+    fn uniform_background_is_blank() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Slightly noisy white on the left, red on the right, 4x2 chunks
+        let mut img = solid_image(32, 16, [255, 0, 0]);
+        for y in 0..16 {
+            for x in 0..16 {
+                let v = if (x + y) % 3 == 0 { 240 } else { 252 };
+                img.put_pixel(x, y, Rgba([v, v, v - 4, 255]));
+            }
+        }
+        let blank = EmoimageBuilder::new().frac(8).blank_uniform(true).build(&img, &emos).unwrap();
+        let filled : Vec<bool> = blank.emopixels.iter().map(|e| e.is_some()).collect();
+        assert_eq!(filled, vec![false, false, true, true, false, false, true, true]);
+        let plain = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        assert!(plain.emopixels.iter().all(|e| e.is_some()));
+        assert!(Rc::ptr_eq(blank.emopixels[2].as_ref().unwrap(), plain.emopixels[2].as_ref().unwrap()));
+    }
+
+    #[test]
+    fn edge_weight_matches_busy_chunks_to_busy_emoticons() {
+        let stripes = |x : u32, _ : u32| if x.is_multiple_of(2) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) };
+        let flat = emoticons::Emoticon::from_image(solid_image(8, 8, [128, 128, 128]), "e100.png");
+        let busy = emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, stripes)), "e101.png");
+        assert_eq!(flat.edge_density, 0.0);
+        assert!(busy.edge_density > 0.8);
+        let emos = vec![Rc::new(flat), Rc::new(busy)];
+        // Stripes on the left, flat grey on the right
+        let mut img = solid_image(16, 8, [128, 128, 128]);
+        for y in 0..8 {
+            for x in 0..8 {
+                img.put_pixel(x, y, stripes(x, y));
+            }
+        }
+        let emoimg = EmoimageBuilder::new().frac(8).edge_weight(1.0).build(&img, &emos).unwrap();
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "e101.png");
+        assert_eq!(emoimg.emopixels[1].as_ref().unwrap().filename, "e100.png");
+        assert!(EmoimageBuilder::new().frac(8).edge_weight(1.5).build(&img, &emos).is_err());
+    }
+
+    #[test]
+    fn edge_weight_below_one_changes_the_choice() {
+        let stripes = |x : u32, _ : u32| if x.is_multiple_of(2) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) };
+        // The same colors as the stripes, but with a single edge
+        let halves = |x : u32, _ : u32| if x < 4 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) };
+        // Busy like the stripes, but with a slightly darker white
+        let grey_stripes = |x : u32, _ : u32| if x.is_multiple_of(2) { Rgba([0, 0, 0, 255]) } else { Rgba([230, 230, 230, 255]) };
+        let emos : Emoticons = vec![
+            Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, halves)), "e100.png")),
+            Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, grey_stripes)), "e101.png")),
+        ];
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, stripes));
+        let chosen = |weight : f32| {
+            let emoimg = EmoimageBuilder::new().frac(8).edge_weight(weight).build(&img, &emos).unwrap();
+            emoimg.emopixels[0].as_ref().unwrap().filename.clone()
+        };
+        assert_eq!(chosen(0.0), "e100.png");
+        assert_eq!(chosen(0.5), "e101.png");
+    }
+
+    #[test]
+    fn maxima_only_histograms_match_like_full_ones() {
+        let full = emoticons::hue_wheel(12, 8);
+        let mut maxima_only = emoticons::hue_wheel(12, 8);
+        for e in &mut maxima_only {
+            Rc::get_mut(e).unwrap().discard(hsl::HistogramMode::Maxima);
+        }
+        assert!(maxima_only[0].hsl.pixels.is_empty());
+        assert_eq!(maxima_only[0].maxima_histogram(), full[0].maxima_histogram());
+        assert_eq!((maxima_only[0].maxima_histogram().width, maxima_only[0].maxima_histogram().height), (8, 8));
+        let mut img = solid_image(32, 32, [255, 0, 0]);
+        for y in 0..32 {
+            for x in 0..y {
+                img.put_pixel(x, y, Rgba([0, (x * 8) as u8, 255, 255]));
+            }
+        }
+        let builder = EmoimageBuilder::new().frac(8).method(ComparisationMethod::Maxima);
+        let a = builder.build(&img, &full).unwrap();
+        let b = builder.build(&img, &maxima_only).unwrap();
+        for (a, b) in a.emopixels.iter().zip(b.emopixels.iter()) {
+            assert_eq!(a.as_ref().unwrap().filename, b.as_ref().unwrap().filename);
+        }
+        // The chunks do not keep the smoothed distribution either
+        let rgba = img.to_rgba();
+        let (_, hist) = builder.analyze_region(&rgba, 0, 0, 8, false).unwrap();
+        assert!(hist.smoothed.is_none() && hist.distribution.is_some());
+        let (_, hist) = EmoimageBuilder::new().frac(8).analyze_region(&rgba, 0, 0, 8, false).unwrap();
+        assert!(hist.smoothed.is_some());
+    }
+
+    #[test]
+    fn discarded_histograms_fail_instead_of_panicking() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        Rc::get_mut(&mut emos[3]).unwrap().hist.discard(hsl::HistogramMode::Maxima);
+        let img = solid_image(16, 16, [255, 0, 0]);
+        for &method in &[ComparisationMethod::Correlation, ComparisationMethod::Adaptive,
+                         ComparisationMethod::Chroma, ComparisationMethod::ColorCoverage { gamma : 1.0 }] {
+            match EmoimageBuilder::new().frac(8).method(method).build(&img, &emos) {
+                Err(EmotimError::DiscardedHistogram { ref filename, needed }) => {
+                    assert_eq!(filename, "e003.png");
+                    assert_eq!(needed, hsl::HistogramMode::Correlation);
+                },
+                _ => panic!("{:?} did not fail", method),
+            }
+        }
+        assert!(EmoimageBuilder::new().frac(8).method(ComparisationMethod::Maxima).build(&img, &emos).is_ok());
+        // Bins are calculated from the HSL image, unless it has been dropped as well
+        let bins = EmoimageBuilder::new().frac(8).bins(hsl::Bins { hue : 8, chroma : 4, lightness : 4 });
+        assert!(bins.build(&img, &emos).is_ok());
+        let mut dropped = emoticons::hue_wheel(12, 8);
+        Rc::get_mut(&mut dropped[3]).unwrap().discard(hsl::HistogramMode::Maxima);
+        assert!(bins.build(&img, &dropped).is_err());
+        // With a sparse histogram, only the mood references need the dense one
+        let sparse = hsl::SparseHistogram::from_histogram(&emoticons::hue_wheel(12, 8)[3].hist);
+        Rc::get_mut(&mut emos[3]).unwrap().sparse = Some(sparse);
+        let builder = EmoimageBuilder::new().frac(8);
+        assert!(builder.clone().build(&img, &emos).is_ok());
+        assert!(builder.mood(&[emos[3].clone()], 1.0).build(&img, &emos).is_err());
+    }
+
+    #[test]
+    fn mood_prefers_emoticons_like_the_references() {
+        let halves = |left : [u8 ; 3], right : [u8 ; 3], name : &str| {
+            let img = RgbaImage::from_fn(8, 8, |x, _| {
+                let rgb = if x < 4 { left } else { right };
+                Rgba([rgb[0], rgb[1], rgb[2], 255])
+            });
+            Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(img), name))
+        };
+        let red = [255, 0, 0];
+        let yellow = [255, 255, 0];
+        let blue = [0, 0, 255];
+        let emos = vec![halves(red, red, "e100.png"), halves(red, blue, "e101.png"),
+                        halves(red, yellow, "e102.png"), halves(yellow, yellow, "e103.png")];
+        let img = solid_image(8, 8, red);
+        let chosen = |builder : EmoimageBuilder| builder.frac(8).build(&img, &emos).unwrap().emopixels[0].as_ref().unwrap().filename.clone();
+        assert_eq!(chosen(EmoimageBuilder::new()), "e100.png");
+        assert_eq!(chosen(EmoimageBuilder::new().mood(&[emos[3].clone()], 0.5)), "e100.png");
+        // Half red and half like the reference
+        assert_eq!(chosen(EmoimageBuilder::new().mood(&[emos[3].clone()], 4.0)), "e102.png");
+        // References that are not part of the set are ignored
+        let outsider = halves(yellow, yellow, "e200.png");
+        assert_eq!(chosen(EmoimageBuilder::new().mood(&[outsider], 4.0)), "e100.png");
+    }
+
+    #[test]
+    fn palette_slots() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let colors = vec![("brand blue".to_string(), Rgba([0, 0, 255, 255])),
+                          ("invisible".to_string(), Rgba([255, 0, 0, 0])),
+                          ("signal red".to_string(), Rgba([255, 0, 0, 255]))];
+        let slots = match_palette(&colors, &emos, ComparisationMethod::Correlation);
+        assert_eq!(slots.len(), 2);
+        assert_eq!((slots[0].0.as_str(), slots[0].1.filename.as_str()), ("brand blue", "e008.png"));
+        assert_eq!((slots[1].0.as_str(), slots[1].1.filename.as_str()), ("signal red", "e000.png"));
+        assert!(slots[1].2 > 0.0);
+    }
+
+    #[test]
+    fn explain_chunk_lists_all_emoticons() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(16, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 8..16 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let scores = explain_chunk(&img, 8, 1, 0, &emos, ComparisationMethod::Correlation);
+        assert_eq!(scores.len(), 12);
+        assert!(scores.windows(2).all(|w| w[0].1 >= w[1].1));
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        assert!(Rc::ptr_eq(&scores[0].0, emoimg.emopixels[1].as_ref().unwrap()));
+        assert_eq!(scores[0].1, emoimg.confidences[1]);
+        assert!(explain_chunk(&img, 8, 2, 0, &emos, ComparisationMethod::Correlation).is_empty());
+    }
+
+    #[test]
+    fn emojifiability_of_covered_colors() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let red = solid_image(64, 64, [255, 0, 0]);
+        let grey = solid_image(64, 64, [128, 128, 128]);
+        for &method in &[ComparisationMethod::Correlation, ComparisationMethod::NearestColor] {
+            let covered = emojifiability(&red, 8, &emos, method);
+            assert!(covered > emojifiability(&grey, 8, &emos, method), "{:?}", method);
+        }
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(16, 16));
+        assert_eq!(emojifiability(&transparent, 8, &emos, ComparisationMethod::Correlation), 0.0);
+        assert_eq!(emojifiability(&red, 0, &emos, ComparisationMethod::Correlation), 0.0);
+    }
+
+    #[test]
+    fn hue_family_restricts_the_hues() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        emos.push(Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [128, 128, 128]), "e200.png")));
+        // Red, green and blue thirds
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(24, 8, |x, _| match x / 8 {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([0, 255, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        }));
+        let blue = emos[8].dominant_color().unwrap().h2;
+        let emoimg = EmoimageBuilder::new().frac(8).hue_family(Some((blue, 1))).build(&img, &emos).unwrap();
+        for e in emoimg.emopixels.iter().map(|e| e.as_ref().unwrap()) {
+            let c = e.dominant_color().unwrap();
+            let distance = (c.h2 as i32 - blue as i32).rem_euclid(16);
+            assert!(distance.min(16 - distance) <= 1 && c.c2 >= HUE_FAMILY_MIN_CHROMA, "{}", e.filename);
+        }
+        assert_eq!(emoimg.emopixels[2].as_ref().unwrap().filename, "e008.png");
+        // Only greys
+        let greys = vec![emos[12].clone()];
+        assert!(EmoimageBuilder::new().frac(8).hue_family(Some((blue, 1))).build(&img, &greys).is_err());
+    }
+
+    #[test]
+    fn regions_restrict_the_groups() {
+        let emos : Emoticons = emoticons::hue_wheel(12, 8).iter().enumerate().map(|(i, e)| {
+            let mut e = emoticons::Emoticon::from_image(e.img.clone(), &e.filename);
+            e.group = Some(if (6..10).contains(&i) { "Cool" } else { "Warm" }.to_string());
+            Rc::new(e)
+        }).collect();
+        // Left half is labeled 1, right half 0
+        let labels = image::GrayImage::from_fn(2, 1, |x, _| image::Luma([if x == 0 { 1 } else { 0 }]));
+        let groups : HashMap<u8, String> = vec![(1, "Cool".to_string())].into_iter().collect();
+        let builder = EmoimageBuilder::new().frac(8).regions(labels, groups);
+        let emoimg = builder.build(&solid_image(32, 8, [255, 0, 0]), &emos).unwrap();
+        for e in emoimg.emopixels[..2].iter().map(|e| e.as_ref().unwrap()) {
+            assert_eq!(e.group.as_ref().unwrap(), "Cool", "{}", e.filename);
+        }
+        for e in emoimg.emopixels[2..].iter().map(|e| e.as_ref().unwrap()) {
+            assert_eq!(e.filename, "e000.png");
+        }
+        let warm : Emoticons = emos.iter().filter(|e| e.group.as_ref().unwrap() == "Warm").cloned().collect();
+        match builder.build(&solid_image(32, 8, [255, 0, 0]), &warm) {
+            Err(EmotimError::EmptyGroup(group)) => assert_eq!(group, "Cool"),
+            _ => panic!("expected EmptyGroup"),
+        }
+    }
+
+    #[test]
+    fn random_tiebreak_is_reproducible() {
+        // Five identical red emoticons
+        let emos : Emoticons = (0..5).map(|i| Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 0, 0]), &format!("e10{}.png", i))))
+                                     .collect();
+        let img = solid_image(64, 8, [255, 0, 0]);
+        let names = |builder : EmoimageBuilder| builder.frac(8).build(&img, &emos).unwrap()
+                                                       .emopixels.iter().map(|e| e.as_ref().unwrap().filename.clone()).collect::<Vec<_>>();
+        assert!(names(EmoimageBuilder::new()).iter().all(|n| n == "e100.png"));
+        let varied = names(EmoimageBuilder::new().random_tiebreak(Some(7), 0.01));
+        assert!(varied.iter().any(|n| n != &varied[0]), "{:?}", varied);
+        assert_eq!(names(EmoimageBuilder::new().random_tiebreak(Some(7), 0.01)), varied);
+        assert_ne!(names(EmoimageBuilder::new().random_tiebreak(Some(8), 0.01)), varied);
+        assert!(EmoimageBuilder::new().random_tiebreak(Some(7), -1.0).build(&img, &emos).is_err());
+    }
+
+    #[test]
+    fn random_tiebreak_epsilon_is_relative() {
+        let emos : Emoticons = vec![
+            Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 0, 0]), "e100.png")),
+            Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 40, 0]), "e101.png")),
+        ];
+        let img = solid_image(64, 8, [255, 0, 0]);
+        let similarity = |e : &emoticons::Emoticon| e.similarity(&emos[0], ComparisationMethod::Correlation);
+        let (best, second) = (similarity(&emos[0]), similarity(&emos[1]));
+        assert!(best - second > 1.0 && second > 0.1 * best, "{} {}", best, second);
+        let names = |epsilon : f32| EmoimageBuilder::new().frac(8).random_tiebreak(Some(7), epsilon).build(&img, &emos).unwrap()
+                                                      .emopixels.iter().map(|e| e.as_ref().unwrap().filename.clone()).collect::<Vec<_>>();
+        assert!(names(0.01).iter().all(|n| n == "e100.png"));
+        assert!(names(0.9).iter().any(|n| n == "e101.png"));
+    }
+
+    #[test]
+    fn candidate_k_keeps_the_best_emoticons() {
+        let emos = emoticons::hue_wheel(24, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 16, |x, _| {
+            Rgba([255 - (x * 4) as u8, (x * 4) as u8, 64, 255])
+        }));
+        let names = |emoimg : &Emoimage| emoimg.emopixels.iter().map(|e| e.as_ref().unwrap().filename.clone()).collect::<Vec<_>>();
+        let full = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        let coarse = EmoimageBuilder::new().frac(8).candidate_k(Some(3)).build(&img, &emos).unwrap();
+        assert_eq!(names(&coarse), names(&full));
+        // Other methods get the histograms for the coarse pass, too
+        let maxima = EmoimageBuilder::new().frac(8).method(ComparisationMethod::Maxima).candidate_k(Some(3));
+        assert!(maxima.build(&img, &emos).unwrap().emopixels.iter().all(|e| e.is_some()));
+        // There are still enough candidates for the alternatives
+        let emoimg = EmoimageBuilder::new().frac(8).alternatives(2).candidate_k(Some(1)).build(&img, &emos).unwrap();
+        assert!(emoimg.alternatives.iter().all(|a| a.len() == 2));
+    }
+
+    #[test]
+    fn linear_light_keeps_the_original_emoticons() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(16, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 8..16 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let linear = EmoimageBuilder::new().frac(8).linear_light(true).build(&img, &emos).unwrap();
+        // Pure red looks the same in linear light
+        assert_eq!(linear.emopixels[0].as_ref().unwrap().filename, "e000.png");
+        assert!(linear.emopixels.iter().flatten().all(|p| emos.iter().any(|e| Rc::ptr_eq(e, p))));
+        assert!(linear.alternatives.iter().flatten().all(|a| emos.iter().any(|e| Rc::ptr_eq(e, &a.0))));
+    }
+
+    #[test]
+    fn weights_prefer_and_avoid_emoticons() {
+        // Two equal red emoticons and a blue one
+        let emos = vec![Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 0, 0]), "e100.png")),
+                        Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 0, 0]), "e101.png")),
+                        Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [0, 0, 255]), "e102.png"))];
+        let img = solid_image(8, 8, [255, 0, 0]);
+        let chosen = |weights : &[(&str, f32)]| {
+            let weights = weights.iter().map(|&(k, v)| (k.to_string(), v)).collect();
+            EmoimageBuilder::new().frac(8).weights(weights).build(&img, &emos).unwrap().emopixels[0].as_ref().unwrap().filename.clone()
+        };
+        assert_eq!(chosen(&[]), "e100.png");
+        assert_eq!(chosen(&[("e101", 1.01)]), "e101.png");
+        assert_eq!(chosen(&[("e100", 0.99)]), "e101.png");
+        assert_eq!(chosen(&[("e102", 2.0)]), "e100.png");
+        assert_eq!(chosen(&[("ffff", 0.01)]), "e100.png");
+    }
+
+    #[test]
+    fn char_grid_keeps_sequences() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        // Red keycap sequence
+        let keycap = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        emos[0] = Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(keycap), "0023-20e3.png"));
+        let mut img = solid_image(24, 16, [255, 0, 0]);
+        for x in 16..24 {
+            for y in 0..8 {
+                img.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let grid = emoimg.to_char_grid();
+        assert_eq!(grid, vec![vec!["#\u{20e3}", "#\u{20e3}", ""], vec!["#\u{20e3}", "#\u{20e3}", "#\u{20e3}"]]);
+    }
+
+    #[test]
+    fn json_is_valid_for_odd_results() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut emoimg = Emoimage::from_image(&solid_image(16, 8, [255, 0, 0]), 8, &emos, ComparisationMethod::Correlation).unwrap();
+        emoimg.confidences[1] = f32::NAN;
+        assert!(emoimg.to_json().ends_with(",null]]}"), "{}", emoimg.to_json());
+        // A width of 0 does not panic
+        emoimg.width = 0;
+        assert!(emoimg.to_json().starts_with("{\"width\":0,"));
+        assert_eq!(emoimg.to_char_grid().len(), 2);
+        assert!(!emoimg.to_string().is_empty() && !emoimg.to_ansi().is_empty());
+    }
+
+    #[test]
+    fn compare_methods_highlights_disagreements() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Each chunk is half red and half blue, which the methods weigh differently
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 8, |x, _| {
+            if x % 8 < 4 { Rgba([200, 50, 50, 255]) } else { Rgba([50, 50, 200, 255]) }
+        }));
+        let highlighted = |heatmap : &DynamicImage| heatmap.pixels().filter(|p| p.2 == Rgba([255, 0, 0, 255])).count();
+        let same = compare_methods(&img, 8, &emos, ComparisationMethod::Correlation, ComparisationMethod::Correlation).unwrap();
+        assert_eq!(same.dimensions(), (16, 8));
+        assert_eq!(highlighted(&same), 0);
+        let correlation = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let maxima = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Maxima).unwrap();
+        let different = compare_methods(&img, 8, &emos, ComparisationMethod::Correlation, ComparisationMethod::Maxima).unwrap();
+        assert!(correlation.diff(&maxima).count > 0);
+        assert_eq!(highlighted(&different), correlation.diff(&maxima).count * 64);
+        assert!(compare_methods(&img, 8, &emos, ComparisationMethod::Correlation, ComparisationMethod::Spatial).is_err());
+    }
+
+    #[test]
+    fn overrides_force_emoticons_for_color_ranges() {
+        let emos = emoticons::hue_wheel(12, 8);
+        // Red left, blue right
+        let mut img = solid_image(16, 8, [255, 0, 0]);
+        for y in 0..8 {
+            for x in 8..16 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        let mut emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let brand = Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [0, 64, 255]), "e200.png"));
+        let blue = emoimg.chunk_colors[1].clone().unwrap();
+        let blues = hsl::ColorRange { hue : (blue.h2, blue.h2), chroma : (0, 15), lightness : (0, 15) };
+        let greys = hsl::ColorRange { hue : (0, 15), chroma : (0, 0), lightness : (0, 15) };
+        assert_eq!(emoimg.apply_overrides(&[(greys, emos[5].clone()), (blues, brand)]), 1);
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "e000.png");
+        assert_eq!(emoimg.emopixels[1].as_ref().unwrap().filename, "e200.png");
+    }
+
+    #[test]
+    fn result_does_not_depend_on_thread_count() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(32, 32, [255, 0, 0]);
+        for y in 0..32 {
+            for x in 0..y {
+                img.put_pixel(x, y, Rgba([0, (x * 8) as u8, 255, 255]));
+            }
+        }
+        let single = EmoimageBuilder::new().frac(8).threads(1).build(&img, &emos).unwrap();
+        let multi = EmoimageBuilder::new().frac(8).threads(4).build(&img, &emos).unwrap();
+        assert!(single.diff(&multi).is_identical());
+        assert_eq!(single.confidences, multi.confidences);
+        assert_eq!(single.cache_hits, multi.cache_hits);
+    }
+
+    #[test]
+    fn auto_frac_skips_unusable_candidates() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = solid_image(16, 16, [255, 0, 0]);
+        assert!(Emoimage::auto_frac(&img, &[0, 32], &emos, ComparisationMethod::Spatial).is_none());
+        let (frac, emoimg) = Emoimage::auto_frac(&img, &[0, 4, 8, 32], &emos, ComparisationMethod::Spatial).unwrap();
+        assert!(frac == 4 || frac == 8);
+        assert_eq!(emoimg.width, 16 / frac);
+    }
+
+    #[test]
+    fn grayscale_input() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(16, 16, image::Luma([128])));
+        let emoimg = Emoimage::from_image(&gray, 8, &emos, ComparisationMethod::Spatial).unwrap();
+        assert_eq!(emoimg.emopixels.iter().filter(|e| e.is_some()).count(), 4);
+
+        let mut png = Vec::new();
+        gray.save(&mut png, image::PNG).unwrap();
+        let output = image::load_from_memory(&emojify_bytes(&png, 8, &emos, ComparisationMethod::Correlation).unwrap()).unwrap();
+        assert_eq!(output.dimensions(), (16, 16));
+
+        let mut transparent = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(16, 16)).save(&mut transparent, image::PNG).unwrap();
+        match emojify_bytes(&transparent, 8, &emos, ComparisationMethod::Correlation) {
+            Err(EmotimError::NoEmoticons) => {},
+            _ => panic!("expected NoEmoticons"),
+        }
+    }
+
+    #[test]
+    fn csv_has_one_line_per_cell() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let emoimg = Emoimage::from_image(&solid_image(16, 8, [0, 0, 255]), 8, &emos, ComparisationMethod::Spatial).unwrap();
+        let dir = TempDir::new("csv_has_one_line_per_cell");
+        let path = dir.join("cells.csv");
+        emoimg.save_csv(&path).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let lines : Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "x,y,codepoints,similarity,chunk_color");
+        assert!(lines[2].starts_with("1,0,e008,"));
+    }
+
+    #[test]
+    fn top_emoticons_are_distinct() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let mut img = solid_image(32, 32, [0, 0, 255]);
+        for y in 0..32 {
+            for x in 0..12 {
+                img.put_pixel(x, y, Rgba([255, 255, 0, 255]));
+            }
+        }
+        let top = top_emoticons_for(&img, 3, &emos, ComparisationMethod::Spatial);
+        assert_eq!(top.len(), 3);
+        assert_eq!(top[0].0.filename, "e008.png");
+        assert_eq!(top[1].0.filename, "e002.png");
+        assert!(top[2].0.filename != "e008.png" && top[2].0.filename != "e002.png");
+    }
+
+    #[test]
+    fn empty_emoticon_set_is_an_error() {
+        let emos = Vec::new();
+        let mut img = solid_image(16, 16, [255, 0, 0]);
+        for &method in &[ComparisationMethod::Correlation, ComparisationMethod::Spatial] {
+            match Emoimage::new(&mut img, 8, &emos, method) {
+                Err(EmotimError::NoEmoticons) => {},
+                _ => panic!("expected NoEmoticons for {:?}", method),
+            }
+        }
+        assert!(matches!(Emoimage::new_fast(&img, 8, &emos), Err(EmotimError::NoEmoticons)));
+        assert!(color_mosaic(&img, 8, &emos, ComparisationMethod::Correlation).is_err());
+        assert!(top_emoticons_for(&img, 3, &emos, ComparisationMethod::Correlation).is_empty());
+    }
+
+    #[test]
+    fn correlation_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::Correlation);
+    }
+
+    #[test]
+    fn maxima_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::Maxima);
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::SoftMaxima);
+    }
+
+    #[test]
+    fn spatial_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::Spatial);
+    }
+
+    #[test]
+    fn nearest_color_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::NearestColor);
+    }
+
+    #[test]
+    fn oklab_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::Oklab);
+    }
+
+    #[test]
+    fn oklab_tells_dark_and_light_tints_apart() {
+        let tile = |rgb : [u8 ; 3], name : &str| {
+            Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, rgb), name))
+        };
+        let emos = vec![tile([255, 150, 150], "e000.png"), tile([120, 0, 0], "e001.png")];
+        for &(rgb, expected) in &[([150, 20, 20], "e001.png"), ([240, 130, 130], "e000.png")] {
+            let emoimg = Emoimage::from_image(&solid_image(8, 8, rgb), 8, &emos, ComparisationMethod::Oklab).unwrap();
+            assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, expected, "rgb {:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn color_coverage_prefers_dense_emoticons() {
+        // Same red, but the second one covers only the upper half
+        let half = RgbaImage::from_fn(8, 8, |_, y| Rgba([255, 0, 0, if y < 4 { 255 } else { 0 }]));
+        let emos = vec![Rc::new(emoticons::Emoticon::from_image(DynamicImage::ImageRgba8(half), "e001.png")),
+                        Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [255, 0, 0]), "e000.png"))];
+        let img = solid_image(8, 8, [255, 0, 0]);
+        let method = ComparisationMethod::ColorCoverage { gamma : 2.0 };
+        let emoimg = Emoimage::from_image(&img, 8, &emos, method).unwrap();
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "e000.png");
+        let plain = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let neutral = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::ColorCoverage { gamma : 0.0 }).unwrap();
+        assert_eq!(neutral.confidences, plain.confidences);
+        match Emoimage::from_image(&img, 8, &emos, ComparisationMethod::ColorCoverage { gamma : -1.0 }) {
+            Err(EmotimError::InvalidGamma(gamma)) => assert_eq!(gamma, -1.0),
+            _ => panic!("expected InvalidGamma"),
+        }
+    }
+
+    #[test]
+    fn adaptive_matches_solid_colors() {
+        assert_solid_colors_match_tile_of_same_hue(ComparisationMethod::Adaptive);
+    }
+
+    #[test]
+    fn adaptive_matches_grey_by_lightness() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        for &(grey, filename) in &[(60, "f000.png"), (120, "f001.png"), (200, "f002.png")] {
+            let img = solid_image(8, 8, [grey, grey, grey]);
+            emos.push(Rc::new(emoticons::Emoticon::from_image(img, filename)));
+        }
+        let img = solid_image(8, 8, [118, 120, 122]);
+        let emoimg = EmoimageBuilder::new().frac(8).method(ComparisationMethod::Adaptive).build(&img, &emos).unwrap();
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "f001.png");
+    }
+
+    #[test]
+    fn chroma_matches_grey_to_grey() {
+        let mut emos = emoticons::hue_wheel(12, 8);
+        emos.push(Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [200, 200, 200]), "f000.png")));
+        let img = solid_image(8, 8, [60, 60, 60]);
+        let emoimg = EmoimageBuilder::new().frac(8).method(ComparisationMethod::Chroma).build(&img, &emos).unwrap();
+        assert_eq!(emoimg.emopixels[0].as_ref().unwrap().filename, "f000.png");
+    }
+
+    #[test]
+    fn remap_swaps_the_style() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 8, |x, _| {
+            if x < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+        let emoimg = EmoimageBuilder::new().frac(8).build(&img, &emos).unwrap();
+        // Another style: larger tiles, but without the blue one
+        let style : Emoticons = emoticons::hue_wheel(12, 16).into_iter().filter(|e| e.filename != "e008.png").collect();
+        let remapped = emoimg.remap(&style);
+        let red = remapped.emopixels[0].as_ref().unwrap();
+        assert_eq!(red.filename, "e000.png");
+        assert!(style.iter().any(|e| Rc::ptr_eq(e, red)));
+        assert_eq!(red.img.width(), 16);
+        // Blue falls back to the nearest color
+        let blue = remapped.emopixels[1].as_ref().unwrap();
+        assert!(blue.filename == "e007.png" || blue.filename == "e009.png", "{}", blue.filename);
+        assert_eq!(remapped.confidences, emoimg.confidences);
+        assert!(emoimg.remap(&Vec::new()).emopixels.iter().all(|e| e.is_none()));
+    }
+
+    #[test]
+    fn fast_path_matches_solid_colors() {
+        let emos = emoticons::hue_wheel(12, 8);
+        for &(rgb, tile) in &[([255, 0, 0], 0), ([255, 255, 0], 2), ([0, 0, 255], 8)] {
+            let emoimg = Emoimage::new_fast(&solid_image(32, 16, rgb), 8, &emos).unwrap();
+            assert_eq!((emoimg.width, emoimg.height), (4, 2));
+            for e in &emoimg.emopixels {
+                assert_eq!(e.as_ref().unwrap().filename, emos[tile].filename, "rgb {:?}", rgb);
+            }
+        }
+    }
+
+
+    fn open_image() -> DynamicImage {
+        let inputimagepath = Path::new("assets/emoticons2/00a9.png");
+        let img = image::open(&inputimagepath).unwrap();
+        img
+    }
+
+    #[bench]
+    fn bench_open_image(b: &mut Bencher) {
+        b.iter(|| open_image());
+    }
+
+    #[test]
+    fn auto_white_balance_removes_the_cast() {
+        let emos = vec![Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [170, 170, 170]), "e200.png")),
+                        Rc::new(emoticons::Emoticon::from_image(solid_image(8, 8, [200, 140, 80]), "e201.png"))];
+        // A grey wall under warm light, with a few untinted spots that are not moved
+        let mut img = solid_image(16, 16, [210, 160, 110]);
+        img.put_pixel(15, 15, Rgba([255, 255, 255, 0]));
+        let builder = EmoimageBuilder::new().frac(8).method(ComparisationMethod::Oklab);
+        let tinted = builder.clone().build(&img, &emos).unwrap();
+        assert!(tinted.emopixels.iter().all(|e| e.as_ref().unwrap().filename == "e201.png"));
+        let balanced = builder.auto_white_balance(true).build(&img, &emos).unwrap();
+        assert!(balanced.emopixels.iter().all(|e| e.as_ref().unwrap().filename == "e200.png"));
+    }
+
+    #[test]
+    fn auto_white_balance_keeps_images_without_cast() {
+        // Orange, azure and grey: the channel means are almost equal
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 1, |x, _| {
+            Rgba([[255, 128, 0, 255], [0, 128, 255, 255], [128, 128, 128, 255]][x as usize])
+        }));
+        let balanced = EmoimageBuilder::new().auto_white_balance(true).input_pixels(&img);
+        for (a, b) in balanced.pixels().zip(img.to_rgba().pixels()) {
+            for c in 0..4 {
+                assert!((a.data[c] as i32 - b.data[c] as i32).abs() <= 1, "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    /// Fixed input for the matching benchmarks: a committed painting, downscaled so
+    /// that one iteration stays short, matched against a synthetic emoticon set.
+    fn bench_input() -> (DynamicImage, Emoticons) {
+        let img = image::open(Path::new("assets/input/monalisa.jpg")).unwrap();
+        let img = img.resize_exact(160, 240, image::FilterType::Triangle);
+        (img, emoticons::hue_wheel(24, 16))
+    }
+
+    fn bench_matching(b: &mut Bencher, threads : usize) {
+        let (img, emos) = bench_input();
+        let builder = EmoimageBuilder::new().frac(8).threads(threads);
+        b.iter(|| builder.build(&img, &emos).unwrap());
+    }
+
+    #[bench]
+    fn bench_matching_serial(b: &mut Bencher) {
+        bench_matching(b, 1);
+    }
+
+    #[bench]
+    fn bench_matching_parallel(b: &mut Bencher) {
+        bench_matching(b, 4);
     }
 }
@@ -1,31 +1,45 @@
 
-
 extern crate emotim;
 use emotim::*;
+use std::env;
 use std::path::Path;
 
-fn convert(file : &str, emoticons : &emoticons::Emoticons, frac : u32) {
+fn convert(file : &str, outdir : &str, emoticons : &emoticons::Emoticons, frac : u32, format : OutputFormat) {
     let mut ii = read_input_image(&format!("{}.jpg", file));
-    let emoimg = Emoimage::new(&mut ii, frac, &emoticons, ComparisationMethod::Correlation);
-    emoimg.save(&Path::new(&format!("out/{}.png", file)));
+    let emoimg = Emoimage::new(&mut ii, frac, &emoticons, ComparisationMethod::Correlation).expect("could not convert image");
+    emoimg.save_as(&Path::new(outdir).join(format!("{}.{}", file, format.extension())), format).expect("could not save image");
     println!("{}", emoimg);
 }
 
 fn main()  {
     println!("Hey");
+    // Output directory can be given as first argument,
+    // the output format via `--output-format {png,text,html,ansi,json}`
+    let mut outdir = None;
+    let mut format = OutputFormat::Png;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--output-format" {
+            let name = args.next().expect("--output-format needs a value");
+            format = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else {
+            outdir = Some(arg);
+        }
+    }
+    let outdir = outdir.unwrap_or("out".to_string());
     let emos = emoticons::read_emoticons();
 
-    //convert("angels", &emos, 20);
-    //convert("michelangelo", &emos, 25);
-    //convert("monalisa", &emos, 25);
-    //convert("perlenohrring", &emos, 25);
-    convert("schrei", &emos, 15);
-    //convert("sonnenblumen", &emos, 25);
-    //convert("turmderblauenpferde", &emos, 25);
+    //convert("angels", &outdir, &emos, 20, format);
+    //convert("michelangelo", &outdir, &emos, 25, format);
+    //convert("monalisa", &outdir, &emos, 25, format);
+    //convert("perlenohrring", &outdir, &emos, 25, format);
+    convert("schrei", &outdir, &emos, 15, format);
+    //convert("sonnenblumen", &outdir, &emos, 25, format);
+    //convert("turmderblauenpferde", &outdir, &emos, 25, format);
 
     let mut ii = read_input_image("schrei.jpg");
-    let emoimg = Emoimage::new(&mut ii, 15, &emos, ComparisationMethod::Maxima);
+    let emoimg = Emoimage::new(&mut ii, 15, &emos, ComparisationMethod::Maxima).expect("could not convert image");
     println!("{}", emoimg);
-    emoimg.save(&Path::new("out/schrei_max.png"));
+    emoimg.save_as(&Path::new(&outdir).join(format!("schrei_max.{}", format.extension())), format).expect("could not save image");
 
 }
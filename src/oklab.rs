@@ -0,0 +1,117 @@
+//! The perceptual color space OKLab by Björn Ottosson
+//! (see [his introduction](https://bottosson.github.io/posts/oklab/)).
+//!
+//! Euclidean distances in OKLab follow the perceived color differences much closer than
+//! the distances of the HSL formula, see `ComparisationMethod::Oklab`.
+
+use image::{GenericImage, Pixel, Rgba};
+use hsl::srgb_to_linear;
+
+/// Distance at which `Oklab::similarity()` reaches 0, about the distance of black and white.
+pub const MAX_DISTANCE : f32 = 1.0;
+
+/// A color in OKLab: perceived lightness `l` from 0.0 to 1.0 and the opponent axes `a`
+/// (green to red) and `b` (blue to yellow), which stay roughly within ±0.4 for sRGB.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Oklab {
+    pub l : f32,
+    pub a : f32,
+    pub b : f32,
+}
+
+impl Oklab {
+    /// Converts an sRGB pixel. Alpha is ignored.
+    pub fn from_rgba(pixel : Rgba<u8>) -> Oklab {
+        let (r, g, b, _) = pixel.channels4();
+        Oklab::from_linear(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+    }
+
+    /// Converts linear sRGB, each channel from 0.0 to 1.0.
+    pub fn from_linear(r : f32, g : f32, b : f32) -> Oklab {
+        let (r, g, b) = (r as f64, g as f64, b as f64);
+        // Cone responses
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+        Oklab {
+            l : (0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s) as f32,
+            a : (1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s) as f32,
+            b : (0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s) as f32,
+        }
+    }
+
+    /// Euclidean distance, 0.0 for equal colors.
+    pub fn distance(&self, other : &Oklab) -> f32 {
+        ((self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+
+    /// 1.0 for equal colors, falling linearly with the distance to 0.0 at `MAX_DISTANCE`.
+    pub fn similarity(&self, other : &Oklab) -> f32 {
+        (1.0 - self.distance(other) / MAX_DISTANCE).max(0.0)
+    }
+
+    /// The bit patterns of the components, for hashing.
+    pub fn to_bits(&self) -> [u32 ; 3] {
+        [self.l.to_bits(), self.a.to_bits(), self.b.to_bits()]
+    }
+
+    /// Inverse of `to_bits()`.
+    pub fn from_bits(bits : [u32 ; 3]) -> Oklab {
+        Oklab { l : f32::from_bits(bits[0]), a : f32::from_bits(bits[1]), b : f32::from_bits(bits[2]) }
+    }
+}
+
+/// Mean color of the visible pixels of an RGBA image, averaged in linear light.
+///
+/// `None` if less than half of the pixels are visible, like `hsl::mean_color()`.
+pub fn mean_oklab<T>(rgba_img : &T) -> Option<Oklab>
+    where T : GenericImage<Pixel = Rgba<u8>> {
+
+    let mut rgb = [0f32 ; 3];
+    let mut visible = 0u32;
+    for (_, _, pixel) in rgba_img.pixels() {
+        let (r, g, b, a) = pixel.channels4();
+        if a > 204 { // same threshold as in `hsl::mean_color()`
+            rgb[0] += srgb_to_linear(r);
+            rgb[1] += srgb_to_linear(g);
+            rgb[2] += srgb_to_linear(b);
+            visible += 1;
+        }
+    }
+    if visible == 0 || visible * 2 < rgba_img.width() * rgba_img.height() {
+        return None;
+    }
+    let n = visible as f32;
+    Some(Oklab::from_linear(rgb[0] / n, rgb[1] / n, rgb[2] / n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn reference_colors() {
+        let white = Oklab::from_rgba(Rgba([255, 255, 255, 255]));
+        assert!((white.l - 1.0).abs() < 1e-3 && white.a.abs() < 1e-3 && white.b.abs() < 1e-3, "{:?}", white);
+        // Values from the introduction of OKLab
+        let red = Oklab::from_rgba(Rgba([255, 0, 0, 255]));
+        assert!((red.l - 0.628).abs() < 1e-3 && (red.a - 0.225).abs() < 1e-3 && (red.b - 0.126).abs() < 1e-3, "{:?}", red);
+        let black = Oklab::from_rgba(Rgba([0, 0, 0, 255]));
+        assert_eq!(black.similarity(&black), 1.0);
+        assert!(black.similarity(&white) < 0.01);
+        assert_eq!(Oklab::from_bits(red.to_bits()), red);
+    }
+
+    #[test]
+    fn mean() {
+        let img = RgbaImage::from_fn(4, 4, |x, _| {
+            if x < 2 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+        });
+        // Mixing in linear light: half white is lighter than sRGB mid grey
+        let mean = mean_oklab(&img).unwrap();
+        assert!(mean.l > Oklab::from_rgba(Rgba([128, 128, 128, 255])).l);
+        let transparent = RgbaImage::from_fn(4, 4, |x, _| Rgba([255, 0, 0, if x < 1 { 255 } else { 0 }]));
+        assert_eq!(mean_oklab(&transparent), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Selectable output artifacts of an `Emoimage`: image, text, html, colored terminal output or JSON.
+//! Additionally the emoticons can be exported as sprites, see `Emoimage::export_sprites()`.
+
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use {Emoimage, EmotimError, write_creating_dirs, encode_png};
+
+/// Name of the layout file written by `Emoimage::export_sprites()`.
+pub const SPRITE_LAYOUT : &str = "layout.json";
+
+/// Type of the artifact written by `Emoimage::save_as()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The rendered emoticons, see `Emoimage::save()`.
+    Png,
+    /// The emoticons as unicode text, see `Emoimage::save_text()`.
+    Text,
+    /// A html page with the emoticons as text, see `Emoimage::to_html()`.
+    Html,
+    /// Colored blocks for true color terminals, see `Emoimage::to_ansi()`.
+    Ansi,
+    /// The emoticon grid as JSON, see `Emoimage::to_json()`.
+    Json,
+}
+
+impl OutputFormat {
+    /// All formats, in the order of the variants.
+    pub const ALL : [OutputFormat ; 5] = [OutputFormat::Png, OutputFormat::Text, OutputFormat::Html,
+                                         OutputFormat::Ansi, OutputFormat::Json];
+
+    /// The usual file extension, eg. `txt` for `Text`.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Png => "png",
+            OutputFormat::Text => "txt",
+            OutputFormat::Html => "html",
+            OutputFormat::Ansi => "ans",
+            OutputFormat::Json => "json",
+        }
+    }
+
+    /// The name accepted by `from_str()`, eg. `text` for `Text`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OutputFormat::Png => "png",
+            OutputFormat::Text => "text",
+            OutputFormat::Html => "html",
+            OutputFormat::Ansi => "ansi",
+            OutputFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = EmotimError;
+
+    /// Parses `png`, `text`, `html`, `ansi` or `json` (case insensitive).
+    fn from_str(s : &str) -> Result<OutputFormat, EmotimError> {
+        OutputFormat::ALL.iter()
+                         .find(|f| f.name().eq_ignore_ascii_case(s))
+                         .cloned()
+                         .ok_or_else(|| EmotimError::UnknownOutputFormat(s.to_string()))
+    }
+}
+
+/// One non empty cell of a tilemap, see `Emoimage::to_tilemap()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TilePlacement {
+    /// Column of the cell, 0 is the left edge.
+    pub x : u32,
+    /// Row of the cell, 0 is the top edge.
+    pub y : u32,
+    /// The codepoints of the emoticon in hex, as in `Emoticon::codepoints_hex()`. This is
+    /// also the file name of its sprite in `export_sprites()`.
+    pub codepoint : String,
+}
+
+impl Emoimage {
+    /// The non empty cells in reading order (row by row, left to right), for importing
+    /// the result as a tilemap. Cells of merged blocks are listed individually.
+    pub fn to_tilemap(&self) -> Vec<TilePlacement> {
+        self.emopixels.iter().enumerate().filter_map(|(i, emo)| {
+            emo.as_ref().map(|emo| TilePlacement {
+                x : i as u32 % self.width,
+                y : i as u32 / self.width,
+                codepoint : emo.codepoints_hex(),
+            })
+        }).collect()
+    }
+
+    /// Saves the calculated emoticons in the given format. Creates missing parent directories.
+    pub fn save_as(&self, path : &Path, format : OutputFormat) -> io::Result<()> {
+        match format {
+            OutputFormat::Png => self.save(path),
+            OutputFormat::Text => self.save_text(path),
+            OutputFormat::Html => write_creating_dirs(path, self.to_html().as_bytes()),
+            OutputFormat::Ansi => write_creating_dirs(path, self.to_ansi().as_bytes()),
+            OutputFormat::Json => write_creating_dirs(path, self.to_json().as_bytes()),
+        }
+    }
+
+    /// Saves the emoticons as unicode text, one line per row of cells (see `Display`).
+    /// Creates missing parent directories.
+    pub fn save_text(&self, path : &Path) -> io::Result<()> {
+        write_creating_dirs(path, self.to_string().as_bytes())
+    }
+
+    /// A html page that shows the emoticons as text in the font of the browser.
+    /// Characters with a meaning in html, eg. `<`, are escaped.
+    pub fn to_html(&self) -> String {
+        format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>emotim</title></head>\n\
+                 <body>\n<pre style=\"line-height: 1\">\n{}</pre>\n</body>\n</html>\n", escape_html(&self.to_string()))
+    }
+
+    /// Each cell as two spaces with the dominant color of its emoticon as 24 bit background
+    /// color (ANSI escape codes), one line per row. Empty cells keep the default background.
+    pub fn to_ansi(&self) -> String {
+        let mut ansi = String::new();
+        for line in self.rows(&self.emopixels) {
+            for emo in line {
+                match emo.as_ref().and_then(|e| e.dominant_color()) {
+                    Some(color) => {
+                        let rgba = color.extend_dynamic().to_rgba();
+                        ansi.push_str(&format!("\x1b[48;2;{};{};{}m  ", rgba.data[0], rgba.data[1], rgba.data[2]));
+                    },
+                    None => ansi.push_str("\x1b[0m  "),
+                }
+            }
+            ansi.push_str("\x1b[0m\n");
+        }
+        ansi
+    }
+
+    /// Writes each distinct emoticon (see `palette()`) once as `<codepoints_hex>.png` into
+    /// `dir` and a layout file `SPRITE_LAYOUT` that lists the sprites and, for each non
+    /// empty cell, its position, span (see `spans`), sprite index and codepoints:
+    ///
+    /// ```json
+    /// {"width":2,"height":1,"sprites":["1f300.png"],
+    ///  "cells":[{"x":0,"y":0,"span":1,"sprite":0,"codepoints":[127744]}, ...]}
+    /// ```
+    ///
+    /// A browser can assemble the grid from the shared sprites, which is much smaller than
+    /// the rendered image. Cells covered by a merged block are left out. Creates `dir` if
+    /// it is missing.
+    pub fn export_sprites(&self, dir : &Path) -> io::Result<()> {
+        let palette = self.palette();
+        let names : Vec<String> = palette.iter().map(|p| format!("{}.png", p.0.codepoints_hex())).collect();
+        for (p, name) in palette.iter().zip(names.iter()) {
+            write_creating_dirs(&dir.join(name), &encode_png(&p.0.img)?)?;
+        }
+        let mut cells = Vec::new();
+        for (i, (emo, &span)) in self.emopixels.iter().zip(self.spans.iter()).enumerate() {
+            let emo = match *emo {
+                Some(ref emo) if span > 0 => emo,
+                _ => continue,
+            };
+            let sprite = palette.iter().position(|p| p.0.filename == emo.filename).unwrap();
+            let codepoints : Vec<String> = emo.codepoints().iter().map(|&c| (c as u32).to_string()).collect();
+            cells.push(format!("{{\"x\":{},\"y\":{},\"span\":{},\"sprite\":{},\"codepoints\":[{}]}}",
+                               i as u32 % self.width, i as u32 / self.width, span, sprite, codepoints.join(",")));
+        }
+        let sprites : Vec<String> = names.iter().map(|n| format!("\"{}\"", n)).collect();
+        let layout = format!("{{\"width\":{},\"height\":{},\"sprites\":[{}],\"cells\":[{}]}}",
+                             self.width, self.height, sprites.join(","), cells.join(","));
+        write_creating_dirs(&dir.join(SPRITE_LAYOUT), layout.as_bytes())
+    }
+}
+
+/// Replaces `&`, `<`, `>` and `"` by their html entities.
+fn escape_html(text : &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbaImage, Rgba};
+    use std::fs::File;
+    use std::io::Read;
+    use emoticons;
+    use ComparisationMethod;
+    use tests::TempDir;
+
+    #[test]
+    fn formats() {
+        for format in &OutputFormat::ALL {
+            assert_eq!(format.name().parse::<OutputFormat>().unwrap(), *format);
+        }
+        assert_eq!("HTML".parse::<OutputFormat>().unwrap(), OutputFormat::Html);
+        assert!("gif".parse::<OutputFormat>().is_err());
+
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 8, Rgba([255, 0, 0, 255])));
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let text = emoimg.to_string();
+        assert!(emoimg.to_html().contains(&text));
+        let markup = emoticons::Emoticon::from_image(img.clone(), "003c-0026-0022-003e.png");
+        let escaped = Emoimage::from_image(&img, 8, &vec![::std::rc::Rc::new(markup)], ComparisationMethod::Correlation).unwrap();
+        assert!(escaped.to_html().contains("&lt;&amp;&quot;&gt;&lt;&amp;&quot;&gt;\n</pre>"));
+        let ansi = emoimg.to_ansi();
+        assert_eq!(ansi.matches("\x1b[48;2;").count(), 2);
+        assert!(ansi.ends_with("\x1b[0m\n"));
+
+        let dir = TempDir::new("output_format");
+        let path = dir.join("output.txt");
+        emoimg.save_as(&path, OutputFormat::Text).unwrap();
+        let mut saved = String::new();
+        File::open(&path).unwrap().read_to_string(&mut saved).unwrap();
+        assert_eq!(saved, text);
+    }
+
+    #[test]
+    fn sprites() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(24, 8, |x, _| {
+            if x < 16 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let dir = TempDir::new("sprites");
+        emoimg.export_sprites(&dir.join("sprites")).unwrap();
+        let dir = dir.join("sprites");
+        let mut layout = String::new();
+        File::open(dir.join(SPRITE_LAYOUT)).unwrap().read_to_string(&mut layout).unwrap();
+        let red = &emoimg.emopixels[0].as_ref().unwrap();
+        let blue = &emoimg.emopixels[2].as_ref().unwrap();
+        let red_name = format!("{}.png", red.codepoints_hex());
+        let blue_name = format!("{}.png", blue.codepoints_hex());
+        assert!(layout.starts_with(&format!("{{\"width\":3,\"height\":1,\"sprites\":[\"{}\",\"{}\"]", red_name, blue_name)), "{}", layout);
+        assert_eq!(layout.matches("\"sprite\":0").count(), 2);
+        assert!(layout.contains(&format!("{{\"x\":2,\"y\":0,\"span\":1,\"sprite\":1,\"codepoints\":[{}]}}", blue.unicode as u32)));
+        let sprite = ::image::open(dir.join(&red_name)).unwrap();
+        assert_eq!(sprite.raw_pixels(), red.img.raw_pixels());
+        assert!(dir.join(&blue_name).exists());
+    }
+
+    #[test]
+    fn tilemap() {
+        let emos = emoticons::hue_wheel(12, 8);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |_, y| {
+            if y < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) }
+        }));
+        let emoimg = Emoimage::from_image(&img, 8, &emos, ComparisationMethod::Correlation).unwrap();
+        let red = emoimg.emopixels[0].as_ref().unwrap().codepoints_hex();
+        let tiles = emoimg.to_tilemap();
+        assert_eq!(tiles, vec![TilePlacement { x : 0, y : 0, codepoint : red.clone() },
+                               TilePlacement { x : 1, y : 0, codepoint : red }]);
+    }
+}
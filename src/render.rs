@@ -0,0 +1,653 @@
+//! Render an `Emoimage` into a normal image.
+
+use image::{GenericImage, DynamicImage, RgbaImage, Rgba, Pixel, FilterType};
+use png;
+use png::HasParameters;
+use flate2;
+use std::io::{self, Write};
+use std::mem;
+
+use {Emoimage, ComparisationMethod, EmotimError, grid_size};
+use hsl::Hsl;
+use emoticons::Emoticons;
+
+/// Brightness of the original image in `Emoimage::composite_over()`.
+const COMPOSITE_DIM_FACTOR : f32 = 0.5;
+
+/// Color of the cells that differ in `compare_methods()`.
+const DIFFERENCE_HIGHLIGHT : [u8 ; 4] = [255, 0, 0, 255];
+
+/// Largest image in bytes (4 per pixel) that `Emoimage::render()` creates. Bigger images
+/// overflow the `u32` buffer size calculations of the `image` crate.
+pub const MAX_OUTPUT_BYTES : u64 = u32::MAX as u64;
+
+/// Maximum number of emoticons listed by `Emoimage::render_with_legend()`.
+const LEGEND_ENTRIES : usize = 8;
+
+/// 3x5 pixel glyphs for the digits 0 to 9 and `%`. Each row is a bitmask, the
+/// highest of the three bits is the leftmost pixel.
+const GLYPHS : [[u8 ; 5] ; 11] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b101, 0b001, 0b010, 0b100, 0b101],
+];
+
+/// Options for rendering an `Emoimage` via `Emoimage::render()`.
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// Blend the chosen emoticon of each cell with the second best one.
+    ///
+    /// The weight of the second best emoticon depends on the difference of the
+    /// similarities: 50% if they are equally similar, down to 0% if the second
+    /// best one is not similar at all. This smoothes the transitions between
+    /// cells but makes the single emoticons muddier.
+    pub blend_top2 : bool,
+    /// Paste each emoticon undistorted and centered into a square cell that is large
+    /// enough for the largest emoticon. The margins stay transparent.
+    ///
+    /// Without this, the cell size is the size of the first emoticon and larger
+    /// emoticons are cropped.
+    pub center_in_cell : bool,
+    /// Paste only the opaque part of each emoticon (see `Emoticon::bounding_box`), centered
+    /// in its cell, so that emoticons with wide transparent margins do not look sparse.
+    /// Ignored for merged blocks and with `blend_top2`.
+    pub crop_to_content : bool,
+    /// Width in pixels of the gutter between neighbouring cells. The output grows by
+    /// `gutter * (cells - 1)` in each direction. 0 (the default) renders no gutter.
+    pub gutter : u32,
+    /// Color of the gutter. `None` leaves it transparent.
+    pub gutter_color : Option<Rgba<u8>>,
+    /// Resize the rendered mosaic to these dimensions (Lanczos3 filter), eg. to the
+    /// dimensions of the input image for a drop-in stylized version. `None` keeps
+    /// the size of `cells × cell size`.
+    pub target_dimensions : Option<(u32, u32)>,
+    /// Enlarges mosaics whose longer side is below this size by the smallest integer
+    /// factor that reaches it (nearest neighbour filter, so the emoticons stay crisp).
+    /// Prevents postage stamp sized outputs of small emoticon sets. Ignored if
+    /// `target_dimensions` is given. `None` keeps the size.
+    pub min_output_dimension : Option<u32>,
+    /// Multiplies the alpha of each emoticon by this factor (0.0 to 1.0) and composites
+    /// it over the cell, so that the mosaic reads as a subtle texture, eg. over a
+    /// `gutter_color`. `None` (the default) pastes the emoticons as they are.
+    pub emoji_opacity : Option<f32>,
+    /// Draws a drop shadow behind the emoticons, see `Shadow`. Only visible on transparent
+    /// parts, eg. not below an opaque gutter. `None` (the default) draws none.
+    pub shadow : Option<Shadow>,
+}
+
+/// A drop shadow: a blurred and darkened copy of the silhouettes of the emoticons,
+/// offset behind them. See `RenderOptions::shadow`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    /// Offset in pixels to the right and down.
+    pub offset : (i32, i32),
+    /// Standard deviation of the gaussian blur in pixels. 0.0 gives hard edges.
+    pub blur : f32,
+    /// Opacity of the shadow below an opaque pixel, from 0.0 to 1.0.
+    pub opacity : f32,
+}
+
+impl Default for Shadow {
+    fn default() -> Shadow {
+        Shadow { offset : (2, 2), blur : 1.5, opacity : 0.5 }
+    }
+}
+
+impl Emoimage {
+    /// Renders the emoticons into one image.
+    pub fn to_image(&self) -> DynamicImage {
+        self.render(&RenderOptions::default())
+    }
+
+    /// Size of one cell in the image rendered by `to_image()` and `save()`: the size
+    /// of the first emoticon. `None` if all cells are empty.
+    pub fn cell_size(&self) -> Option<(u32, u32)> {
+        self.emopixels.iter()
+                      .flat_map(|e| e.as_ref())
+                      .next()
+                      .map(|e| (e.img.width(), e.img.height()))
+    }
+
+    /// Writes the image of `to_image()` as png to `w` without holding it in memory: The
+    /// emoticons are pasted into a band of two rows of cells (merged blocks span two
+    /// rows), whose upper row is compressed and written before the band moves on.
+    ///
+    /// Fails with `InvalidInput` if all cells are empty.
+    pub fn write_png_banded<W : Write>(&self, w : W) -> io::Result<()> {
+        let (cell_width, cell_height) = self.cell_size()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no emoticon in image"))?;
+        let (width, height) = match (cell_width.checked_mul(self.width), cell_height.checked_mul(self.height)) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too large for png")),
+        };
+        let mut encoder = png::Encoder::new(w, width, height);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+        let mut band = RgbaImage::new(width, 2 * cell_height);
+        let row_bytes = width as usize * 4;
+        let upper_bytes = row_bytes * cell_height as usize;
+        for h in 0..self.height {
+            for w in 0..self.width {
+                let i = (h * self.width + w) as usize;
+                let emo = match self.emopixels[i] {
+                    Some(ref emo) => emo,
+                    None => continue,
+                };
+                let span = self.spans.get(i).cloned().unwrap_or(1) as u32;
+                if span == 0 {
+                    continue;
+                }
+                let big;
+                let img = if span == 1 {
+                    &emo.img
+                } else {
+                    big = emo.img.resize_exact(cell_width * span, cell_height * span, FilterType::Lanczos3);
+                    &big
+                };
+                // Like `render()`, skip emoticons that do not fit into the whole image
+                if h * cell_height + img.height() <= height {
+                    band.copy_from(img, w * cell_width, 0);
+                }
+            }
+            let mut raw = band.into_raw();
+            for row in raw[..upper_bytes].chunks(row_bytes) {
+                // filter type None
+                zlib.write_all(&[0])?;
+                zlib.write_all(row)?;
+            }
+            let compressed = mem::take(zlib.get_mut());
+            if !compressed.is_empty() {
+                writer.write_chunk(png::chunk::IDAT, &compressed)?;
+            }
+            // The lower row moves up
+            raw.drain(..upper_bytes);
+            raw.resize(2 * upper_bytes, 0);
+            band = RgbaImage::from_raw(width, 2 * cell_height, raw).unwrap();
+        }
+        writer.write_chunk(png::chunk::IDAT, &zlib.finish()?)?;
+        Ok(())
+    }
+
+    /// Size of the cells in `render()`. `None` if all cells are empty.
+    fn render_cell_size(&self, options : &RenderOptions) -> Option<(u32, u32)> {
+        if options.center_in_cell {
+            self.emopixels.iter()
+                          .flat_map(|e| e.as_ref())
+                          .map(|e| e.img.width().max(e.img.height()))
+                          .max()
+                          .map(|side| (side, side))
+        } else {
+            self.cell_size()
+        }
+    }
+
+    /// Size of the image that `render()` creates with the given options.
+    ///
+    /// Fails with `OutputTooLarge` if the image (or the canvas before resizing it to
+    /// `target_dimensions`) needs more than `MAX_OUTPUT_BYTES`, e.g. for panoramas with
+    /// a small `frac`, and with `NoEmoticons` if all cells are empty.
+    pub fn output_dimensions(&self, options : &RenderOptions) -> Result<(u32, u32), EmotimError> {
+        let (cell_width, cell_height) = self.render_cell_size(options).ok_or(EmotimError::NoEmoticons)?;
+        let canvas = |cells : u32, cell : u32| {
+            cells as u64 * cell as u64 + options.gutter as u64 * cells.saturating_sub(1) as u64
+        };
+        let (width, height) = (canvas(self.width, cell_width), canvas(self.height, cell_height));
+        check_output_size(width, height)?;
+        let (width, height) = match (options.target_dimensions, options.min_output_dimension) {
+            (Some((width, height)), _) => (width as u64, height as u64),
+            (None, Some(min)) if width.max(height) > 0 && width.max(height) < min as u64 => {
+                let factor = (min as u64).div_ceil(width.max(height));
+                (width * factor, height * factor)
+            },
+            _ => (width, height),
+        };
+        check_output_size(width, height)?;
+        Ok((width as u32, height as u32))
+    }
+
+    /// Renders the emoticons into one image using the given options.
+    ///
+    /// Panics if all cells are empty or the image would be too large, see `try_render()`.
+    pub fn render(&self, options : &RenderOptions) -> DynamicImage {
+        self.try_render(options).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `render()`, but fails instead of panicking if all cells are empty or the image
+    /// would be too large (see `output_dimensions()`). Nothing is allocated in that case.
+    pub fn try_render(&self, options : &RenderOptions) -> Result<DynamicImage, EmotimError> {
+        self.output_dimensions(options)?;
+        let (cell_width, cell_height) = self.render_cell_size(options).unwrap();
+        let gutter = options.gutter;
+        let height = cell_height * self.height + gutter * self.height.saturating_sub(1);
+        let width = cell_width * self.width + gutter * self.width.saturating_sub(1);
+        let background = options.gutter_color.unwrap_or(Rgba([0, 0, 0, 0]));
+        let mut img = RgbaImage::from_pixel(width, height, background);
+        if options.gutter_color.is_some() {
+            // Only the gutter keeps the color, the cells start transparent
+            let cell = RgbaImage::from_pixel(cell_width, cell_height, Rgba([0, 0, 0, 0]));
+            for h in 0..self.height {
+                for w in 0..self.width {
+                    img.copy_from(&cell, w * (cell_width + gutter), h * (cell_height + gutter));
+                }
+            }
+        }
+        let mut img = DynamicImage::ImageRgba8(img);
+        for h in 0..self.height {
+            for w in 0..self.width {
+                let i = (h * self.width + w) as usize;
+                let (cell_left, cell_top) = (w * (cell_width + gutter), h * (cell_height + gutter));
+                let emo = match self.emopixels[i] {
+                    Some(ref emo) => emo,
+                    None => continue,
+                };
+                match self.spans.get(i).cloned().unwrap_or(1) {
+                    0 => continue,
+                    1 => {},
+                    span => {
+                        let span = span as u32;
+                        let size = (cell_width * span + gutter * (span - 1), cell_height * span + gutter * (span - 1));
+                        let big = if options.center_in_cell {
+                            emo.img.resize_exact(emo.img.width() * span, emo.img.height() * span, FilterType::Lanczos3)
+                        } else {
+                            emo.img.resize_exact(size.0, size.1, FilterType::Lanczos3)
+                        };
+                        paste(&mut img, &big, cell_left + (size.0 - big.width()) / 2, cell_top + (size.1 - big.height()) / 2,
+                              options.emoji_opacity);
+                        continue;
+                    }
+                }
+                let cropped;
+                let emo_img = match emo.bounding_box {
+                    Some((x, y, w, h)) if options.crop_to_content && !options.blend_top2 => {
+                        cropped = DynamicImage::ImageRgba8(RgbaImage::from_fn(w, h, |px, py| emo.img.get_pixel(x + px, y + py)));
+                        &cropped
+                    },
+                    _ => &emo.img,
+                };
+                let (left, top) = if options.center_in_cell || options.crop_to_content {
+                    (cell_left + cell_width.saturating_sub(emo_img.width()) / 2,
+                     cell_top + cell_height.saturating_sub(emo_img.height()) / 2)
+                } else {
+                    (cell_left, cell_top)
+                };
+                match self.alternatives[i].first() {
+                    Some(&(ref second, similarity)) if options.blend_top2 => {
+                        let weight = blend_weight(self.confidences[i], similarity);
+                        for y in 0..cell_height.min(emo.img.height()).min(second.img.height()) {
+                            for x in 0..cell_width.min(emo.img.width()).min(second.img.width()) {
+                                let pixel = blend_premultiplied(emo.img.get_pixel(x, y), second.img.get_pixel(x, y), weight);
+                                let pixel = match options.emoji_opacity {
+                                    Some(opacity) => over(fade(pixel, opacity), img.get_pixel(left + x, top + y)),
+                                    None => pixel,
+                                };
+                                img.put_pixel(left + x, top + y, pixel);
+                            }
+                        }
+                    },
+                    _ => paste(&mut img, emo_img, left, top, options.emoji_opacity),
+                }
+            }
+        }
+        let img = match options.shadow {
+            Some(ref shadow) => with_shadow(&img, shadow),
+            None => img,
+        };
+        Ok(match (options.target_dimensions, options.min_output_dimension) {
+            (Some((width, height)), _) => img.resize_exact(width, height, FilterType::Lanczos3),
+            (None, Some(min)) if width.max(height) > 0 && width.max(height) < min => {
+                enlarge(&img, min.div_ceil(width.max(height)))
+            },
+            _ => img,
+        })
+    }
+
+    /// Renders the emoticons into one image and recolors it afterwards: The hue of each
+    /// pixel is rotated by `hue_shift` (256 is a full turn) and its chroma is multiplied
+    /// by `saturation_scale`. Useful for theming.
+    ///
+    /// The round trip through HSL is not lossless, so even `recolor(0, 1.0)` slightly
+    /// changes the colors.
+    pub fn recolor(&self, hue_shift : u8, saturation_scale : f32) -> DynamicImage {
+        let mut img = self.to_image();
+        let (width, height) = img.dimensions();
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = img.get_pixel(x, y);
+                if pixel.data[3] == 0 {
+                    continue;
+                }
+                let mut hsl = Hsl::from(pixel);
+                hsl.h2 = hsl.h2.wrapping_add(hue_shift);
+                hsl.c2 = (hsl.c2 as f32 * saturation_scale).clamp(0.0, 255.0) as u8;
+                img.put_pixel(x, y, hsl.to_rgba());
+            }
+        }
+        img
+    }
+
+    /// Renders each cell as a solid block of size `cell_size` in the dominant color
+    /// of its emoticon. Empty cells are transparent.
+    pub fn to_color_image(&self, cell_size : u32) -> DynamicImage {
+        let mut img = RgbaImage::new(self.width * cell_size, self.height * cell_size);
+        for (i, emo) in self.emopixels.iter().enumerate() {
+            let color = match emo.as_ref().and_then(|e| e.dominant_color()) {
+                Some(color) => color.extend_dynamic().to_rgba(),
+                None => continue,
+            };
+            let x = (i as u32 % self.width) * cell_size;
+            let y = (i as u32 / self.width) * cell_size;
+            for dy in 0..cell_size {
+                for dx in 0..cell_size {
+                    img.put_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(img)
+    }
+
+    /// The color each cell aimed at: the dominant color of its chunk of the input image.
+    /// Empty cells are transparent. Compare with `to_color_image()` to judge the match quality.
+    pub fn target_colors(&self) -> Vec<Rgba<u8>> {
+        self.chunk_colors.iter()
+                         .map(|c| match *c {
+                             Some(ref color) => color.extend_dynamic().to_rgba(),
+                             None => Rgba([0, 0, 0, 0]),
+                         })
+                         .collect()
+    }
+
+    /// Renders each cell as a solid block of size `cell_size` in its target color,
+    /// see `target_colors()`.
+    pub fn to_target_image(&self, cell_size : u32) -> DynamicImage {
+        let mut img = RgbaImage::new(self.width * cell_size, self.height * cell_size);
+        for (i, color) in self.target_colors().into_iter().enumerate() {
+            let x = (i as u32 % self.width) * cell_size;
+            let y = (i as u32 / self.width) * cell_size;
+            for dy in 0..cell_size {
+                for dx in 0..cell_size {
+                    img.put_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(img)
+    }
+
+    /// One pixel per cell in the dominant color of its emoticon, i.e. `to_color_image(1)`.
+    /// Cheap enough for thumbnails in list views.
+    pub fn thumbnail(&self) -> DynamicImage {
+        self.to_color_image(1)
+    }
+
+    /// Renders the emoticons with a legend below: The most frequent emoticons (see
+    /// `palette()`) each followed by the percentage of the cells they fill.
+    ///
+    /// Fails like `try_render()` if all cells are empty.
+    pub fn render_with_legend(&self) -> Result<DynamicImage, EmotimError> {
+        let mosaic = self.try_render(&RenderOptions::default())?;
+        let (cell_width, cell_height) = self.cell_size().ok_or(EmotimError::NoEmoticons)?;
+        let palette = self.palette();
+        let cells : usize = palette.iter().map(|p| p.1).sum();
+        // Text is about half as high as the emoticons
+        let scale = (cell_height / 10).max(1);
+        let gap = 2 * scale;
+
+        // Place the entries row by row
+        let mut entries = Vec::new();
+        let (mut x, mut y) = (gap, mosaic.height() + gap);
+        for (emo, count) in palette.into_iter().take(LEGEND_ENTRIES) {
+            let text = format!("{}%", (count * 100 + cells / 2) / cells);
+            let text_width = text.len() as u32 * 4 * scale;
+            let entry_width = cell_width + gap + text_width;
+            if x > gap && x + entry_width > mosaic.width() {
+                x = gap;
+                y += cell_height + gap;
+            }
+            entries.push((emo, text, x, y));
+            x += entry_width + 2 * gap;
+        }
+        let width = entries.iter()
+                           .map(|&(_, ref text, x, _)| x + cell_width + gap + text.len() as u32 * 4 * scale + gap)
+                           .max()
+                           .unwrap_or(0)
+                           .max(mosaic.width());
+        let height = y + cell_height + gap;
+
+        let mut img = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+        img.copy_from(&mosaic, 0, 0);
+        for (emo, text, x, y) in entries {
+            img.copy_from(&emo.img, x, y);
+            let text_top = y + (cell_height - 5 * scale) / 2;
+            let mut text_left = x + cell_width + gap;
+            for c in text.chars() {
+                let glyph = &GLYPHS[c.to_digit(10).unwrap_or(10) as usize];
+                for (row, bits) in glyph.iter().enumerate() {
+                    for column in 0..3 {
+                        if bits & (0b100 >> column) == 0 {
+                            continue;
+                        }
+                        for dy in 0..scale {
+                            for dx in 0..scale {
+                                img.put_pixel(text_left + column * scale + dx,
+                                              text_top + row as u32 * scale + dy,
+                                              Rgba([0, 0, 0, 255]));
+                            }
+                        }
+                    }
+                }
+                text_left += 4 * scale;
+            }
+        }
+        Ok(DynamicImage::ImageRgba8(img))
+    }
+
+    /// Renders the emoticons to exactly `width` × `height` pixels, eg. for wallpapers.
+    /// Like CSS `background-size: cover`, the mosaic is scaled (Lanczos3 filter) while
+    /// keeping its aspect ratio until it covers the canvas, and the overhang is cropped
+    /// evenly on both sides.
+    pub fn render_cover(&self, width : u32, height : u32) -> DynamicImage {
+        let emoimg = self.to_image();
+        let (mosaic_width, mosaic_height) = emoimg.dimensions();
+        if mosaic_width == 0 || mosaic_height == 0 {
+            return DynamicImage::new_rgba8(width, height);
+        }
+        let scale = (width as f32 / mosaic_width as f32).max(height as f32 / mosaic_height as f32);
+        let scaled_width = ((mosaic_width as f32 * scale).ceil() as u32).max(width);
+        let scaled_height = ((mosaic_height as f32 * scale).ceil() as u32).max(height);
+        let mut scaled = emoimg.resize_exact(scaled_width, scaled_height, FilterType::Lanczos3);
+        scaled.crop((scaled_width - width) / 2, (scaled_height - height) / 2, width, height)
+    }
+
+    /// Renders the emoticons semi-transparently over a dimmed version of the `original` image.
+    ///
+    /// The original is scaled to the size of the rendered emoticons and its brightness is halved.
+    /// `emoji_opacity` ranges from 0.0 (only the original is visible) to 1.0 (emoticons are opaque).
+    pub fn composite_over(&self, original : &DynamicImage, emoji_opacity : f32) -> DynamicImage {
+        let emoimg = self.to_image();
+        let (width, height) = emoimg.dimensions();
+        let background = original.resize_exact(width, height, FilterType::Triangle);
+        let emoji_opacity = emoji_opacity.clamp(0.0, 1.0);
+
+        let mut img = DynamicImage::new_rgba8(width, height);
+        for (x, y, bg) in background.pixels() {
+            let bg = bg.map_with_alpha(|c| (c as f32 * COMPOSITE_DIM_FACTOR) as u8, |a| a);
+            let emo = emoimg.get_pixel(x, y);
+            let weight = emo.channels4().3 as f32 / 255.0 * emoji_opacity;
+            let mut pixel = blend(bg, emo, weight);
+            pixel.data[3] = bg.data[3].max(emo.data[3]);
+            img.put_pixel(x, y, pixel);
+        }
+        img
+    }
+
+    /// Renders the emoticons (see `to_image()`) as cutout of the subject in `mask`: The alpha
+    /// of each pixel is multiplied by the mask, scaled to the size of the rendered image.
+    /// White opaque mask pixels keep the mosaic, black or transparent ones remove it and grey
+    /// edges feather it. Gives clean subject shaped mosaics, eg. for stickers.
+    ///
+    /// Fails with `EmptyMask` if `mask` has no pixels and like `try_render()` otherwise.
+    pub fn apply_mask(&self, mask : &DynamicImage) -> Result<DynamicImage, EmotimError> {
+        if mask.width() == 0 || mask.height() == 0 {
+            return Err(EmotimError::EmptyMask);
+        }
+        let mut img = self.try_render(&RenderOptions::default())?;
+        let (width, height) = img.dimensions();
+        let mask = mask.resize_exact(width, height, FilterType::Triangle).to_luma_alpha();
+        for (x, y, pixel) in mask.enumerate_pixels() {
+            let (luma, alpha) = (pixel.data[0] as u32, pixel.data[1] as u32);
+            let mut out = img.get_pixel(x, y);
+            out.data[3] = (out.data[3] as u32 * luma * alpha / (255 * 255)) as u8;
+            img.put_pixel(x, y, out);
+        }
+        Ok(img)
+    }
+}
+
+/// Runs the matching, but renders each cell as a solid block (of size `frac`) in the
+/// dominant color of the chosen emoticon instead of the emoticon itself.
+///
+/// Useful as fast preview when tuning `frac` or the method.
+pub fn color_mosaic(img : &DynamicImage,
+                    frac : u32,
+                    emoticons : &Emoticons,
+                    method : ComparisationMethod) -> Result<DynamicImage, EmotimError> {
+    Ok(Emoimage::from_image(img, frac, emoticons, method)?.to_color_image(frac))
+}
+
+/// Matches the image with two methods and renders a heatmap (one block of size `frac`
+/// per cell) of where they disagree: Cells that got different emoticons are highlighted
+/// in red, the others show the color of their emoticon (see `to_color_image()`) dimmed.
+///
+/// The chunks are analyzed only once, so only methods that are supported by
+/// `Emoimage::match_histograms()` can be compared.
+pub fn compare_methods(img : &DynamicImage,
+                       frac : u32,
+                       emoticons : &Emoticons,
+                       a : ComparisationMethod,
+                       b : ComparisationMethod) -> Result<DynamicImage, EmotimError> {
+    let histograms = Emoimage::analyze(img, frac)?;
+    let (width, height) = grid_size(img, frac);
+    let emoimg_a = Emoimage::match_histograms(&histograms, width, height, emoticons, a)?;
+    let emoimg_b = Emoimage::match_histograms(&histograms, width, height, emoticons, b)?;
+    let mut heatmap = emoimg_a.to_color_image(frac);
+    for (x, y, pixel) in heatmap.clone().pixels() {
+        let dimmed = pixel.map_with_alpha(|c| (c as f32 * COMPOSITE_DIM_FACTOR) as u8, |a| a);
+        heatmap.put_pixel(x, y, dimmed);
+    }
+    for &(w, h) in &emoimg_a.diff(&emoimg_b).positions {
+        for dy in 0..frac {
+            for dx in 0..frac {
+                heatmap.put_pixel(w * frac + dx, h * frac + dy, Rgba(DIFFERENCE_HIGHLIGHT));
+            }
+        }
+    }
+    Ok(heatmap)
+}
+
+/// Weight of the second best emoticon when blending, from 0.0 to 0.5.
+fn blend_weight(best : f32, second : f32) -> f32 {
+    if best.abs() < f32::EPSILON {
+        return 0.5;
+    }
+    let difference = (best - second) / best.abs();
+    (0.5 * (1.0 - difference)).clamp(0.0, 0.5)
+}
+
+/// Linear interpolation of all channels: `weight` 0.0 returns `a`, 1.0 returns `b`.
+fn blend(a : Rgba<u8>, b : Rgba<u8>, weight : f32) -> Rgba<u8> {
+    a.map2(&b, |ca, cb| (ca as f32 * (1.0 - weight) + cb as f32 * weight).round() as u8)
+}
+
+/// Like `blend()`, but the colors are weighted by their alpha, so that transparent pixels
+/// of one image do not darken the other.
+fn blend_premultiplied(a : Rgba<u8>, b : Rgba<u8>, weight : f32) -> Rgba<u8> {
+    let (wa, wb) = (a.data[3] as f32 * (1.0 - weight), b.data[3] as f32 * weight);
+    let alpha = wa + wb;
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |c : usize| ((a.data[c] as f32 * wa + b.data[c] as f32 * wb) / alpha).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), alpha.round() as u8])
+}
+
+/// Enlarges `img` by an integer `factor`, each pixel becomes a `factor` × `factor` block.
+/// (The nearest neighbour filter of `resize_exact()` can not enlarge.)
+fn enlarge(img : &DynamicImage, factor : u32) -> DynamicImage {
+    let rgba = img.to_rgba();
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(rgba.width() * factor, rgba.height() * factor,
+                                                |x, y| *rgba.get_pixel(x / factor, y / factor)))
+}
+
+/// Fails if an image of the given size needs more than `MAX_OUTPUT_BYTES`.
+fn check_output_size(width : u64, height : u64) -> Result<(), EmotimError> {
+    match width.checked_mul(height).and_then(|pixels| pixels.checked_mul(4)) {
+        Some(bytes) if bytes <= MAX_OUTPUT_BYTES => Ok(()),
+        _ => Err(EmotimError::OutputTooLarge { width : width, height : height }),
+    }
+}
+
+/// Composites `img` over its shadow, see `Shadow`.
+fn with_shadow(img : &DynamicImage, shadow : &Shadow) -> DynamicImage {
+    let rgba = img.to_rgba();
+    let (width, height) = rgba.dimensions();
+    let silhouette = RgbaImage::from_fn(width, height, |x, y| {
+        let (sx, sy) = (x as i64 - shadow.offset.0 as i64, y as i64 - shadow.offset.1 as i64);
+        let inside = sx >= 0 && sy >= 0 && sx < width as i64 && sy < height as i64;
+        Rgba([0, 0, 0, if inside { rgba.get_pixel(sx as u32, sy as u32).data[3] } else { 0 }])
+    });
+    let silhouette = if shadow.blur > 0.0 {
+        DynamicImage::ImageRgba8(silhouette).blur(shadow.blur).to_rgba()
+    } else {
+        silhouette
+    };
+    let opacity = shadow.opacity.clamp(0.0, 1.0);
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+        let alpha = (silhouette.get_pixel(x, y).data[3] as f32 * opacity).round() as u8;
+        over(*rgba.get_pixel(x, y), Rgba([0, 0, 0, alpha]))
+    }))
+}
+
+/// Like `copy_from()`, but with `opacity` multiplies the alpha of `src` and composites it
+/// over `img`. Skips images that do not fit, like `copy_from()`.
+fn paste(img : &mut DynamicImage, src : &DynamicImage, left : u32, top : u32, opacity : Option<f32>) {
+    let opacity = match opacity {
+        Some(opacity) => opacity,
+        None => {
+            img.copy_from(src, left, top);
+            return;
+        },
+    };
+    if left + src.width() > img.width() || top + src.height() > img.height() {
+        return;
+    }
+    for (x, y, pixel) in src.pixels() {
+        let pixel = over(fade(pixel, opacity), img.get_pixel(left + x, top + y));
+        img.put_pixel(left + x, top + y, pixel);
+    }
+}
+
+/// Multiplies the alpha of `pixel` by `opacity` (clamped to 0.0 to 1.0).
+fn fade(pixel : Rgba<u8>, opacity : f32) -> Rgba<u8> {
+    let mut pixel = pixel;
+    pixel.data[3] = (pixel.data[3] as f32 * opacity.clamp(0.0, 1.0)).round() as u8;
+    pixel
+}
+
+/// Alpha compositing of `top` over `bottom` (source over).
+fn over(top : Rgba<u8>, bottom : Rgba<u8>) -> Rgba<u8> {
+    let (ta, ba) = (top.data[3] as f32 / 255.0, bottom.data[3] as f32 / 255.0);
+    let alpha = ta + ba * (1.0 - ta);
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |c : usize| ((top.data[c] as f32 * ta + bottom.data[c] as f32 * ba * (1.0 - ta)) / alpha).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), (alpha * 255.0).round() as u8])
+}